@@ -52,14 +52,14 @@ def test_openssl_compatibility(exec_path, result_file):
     # Perform command with executable under test and compare
     with open(aes_ctr_tmp_dir + 'test.txt', 'w') as f: 
         f.write(data)
-    aes_ctr_cmd = './' + exec_path + ' -c encrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_file + ' -o ' + test_enc128_file
+    aes_ctr_cmd = './' + exec_path + ' -c encrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_file + ' -o ' + test_enc128_file
     foo = subprocess.run(aes_ctr_cmd.split(' '), cwd=aes_ctr_tmp_dir, capture_output=True, text=True)
     print(foo.stdout)
-    aes_ctr_cmd = './' + exec_path + ' -c decrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_enc128_file + ' -o ' + test_dec128_file
+    aes_ctr_cmd = './' + exec_path + ' -c decrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_enc128_file + ' -o ' + test_dec128_file
     subprocess.run(aes_ctr_cmd.split(' '), cwd=aes_ctr_tmp_dir, capture_output=True, text=True)
-    aes_ctr_cmd = './' + exec_path + ' -c encrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_file + ' -o ' + test_enc256_file
+    aes_ctr_cmd = './' + exec_path + ' -c encrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_file + ' -o ' + test_enc256_file
     subprocess.run(aes_ctr_cmd.split(' '), cwd=aes_ctr_tmp_dir, capture_output=True, text=True)
-    aes_ctr_cmd = './' + exec_path + ' -c decrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_enc256_file + ' -o ' + test_dec256_file
+    aes_ctr_cmd = './' + exec_path + ' -c decrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_enc256_file + ' -o ' + test_dec256_file
     subprocess.run(aes_ctr_cmd.split(' '), cwd=aes_ctr_tmp_dir, capture_output=True, text=True)
     # Compare files
     list_of_files = [
@@ -148,10 +148,10 @@ def get_global_heap_max(exec_path, result_file):
     key_256 = ''.join('{:02x}'.format(x) for x in secrets.token_bytes(32))
     iv      = ''.join('{:02x}'.format(x) for x in secrets.token_bytes(16))
     commands      = [
-        exec_path + ' -c encrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c decrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c encrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c decrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c encrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c decrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c encrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c decrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
         ]
     # Loop through tests with different file sizes and commands
     with tqdm(total=len(filesizes)*len(commands)) as pbar:
@@ -187,10 +187,10 @@ def get_execution_time(exec_path, result_file):
     key_256 = ''.join('{:02x}'.format(x) for x in secrets.token_bytes(32))
     iv      = ''.join('{:02x}'.format(x) for x in secrets.token_bytes(16))
     commands      = [
-        exec_path + ' -c encrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c decrypt -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c encrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
-        exec_path + ' -c decrypt -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c encrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c decrypt --raw -k ' + key_128 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c encrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
+        exec_path + ' -c decrypt --raw -k ' + key_256 + ' -v ' + iv + ' -i ' + test_in_file + ' -o ' + test_out_file,
         ]
     n_measurements = 3
     # Loop through tests with different file sizes and commands
@@ -18,8 +18,8 @@ if __name__ == '__main__':
             continue
         if os.path.exists(output_file):
             os.remove(output_file)
-        command = 'cargo run --release -- -c encrypt -i %s -v 00112233445566778899aabbccddeeff -k 000102030405060708090a0b0c0d0e0f -o data/test_coded.hex'
-        command = './target/release/aes-ctr.exe -c encrypt -i %s -v 00112233445566778899aabbccddeeff -k 000102030405060708090a0b0c0d0e0f -o data/test_coded.hex'
+        command = 'cargo run --release -- -c encrypt -i %s -k 000102030405060708090a0b0c0d0e0f -o data/test_coded.hex'
+        command = './target/release/aes-ctr.exe -c encrypt -i %s -k 000102030405060708090a0b0c0d0e0f -o data/test_coded.hex'
         command = command % filename
         print(command)
         # Run the command and redirect the output to the file
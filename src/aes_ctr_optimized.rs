@@ -1,7 +1,29 @@
+use crate::cbc;
+use crate::ccm::{self, Ccm};
+use crate::cfb::{self, CfbSegment};
+use crate::container;
+use crate::eax::{self, Eax};
+use crate::envelope;
+use crate::error::AesError;
+use crate::gcm_siv::{self, GcmSiv};
+use crate::kcv;
+use crate::kw;
+use crate::log;
+use crate::log_verbose;
+use crate::master_key::{self, FileDerivation};
+use crate::ocb::{self, Ocb};
+use crate::ofb;
+use crate::openssl::{self, PasswordOptions};
+use crate::passphrase::{self, Kdf, PassphraseOptions};
+use crate::random;
+use crate::secret::SecretBytes;
+use crate::siv::{self, Siv};
 use crate::thread_pool::*;
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use crate::xts::{self, Xts};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, Mutex};
 
@@ -28,40 +50,264 @@ const SBOX: [u8; 256] = [
     0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
 ];
 
-const BLOCK_SIZE: usize = 16; // AES block size
-const CHUNK_SIZE: usize = 1_048_576 * 4; //  < 1 MB pro thread
+const INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
 
-/// Encrypt a chunk in CTR mode (mock implementation)
-fn process_chunk(chunks: &mut [u8], keys: &[u8], counter: &[u8], nr: usize, starting_block: u64) {
-    // Create a mutable buffer to store the incremented counter
-    let mut cipher_block = [0u8; BLOCK_SIZE];
-    let mut c: u64;
-    // Iterate over each chunk of data
-    for (i, chunk) in chunks.chunks_mut(BLOCK_SIZE).enumerate() {
-        c = starting_block + i as u64;
-        // Increment the counter using the provided ctr128_inc logic
-        for i in (0..BLOCK_SIZE).rev() {
+pub const BLOCK_SIZE: usize = 16; // AES block size
+pub const CHUNK_SIZE: usize = 1_048_576 * 4; //  < 1 MB pro thread
+
+/// Encrypt a chunk in CTR mode, the IV is one 128-bit big-endian counter (openssl)
+pub(crate) fn process_chunk(
+    chunks: &mut [u8],
+    keys: &[u8],
+    counter: &[u8],
+    nr: usize,
+    starting_block: u64,
+) {
+    process_chunk_with_layout(
+        chunks,
+        keys,
+        counter,
+        nr,
+        starting_block,
+        CounterLayout::Full128,
+        CounterEndianness::Big,
+    );
+}
+
+/// Encrypt a chunk in CTR mode, only the counter field of `layout` is incremented
+/// and the nonce in front of it stays fixed
+pub(crate) fn process_chunk_with_layout(
+    chunks: &mut [u8],
+    keys: &[u8],
+    counter: &[u8],
+    nr: usize,
+    starting_block: u64,
+    layout: CounterLayout,
+    endianness: CounterEndianness,
+) {
+    let counter_start = BLOCK_SIZE - layout.counter_size();
+    apply_keystream(chunks, keys, nr, starting_block, |block| {
+        // Create a mutable buffer to store the incremented counter
+        let mut counter_block = [0u8; BLOCK_SIZE];
+        counter_block.copy_from_slice(counter);
+        let mut c = block;
+        // Increment the counter using the provided ctr128_inc logic, returns
+        // whether the carry is used up
+        let mut add_carry = |i: usize| {
             c += counter[i] as u64;
-            cipher_block[i] = c as u8;
+            counter_block[i] = c as u8;
             c >>= 8;
-            if c == 0 {
-                break;
+            c == 0
+        };
+        match endianness {
+            CounterEndianness::Big => {
+                for i in (counter_start..BLOCK_SIZE).rev() {
+                    if add_carry(i) {
+                        break;
+                    }
+                }
             }
+            CounterEndianness::Little => {
+                for i in counter_start..BLOCK_SIZE {
+                    if add_carry(i) {
+                        break;
+                    }
+                }
+            }
+        }
+        counter_block
+    });
+}
+
+/// Layout of the CTR counter block, a fixed nonce followed by a big-endian block counter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterLayout {
+    /// The whole block is one 128-bit counter (openssl)
+    Full128,
+    /// 64-bit nonce and 64-bit counter
+    Split64,
+    /// 96-bit nonce and 32-bit counter, e.g. `nonce || IV || counter` of RFC 3686.
+    /// Without `check_wrap` the counter silently wraps around after 2^32 blocks.
+    Split96 { check_wrap: bool },
+}
+
+impl CounterLayout {
+    /// Parses a layout name as given on the command line
+    pub fn from_name(name: &str) -> Option<CounterLayout> {
+        match name {
+            "full128" => Some(CounterLayout::Full128),
+            "64-64" => Some(CounterLayout::Split64),
+            "96-32" => Some(CounterLayout::Split96 { check_wrap: true }),
+            "96-32-wrapping" => Some(CounterLayout::Split96 { check_wrap: false }),
+            _ => None,
+        }
+    }
+
+    /// Whether running out of counter values is an error rather than a wrap-around
+    pub fn check_wrap(self) -> bool {
+        match self {
+            CounterLayout::Split96 { check_wrap } => check_wrap,
+            _ => true,
+        }
+    }
+
+    /// Size of the counter field in bytes
+    pub fn counter_size(self) -> usize {
+        match self {
+            CounterLayout::Full128 => 16,
+            CounterLayout::Split64 => 8,
+            CounterLayout::Split96 { .. } => 4,
+        }
+    }
+
+    /// Start value of the counter field of a fresh IV, 1 for 96-32 as in RFC 3686
+    pub fn initial_counter(self) -> u8 {
+        match self {
+            CounterLayout::Split96 { .. } => 1,
+            _ => 0,
         }
+    }
+
+    /// Sets the counter field of `iv` to its start value and keeps the nonce in
+    /// front of it. The whole block of `Full128` is the counter, it stays as it is.
+    pub fn start_counter(self, iv: &mut [u8], endianness: CounterEndianness) {
+        if self == CounterLayout::Full128 {
+            return;
+        }
+        let field = &mut iv[BLOCK_SIZE - self.counter_size()..];
+        for byte in field.iter_mut() {
+            *byte = 0;
+        }
+        match endianness {
+            CounterEndianness::Big => field[field.len() - 1] = self.initial_counter(),
+            CounterEndianness::Little => field[0] = self.initial_counter(),
+        }
+    }
+
+    /// Fills `iv` with a random nonce followed by the counter start value, so that
+    /// the full counter range is available
+    pub fn fresh_iv(self, iv: &mut [u8], endianness: CounterEndianness) -> io::Result<()> {
+        random::fill(iv)?;
+        self.start_counter(iv, endianness);
+        Ok(())
+    }
+
+    /// Number of blocks that can be encrypted from `counter` on before the counter
+    /// field wraps around
+    pub fn blocks_left(self, counter: &[u8], endianness: CounterEndianness) -> u128 {
+        let size = self.counter_size();
+        let field = &counter[BLOCK_SIZE - size..];
+        let value = match endianness {
+            CounterEndianness::Big => field.iter().fold(0u128, |acc, &b| (acc << 8) | b as u128),
+            CounterEndianness::Little => field
+                .iter()
+                .rev()
+                .fold(0u128, |acc, &b| (acc << 8) | b as u128),
+        };
+        if size == 16 {
+            // 2^128 - value, saturated to what fits into an u128
+            (!value).saturating_add(1)
+        } else {
+            (1u128 << (8 * size)) - value
+        }
+    }
+}
+
+/// Byte order of the CTR counter field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CounterEndianness {
+    /// Carries propagate towards the first byte (openssl, NIST SP 800-38A)
+    Big,
+    /// Carries propagate towards the last byte of the block
+    Little,
+}
+
+impl CounterEndianness {
+    /// Parses a byte order as given on the command line
+    pub fn from_name(name: &str) -> Option<CounterEndianness> {
+        match name {
+            "big" => Some(CounterEndianness::Big),
+            "little" => Some(CounterEndianness::Little),
+            _ => None,
+        }
+    }
+}
+
+/// Fails with `CounterOverflow` if `blocks` blocks from `starting_block` on would
+/// wrap the counter field around and thus reuse keystream, unless the layout
+/// wraps on purpose
+pub(crate) fn check_counter_range(
+    counter: &[u8],
+    layout: CounterLayout,
+    endianness: CounterEndianness,
+    starting_block: u64,
+    blocks: u64,
+) -> Result<(), AesError> {
+    if layout.check_wrap()
+        && starting_block as u128 + blocks as u128 > layout.blocks_left(counter, endianness)
+    {
+        return Err(AesError::CounterOverflow);
+    }
+    Ok(())
+}
+
+/// XORs the CTR keystream into `chunks`, `counter_block` returns the counter block
+/// for a block index counted from the start of the message
+#[inline]
+pub(crate) fn apply_keystream<F>(
+    chunks: &mut [u8],
+    keys: &[u8],
+    nr: usize,
+    starting_block: u64,
+    counter_block: F,
+) where
+    F: Fn(u64) -> [u8; BLOCK_SIZE],
+{
+    // Iterate over each chunk of data
+    for (i, chunk) in chunks.chunks_mut(BLOCK_SIZE).enumerate() {
         // Encrypt the counter block
+        let mut cipher_block = counter_block(starting_block + i as u64);
         aes_v2(&mut cipher_block, keys, nr);
         // XOR the encrypted counter block with the current chunk
-        for (j, byte) in chunk.iter_mut().enumerate() {
-            *byte ^= cipher_block[j];
+        for (byte, k) in chunk.iter_mut().zip(cipher_block.iter()) {
+            *byte ^= k;
         }
     }
 }
 
+/// Compares two tags in constant time
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
 /// Funktion fuer die Schlüsselerweiterung
 /// * `key` - The key to expand
 /// * `nk` - Number  of  32-bit  words  comprising  the  Cipher  Key. For  this standard, Nk = 4, 6, or 8.
 /// * `nr` - Number of rounds, which is  a  function  of  Nk  and  Nb  (which  is fixed). For this standard, Nr = 10, 12, or 14.
-fn key_expansion_v2(key: &[u8], nk: usize, nr: usize) -> Vec<u8> {
+pub(crate) fn key_expansion_v2(key: &[u8], nk: usize, nr: usize) -> Vec<u8> {
     let mut words: Vec<u8> = vec![0; (nr + 1) * 4 * 4];
     // Copy the original key into the first part of the words vector
     words[..key.len()].copy_from_slice(key);
@@ -128,23 +374,23 @@ fn rotate(input: &mut [u8]) {
 }
 
 #[inline]
-fn aes_v2(mut stage: &mut [u8], keys: &[u8], nr: usize) {
+pub(crate) fn aes_v2(stage: &mut [u8], keys: &[u8], nr: usize) {
     rotate(stage);
-    add_round_keys_v2(&mut stage, &keys, 0);
+    add_round_keys_v2(stage, keys, 0);
 
     for i in 1..nr + 1 {
-        shift_rows_v3(&mut stage);
+        shift_rows_v3(stage);
         if i < nr {
-            mix_columns_v2(&mut stage);
+            mix_columns_v2(stage);
         }
-        add_round_keys_v2(&mut stage, &keys, i);
+        add_round_keys_v2(stage, keys, i);
     }
     rotate(stage);
 }
 
 #[inline]
 fn shift_rows_v3(stage: &mut [u8]) {
-    stage.copy_from_slice(&mut [
+    stage.copy_from_slice(&[
         SBOX[stage[0] as usize] , SBOX[stage[1] as usize] , SBOX[stage[2] as usize] , SBOX[stage[3] as usize] , // 1. row
         SBOX[stage[5] as usize] , SBOX[stage[6] as usize] , SBOX[stage[7] as usize] , SBOX[stage[4] as usize] , // 2. row
         SBOX[stage[10]as usize] , SBOX[stage[11]as usize] , SBOX[stage[8] as usize] , SBOX[stage[9] as usize] , // 3. row
@@ -152,6 +398,32 @@ fn shift_rows_v3(stage: &mut [u8]) {
     ]);
 }
 
+/// Inverse cipher, decrypts a single block with the same expanded key as `aes_v2`
+#[inline]
+pub(crate) fn inv_aes_v2(stage: &mut [u8], keys: &[u8], nr: usize) {
+    rotate(stage);
+    add_round_keys_v2(stage, keys, nr);
+
+    for i in (0..nr).rev() {
+        inv_shift_rows_v3(stage);
+        add_round_keys_v2(stage, keys, i);
+        if i > 0 {
+            inv_mix_columns_v2(stage);
+        }
+    }
+    rotate(stage);
+}
+
+#[inline]
+fn inv_shift_rows_v3(stage: &mut [u8]) {
+    stage.copy_from_slice(&[
+        INV_SBOX[stage[0] as usize] , INV_SBOX[stage[1] as usize] , INV_SBOX[stage[2] as usize] , INV_SBOX[stage[3] as usize] , // 1. row
+        INV_SBOX[stage[7] as usize] , INV_SBOX[stage[4] as usize] , INV_SBOX[stage[5] as usize] , INV_SBOX[stage[6] as usize] , // 2. row
+        INV_SBOX[stage[10]as usize] , INV_SBOX[stage[11]as usize] , INV_SBOX[stage[8] as usize] , INV_SBOX[stage[9] as usize] , // 3. row
+        INV_SBOX[stage[13]as usize] , INV_SBOX[stage[14]as usize] , INV_SBOX[stage[15]as usize] , INV_SBOX[stage[12]as usize] , // 4. row
+    ]);
+}
+
 /// Funktion zur Mischung einer Spalte
 #[inline]
 fn mix_columns_v2(stage: &mut [u8]) {
@@ -168,6 +440,21 @@ fn mix_columns_v2(stage: &mut [u8]) {
     }
 }
 
+/// Inverse Spaltenmischung fuer die Entschluesselung
+#[inline]
+fn inv_mix_columns_v2(stage: &mut [u8]) {
+    for column in 0..4 {
+        let t0: u8 = stage[column];
+        let t1: u8 = stage[column + 4];
+        let t2: u8 = stage[column + 8];
+        let t3: u8 = stage[column + 12];
+        stage[column] = gmul(t0, 0xe) ^ gmul(t1, 0xb) ^ gmul(t2, 0xd) ^ gmul(t3, 0x9);
+        stage[column + 4] = gmul(t0, 0x9) ^ gmul(t1, 0xe) ^ gmul(t2, 0xb) ^ gmul(t3, 0xd);
+        stage[column + 8] = gmul(t0, 0xd) ^ gmul(t1, 0x9) ^ gmul(t2, 0xe) ^ gmul(t3, 0xb);
+        stage[column + 12] = gmul(t0, 0xb) ^ gmul(t1, 0xd) ^ gmul(t2, 0x9) ^ gmul(t3, 0xe);
+    }
+}
+
 /// Funktion zur Multiplikation von zwei Zahlen in GF(2^8)
 #[inline]
 fn gmul(p: u8, q: u8) -> u8 {
@@ -189,82 +476,953 @@ fn gmul(p: u8, q: u8) -> u8 {
     r
 }
 
-/// Function to print bytes
-fn println_bytes(name_str: &str, bytes: &Vec<u8>) {
-    println!("{name_str} {:02x?}", bytes);
+/// Block cipher modes of operation supported by the tool
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Ctr,
+    Cbc,
+    Xts,
+    Cfb128,
+    Cfb8,
+    Cfb1,
+    Ofb,
+    GcmSiv,
+    Siv,
+    Ccm,
+    Ocb,
+    Eax,
+    Kw,
+    Kwp,
 }
 
-/// Function to handle encryption/decryption command with given parameters
-pub fn handle_aes_ctr_command(
-    command: String,
-    key_size: u16,
-    key_bytes: Vec<u8>,
-    iv_bytes: Vec<u8>,
-    input_file_path: PathBuf,
-    output_file_path: PathBuf,
-) {
-    println!("\n### Dummy printing ...");
-    println!(" - command           = {}", command);
-    println!(" - key_size          = {}", key_size);
-    println_bytes(" - key_bytes         = ", &key_bytes);
-    println_bytes(" - iv_bytes          = ", &iv_bytes);
-    println!(" - input_file_path   = {}", input_file_path.display());
-    println!(" - output_file_path  = {}", output_file_path.display());
-
-    // Determine key and round count based on key size
-    let (nk, nr) = match key_size {
-        128 => (4, 10),
-        192 => (6, 12),
-        256 => (8, 14),
-        _ => panic!("Unsupported key size"),
-    };
+impl Mode {
+    /// Parses a mode name as given on the command line (e.g. `ctr` or `cfb8`),
+    /// `cfb` is CFB128 like in openssl
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "ctr" => Some(Mode::Ctr),
+            "cbc" => Some(Mode::Cbc),
+            "xts" => Some(Mode::Xts),
+            "cfb" | "cfb128" => Some(Mode::Cfb128),
+            "cfb8" => Some(Mode::Cfb8),
+            "cfb1" => Some(Mode::Cfb1),
+            "ofb" => Some(Mode::Ofb),
+            "gcm-siv" => Some(Mode::GcmSiv),
+            "siv" => Some(Mode::Siv),
+            "ccm" => Some(Mode::Ccm),
+            "ocb" => Some(Mode::Ocb),
+            "eax" => Some(Mode::Eax),
+            "kw" => Some(Mode::Kw),
+            "kwp" => Some(Mode::Kwp),
+            _ => None,
+        }
+    }
 
-    let iv_bytes = Arc::new(iv_bytes);
-    let keys = Arc::new(key_expansion_v2(&key_bytes, nk, nr));
+    /// Name of the mode as given on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Ctr => "ctr",
+            Mode::Cbc => "cbc",
+            Mode::Xts => "xts",
+            Mode::Cfb128 => "cfb",
+            Mode::Cfb8 => "cfb8",
+            Mode::Cfb1 => "cfb1",
+            Mode::Ofb => "ofb",
+            Mode::GcmSiv => "gcm-siv",
+            Mode::Siv => "siv",
+            Mode::Ccm => "ccm",
+            Mode::Ocb => "ocb",
+            Mode::Eax => "eax",
+            Mode::Kw => "kw",
+            Mode::Kwp => "kwp",
+        }
+    }
 
-    let input_file = File::open(&input_file_path).expect("Failed to open input file");
-    let file_size = input_file.metadata().unwrap().len();
+    /// Whether the mode takes two AES keys of the same size, i.e. twice the key length
+    pub fn double_key(self) -> bool {
+        self == Mode::Xts || self == Mode::Siv
+    }
+
+    /// Whether the mode wraps keys (`wrap-key` and `unwrap-key`) instead of encrypting files
+    pub fn key_wrap(self) -> bool {
+        self == Mode::Kw || self == Mode::Kwp
+    }
 
-    let output_file = OpenOptions::new()
+    /// Whether the mode authenticates the data, and thus takes associated data
+    pub fn authenticated(self) -> bool {
+        matches!(
+            self,
+            Mode::GcmSiv | Mode::Siv | Mode::Ccm | Mode::Ocb | Mode::Eax
+        )
+    }
+
+    /// Whether `openssl enc` can derive key and IV from a password for this mode
+    pub fn password_based(self) -> bool {
+        matches!(
+            self,
+            Mode::Ctr | Mode::Cbc | Mode::Cfb128 | Mode::Cfb8 | Mode::Cfb1 | Mode::Ofb
+        )
+    }
+
+    /// Shortest and longest IV or nonce in bytes. XTS takes its tweak from the sector
+    /// number, SIV is deterministic and key wrap uses a fixed initial value, so they
+    /// need none.
+    pub fn iv_sizes(self) -> (usize, usize) {
+        match self {
+            Mode::Xts | Mode::Siv | Mode::Kw | Mode::Kwp => (0, 0),
+            Mode::GcmSiv => (gcm_siv::NONCE_SIZE, gcm_siv::NONCE_SIZE),
+            Mode::Ccm => ccm::NONCE_SIZES,
+            Mode::Ocb => ocb::NONCE_SIZES,
+            Mode::Eax => eax::NONCE_SIZES,
+            _ => (BLOCK_SIZE, BLOCK_SIZE),
+        }
+    }
+}
+
+/// Mode of operation and its mode specific settings
+#[derive(Clone, Debug)]
+pub struct CipherOptions {
+    pub mode: Mode,
+    /// Size of an XTS data unit in bytes, each sector gets its own tweak
+    pub sector_size: usize,
+    /// Associated data authenticated by GCM-SIV, SIV, CCM, OCB and EAX (after the
+    /// container header, which they authenticate as well)
+    pub aad: Option<Vec<u8>>,
+    /// Length of the CCM, OCB or EAX authentication tag in bytes
+    pub tag_size: usize,
+    /// Layout of the CTR counter block
+    pub counter_layout: CounterLayout,
+    /// Byte order the CTR counter field is incremented in
+    pub counter_endianness: CounterEndianness,
+    /// Derive key and IV from a password and use the `Salted__` header of `openssl enc`
+    pub password: Option<PasswordOptions>,
+    /// Derive key and IV from a passphrase with a memory-hard KDF, the KDF and its
+    /// parameters are stored in the container header
+    pub passphrase: Option<PassphraseOptions>,
+    /// Headerless output like `openssl enc` instead of the container format, implied
+    /// by `password`
+    pub raw: bool,
+    /// Encrypt with a random data key, which is wrapped under the given key and
+    /// stored in the container header
+    pub envelope: bool,
+    /// Derive key and IV of the file from the given master key with HKDF, bound to
+    /// this relative path of the input file. Decryption reads the path from the
+    /// container header.
+    pub master: Option<String>,
+}
+
+/// Expands an AES-128, AES-192 or AES-256 key and returns the round keys and round count
+pub(crate) fn expand_key(key: &[u8]) -> Result<(SecretBytes, usize), AesError> {
+    let (nk, nr) = match key.len() {
+        16 => (4, 10),
+        24 => (6, 12),
+        32 => (8, 14),
+        len => return Err(AesError::InvalidKeyLength(len)),
+    };
+    Ok((SecretBytes::new(key_expansion_v2(key, nk, nr)), nr))
+}
+
+/// Opens (and truncates) the output file
+pub(crate) fn open_output_file(output_file_path: &Path) -> io::Result<File> {
+    OpenOptions::new()
         .write(true)
         .create(true)
-        .open(&output_file_path)
-        .expect("Failed to open output file");
+        .truncate(true)
+        .open(output_file_path)
+}
+
+/// Writes the output to a temporary file next to it, which replaces the output once
+/// `write` succeeded. The output is thus never left half written and may even be
+/// the input file.
+pub(crate) fn replace_atomically<F>(output_file_path: &Path, write: F) -> Result<(), AesError>
+where
+    F: FnOnce(File) -> Result<(), AesError>,
+{
+    let name = output_file_path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "output path has no file name")
+    })?;
+    let mut temporary_name = OsString::from(".");
+    temporary_name.push(name);
+    temporary_name.push(".tmp");
+    let temporary_path = output_file_path.with_file_name(temporary_name);
+    let create = || {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temporary_path)
+    };
+    // A temporary file left behind by a run that crashed is removed
+    let temporary_file = match create() {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            fs::remove_file(&temporary_path).and_then(|_| create())
+        }
+        result => result,
+    }
+    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", temporary_path.display(), e)))?;
+    let result = temporary_file
+        .try_clone()
+        .map_err(AesError::from)
+        .and_then(write)
+        .and_then(|_| Ok(temporary_file.sync_all()?))
+        .and_then(|_| Ok(fs::rename(&temporary_path, output_file_path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+/// Reads until `buf` is full or the end of the input is reached
+pub(crate) fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match reader.read(&mut buf[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => bytes_read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(bytes_read)
+}
+
+/// Reads `len` bytes from the current position of `file` chunk by chunk on the current
+/// thread, `f` gets each chunk and the index of its first block
+pub(crate) fn for_each_chunk<F>(file: &File, len: u64, mut f: F) -> Result<(), AesError>
+where
+    F: FnMut(&mut [u8], u64),
+{
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, file.take(len));
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    let mut block = 0;
+    loop {
+        let bytes_read = read_full(&mut reader, &mut chunk)?;
+        if bytes_read == 0 {
+            break;
+        }
+        f(&mut chunk[..bytes_read], block);
+        block += (bytes_read / BLOCK_SIZE) as u64;
+    }
+    Ok(())
+}
+
+/// Processes the input file chunk by chunk on the current thread, for modes where
+/// every chunk depends on the state left behind by the previous one. Both files are
+/// processed from their current position on, e.g. behind a header.
+pub(crate) fn process_file_sequential<F>(
+    input_file: File,
+    output_file: File,
+    mut job: F,
+) -> Result<(), AesError>
+where
+    F: FnMut(&mut [u8]),
+{
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, input_file);
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, output_file);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let bytes_read = read_full(&mut reader, &mut chunk)?;
+        if bytes_read == 0 {
+            break;
+        }
+        job(&mut chunk[..bytes_read]);
+        writer.write_all(&chunk[..bytes_read])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Part of the input file that is processed and where the result goes in the output
+/// file, e.g. to leave out a header or a trailing tag
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Payload {
+    /// Offset of the first payload byte in the input file
+    pub input_offset: u64,
+    /// Number of payload bytes
+    pub len: u64,
+    /// Offset the processed payload is written to in the output file
+    pub output_offset: u64,
+}
+
+/// Processes the input file chunk by chunk on the thread pool, see `process_payload_chunked`.
+/// The payload runs from the current position of the input file to its end and is
+/// written from the current position of the output file on.
+pub(crate) fn process_file_chunked<F>(
+    mut input_file: File,
+    mut output_file: File,
+    lookbehind: usize,
+    job: F,
+) -> Result<(), AesError>
+where
+    F: Fn(usize, &[u8], &mut Vec<u8>, bool) -> Result<(), AesError> + Send + Sync + 'static,
+{
+    let input_offset = input_file.stream_position()?;
+    let payload = Payload {
+        input_offset,
+        len: input_file.metadata()?.len() - input_offset,
+        output_offset: output_file.stream_position()?,
+    };
+    process_payload_chunked(input_file, output_file, payload, lookbehind, job)
+}
+
+/// Processes the payload chunk by chunk on the thread pool.
+///
+/// `job` is called with the chunk index, the `lookbehind` bytes preceding the chunk
+/// (empty for the first chunk), the chunk itself and whether it is the last chunk.
+/// Whatever the job leaves in the chunk is written to the output file at the offset
+/// the chunk was read from.
+pub(crate) fn process_payload_chunked<F>(
+    input_file: File,
+    output_file: File,
+    payload: Payload,
+    lookbehind: usize,
+    job: F,
+) -> Result<(), AesError>
+where
+    F: Fn(usize, &[u8], &mut Vec<u8>, bool) -> Result<(), AesError> + Send + Sync + 'static,
+{
     let writer = Arc::new(Mutex::new(BufWriter::with_capacity(
         CHUNK_SIZE,
         output_file,
     )));
+    run_payload_chunked(input_file, Some(writer.clone()), payload, lookbehind, job)?;
+
+    match Arc::try_unwrap(writer) {
+        Ok(writer) => {
+            writer
+                .into_inner()
+                .unwrap()
+                .into_inner()
+                .map_err(|e| e.into_error())?;
+        }
+        Err(_) => unreachable!("all workers have finished"),
+    }
+    Ok(())
+}
+
+/// Like `process_payload_chunked`, but the processed chunks are dropped instead of
+/// written, e.g. to verify a tag before any plaintext is released
+pub(crate) fn scan_payload_chunked<F>(
+    input_file: File,
+    payload: Payload,
+    lookbehind: usize,
+    job: F,
+) -> Result<(), AesError>
+where
+    F: Fn(usize, &[u8], &mut Vec<u8>, bool) -> Result<(), AesError> + Send + Sync + 'static,
+{
+    run_payload_chunked(input_file, None, payload, lookbehind, job)
+}
 
+fn run_payload_chunked<F>(
+    input_file: File,
+    writer: Option<Arc<Mutex<BufWriter<File>>>>,
+    payload: Payload,
+    lookbehind: usize,
+    job: F,
+) -> Result<(), AesError>
+where
+    F: Fn(usize, &[u8], &mut Vec<u8>, bool) -> Result<(), AesError> + Send + Sync + 'static,
+{
     let nr_t: u64 = 4; //Anzahl von Thread.
-    let num_chunks = (file_size as f64 / CHUNK_SIZE as f64).ceil() as usize;
+    let num_chunks = (payload.len as f64 / CHUNK_SIZE as f64).ceil() as usize;
     let pool = ThreadPool::new(nr_t as usize);
     let reader = Arc::new(Mutex::new(BufReader::with_capacity(CHUNK_SIZE, input_file)));
+    let job = Arc::new(job);
+    let failure: Arc<Mutex<Option<AesError>>> = Arc::new(Mutex::new(None));
 
     for chunk_id in 0..num_chunks {
-        let keys = Arc::clone(&keys);
-        let iv = Arc::clone(&iv_bytes);
         let writer = writer.clone();
         let reader = reader.clone();
+        let job = job.clone();
+        let failure = failure.clone();
 
         pool.execute(move || {
-            let mut chunk = vec![0; CHUNK_SIZE];
-            let starting_pos = chunk_id * CHUNK_SIZE;
-            let starting_block = (starting_pos / BLOCK_SIZE) as u64;
-
-            {
-                let mut reader = reader.lock().unwrap();
-                reader.seek(SeekFrom::Start(starting_pos as u64)).unwrap();
-                let bytes_read = reader.read(&mut chunk).unwrap_or(0);
-                chunk.truncate(bytes_read);
-            }
+            let starting_pos = (chunk_id * CHUNK_SIZE) as u64;
+            let chunk_len = (payload.len - starting_pos).min(CHUNK_SIZE as u64) as usize;
+            let mut chunk = vec![0; chunk_len];
+            let mut previous = Vec::new();
 
-            process_chunk(&mut chunk, &keys, &iv, nr, starting_block);
+            let result: Result<(), AesError> = (|| {
+                {
+                    let mut reader = reader.lock().unwrap();
+                    let input_pos = payload.input_offset + starting_pos;
+                    if chunk_id > 0 && lookbehind > 0 {
+                        previous.resize(lookbehind, 0);
+                        reader.seek(SeekFrom::Start(input_pos - lookbehind as u64))?;
+                        reader.read_exact(&mut previous)?;
+                    } else {
+                        reader.seek(SeekFrom::Start(input_pos))?;
+                    }
+                    reader.read_exact(&mut chunk)?;
+                }
 
-            let mut writer = writer.lock().unwrap();
-            writer
-                .seek(SeekFrom::Start((chunk_id * CHUNK_SIZE) as u64))
-                .unwrap();
-            writer.write_all(&chunk).unwrap();
+                job(chunk_id, &previous, &mut chunk, chunk_id == num_chunks - 1)?;
+
+                if let Some(writer) = writer {
+                    let mut writer = writer.lock().unwrap();
+                    writer.seek(SeekFrom::Start(payload.output_offset + starting_pos))?;
+                    writer.write_all(&chunk)?;
+                }
+                Ok(())
+            })();
+
+            if let Err(e) = result {
+                failure.lock().unwrap().get_or_insert(e);
+            }
         });
     }
+    // Wait for all workers before looking at the results
+    drop(pool);
+
+    if let Some(e) = failure.lock().unwrap().take() {
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Key and IV derived from a password or passphrase
+struct DerivedKey {
+    key: SecretBytes,
+    iv: Vec<u8>,
+    /// `Salted__` header to write in front of the output, empty otherwise
+    header: Vec<u8>,
+    /// KDF and salt of a passphrase for the container header
+    kdf: Option<(Kdf, [u8; passphrase::SALT_SIZE])>,
+}
+
+/// Derives key and IV from the password or passphrase, if one is given. Encryption
+/// draws a fresh salt, decryption reads the `Salted__` header from the input file or
+/// takes `kdf` from the container header.
+fn derive_from_password(
+    encrypt: bool,
+    options: &CipherOptions,
+    key_len: usize,
+    iv_len: usize,
+    input_file: &mut File,
+    kdf: Option<(Kdf, [u8; passphrase::SALT_SIZE])>,
+) -> Result<Option<DerivedKey>, AesError> {
+    if let Some(ref password) = options.password {
+        let (salt, header) = if encrypt {
+            let salt = match password.salt {
+                Some(salt) => salt,
+                None => {
+                    let mut salt = [0u8; openssl::SALT_SIZE];
+                    random::fill(&mut salt)?;
+                    salt
+                }
+            };
+            (salt, openssl::header(&salt))
+        } else {
+            (openssl::read_header(input_file)?, Vec::new())
+        };
+        let (key, iv) = openssl::derive_key_iv(password, &salt, key_len, iv_len);
+        Ok(Some(DerivedKey {
+            key,
+            iv,
+            header,
+            kdf: None,
+        }))
+    } else if let Some(ref passphrase) = options.passphrase {
+        let (kdf, salt) = match kdf {
+            Some(kdf) => kdf,
+            None if encrypt => {
+                let mut salt = [0u8; passphrase::SALT_SIZE];
+                random::fill(&mut salt)?;
+                (passphrase.kdf, salt)
+            }
+            None => return Err(AesError::InvalidHeader),
+        };
+        let mut output = SecretBytes::zeroed(key_len + iv_len);
+        kdf.derive(&passphrase.passphrase, &salt, &mut output);
+        Ok(Some(DerivedKey {
+            key: SecretBytes::new(output[..key_len].to_vec()),
+            iv: output[key_len..].to_vec(),
+            header: Vec::new(),
+            kdf: Some((kdf, salt)),
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Function to handle encryption/decryption and key wrap commands with given parameters
+pub fn handle_aes_ctr_command(
+    command: String,
+    options: CipherOptions,
+    key_size: u16,
+    key_bytes: &[u8],
+    iv_bytes: Vec<u8>,
+    input_file_path: PathBuf,
+    output_file_path: PathBuf,
+) -> Result<(), AesError> {
+    log_verbose!("\n### Settings ...");
+    log_verbose!(" - command           = {}", command);
+    log_verbose!(" - input_file_path   = {}", input_file_path.display());
+    log_verbose!(" - output_file_path  = {}", output_file_path.display());
+
+    // Key wrap works on a whole key instead of a stream
+    if options.mode.key_wrap() {
+        log_verbose!(" - mode              = {:?}", options.mode);
+        log_verbose!(" - key_fingerprint   = {}", log::fingerprint(key_bytes));
+        return kw::process_file(
+            key_bytes,
+            command == "wrap-key",
+            options.mode == Mode::Kwp,
+            &input_file_path,
+            &output_file_path,
+        );
+    }
+
+    // Unless the output is raw, decryption takes the mode and its settings from the
+    // container header instead
+    let encrypt = command == "encrypt";
+    let raw = options.raw || options.password.is_some();
+    let mut options = options;
+    let mut input_file = File::open(&input_file_path)?;
+    let container = if encrypt || raw {
+        None
+    } else {
+        let container = container::Header::read(&mut input_file)?;
+        container.apply(&mut options);
+        Some(container)
+    };
+    let key_size = container.as_ref().map_or(key_size, |c| c.key_size);
+
+    // The output starts with a header, so the modes work on files positioned behind
+    // it. CCM takes the shortest nonce, which leaves the most room for the message
+    // length.
+    let (iv_min, iv_max) = options.mode.iv_sizes();
+    let iv_len = if options.mode == Mode::Ccm {
+        iv_min
+    } else {
+        iv_max
+    };
+    let mut derived_key = None;
+    let (mut iv_bytes, salted_header, kdf) = match derive_from_password(
+        encrypt,
+        &options,
+        key_size as usize / 8,
+        iv_len,
+        &mut input_file,
+        container.as_ref().and_then(|c| c.kdf),
+    )? {
+        Some(derived) => {
+            derived_key = Some(derived.key);
+            (derived.iv, derived.header, derived.kdf)
+        }
+        None => match container {
+            Some(ref container) => (container.iv.clone(), Vec::new(), None),
+            None => (iv_bytes, Vec::new(), None),
+        },
+    };
+    let key_bytes = derived_key.as_deref().unwrap_or(key_bytes);
+
+    // A master key yields a key and IV of their own for every file
+    let master = match container {
+        Some(ref container) => container.master.clone(),
+        None if encrypt => match options.master {
+            Some(ref path) => {
+                let mut salt = [0u8; master_key::SALT_SIZE];
+                random::fill(&mut salt)?;
+                Some(FileDerivation {
+                    salt,
+                    path: path.clone(),
+                })
+            }
+            None => None,
+        },
+        None => None,
+    };
+    let file_key = match master {
+        Some(ref file) => {
+            let (key, iv) = master_key::derive_key_iv(
+                key_bytes,
+                file,
+                key_size as usize / 8,
+                iv_len,
+                options.counter_layout,
+                options.counter_endianness,
+            );
+            iv_bytes = iv;
+            Some(key)
+        }
+        None => None,
+    };
+    let key_bytes = file_key.as_deref().unwrap_or(key_bytes);
+
+    // With envelope encryption the given key only wraps the data key, so rotating it
+    // means rewrapping instead of reencrypting the payload
+    let mut recipients = Vec::new();
+    let data_key = match container {
+        Some(ref container) if !container.recipients.is_empty() => {
+            Some(envelope::unwrap_data_key(container, key_bytes)?)
+        }
+        None if encrypt && options.envelope => {
+            let mut data_key = SecretBytes::zeroed(key_size as usize / 8);
+            random::fill(&mut data_key)?;
+            recipients.push(envelope::recipient(key_bytes, &data_key)?);
+            Some(data_key)
+        }
+        _ => None,
+    };
+    let key_bytes = data_key.as_deref().unwrap_or(key_bytes);
+    if key_bytes.len() * 8 != key_size as usize {
+        return Err(AesError::InvalidKeyLength(key_bytes.len()));
+    }
+    // The settings as used, i.e. after reading the container and deriving the key
+    log_verbose!(" - mode              = {:?}", options.mode);
+    log_verbose!(" - key_size          = {}", key_size);
+    log_verbose!(" - key_fingerprint   = {}", log::fingerprint(key_bytes));
+    log_verbose!(" - iv_bytes          = {}", hex::encode(&iv_bytes));
+
+    // A wrong key is caught by the key check value before anything is decrypted,
+    // even in modes without authentication
+    let kcv = kcv::for_mode(key_bytes, options.mode)?;
+    log_verbose!(" - key_check_value   = {}", kcv::to_hex(&kcv));
+    if let Some(ref container) = container {
+        if container.kcv != kcv {
+            return Err(AesError::KeyCheckFailed);
+        }
+    }
+
+    // Authenticated modes take the container header (except the wrapped data keys) as
+    // associated data, so it cannot be altered without failing the tag
+    let container = match container {
+        Some(container) => Some(container),
+        None if !raw => {
+            // The key check value is the encryption of the zero block, which with an
+            // all-zero IV is the first keystream block of CTR, OFB and CFB
+            if !iv_bytes.is_empty() && iv_bytes.iter().all(|&byte| byte == 0) {
+                return Err(AesError::ZeroIv);
+            }
+            Some(container::Header::new(
+                &options, key_size, &iv_bytes, kcv, kdf, master, recipients,
+            ))
+        }
+        None => None,
+    };
+    let (header, authenticated) = match container {
+        Some(container) => (container.to_bytes(), container.authenticated_bytes()),
+        None => (salted_header.clone(), salted_header),
+    };
+    let aad = [&authenticated[..], options.aad.as_deref().unwrap_or(&[])].concat();
+    // Authenticated modes create the output file themselves once the tag is verified
+    let open_output = || -> Result<File, AesError> {
+        let mut output_file = open_output_file(&output_file_path)?;
+        if encrypt {
+            output_file.write_all(&header)?;
+        }
+        Ok(output_file)
+    };
+
+    // XTS splits the key into a data key and a tweak key itself
+    if options.mode == Mode::Xts {
+        let xts = Arc::new(Xts::new(key_bytes)?);
+        return xts::process_file(
+            xts,
+            encrypt,
+            options.sector_size,
+            input_file,
+            open_output()?,
+        );
+    }
+
+    // AES-GCM-SIV derives its own keys per nonce
+    if options.mode == Mode::GcmSiv {
+        let siv = GcmSiv::new(key_bytes)?;
+        return if encrypt {
+            gcm_siv::encrypt_file(&siv, &iv_bytes, &aad, input_file, open_output()?)
+        } else {
+            gcm_siv::decrypt_file(&siv, &iv_bytes, &aad, input_file, &output_file_path)
+        };
+    }
+
+    if options.mode == Mode::Ccm {
+        let ccm = Ccm::new(key_bytes, options.tag_size)?;
+        return if encrypt {
+            ccm::encrypt_file(&ccm, &iv_bytes, &aad, input_file, open_output()?)
+        } else {
+            ccm::decrypt_file(&ccm, &iv_bytes, &aad, input_file, &output_file_path)
+        };
+    }
+
+    if options.mode == Mode::Ocb {
+        let ocb = Arc::new(Ocb::new(key_bytes, options.tag_size)?);
+        return if encrypt {
+            ocb::encrypt_file(&ocb, &iv_bytes, &aad, input_file, open_output()?)
+        } else {
+            ocb::decrypt_file(&ocb, &iv_bytes, &aad, input_file, &output_file_path)
+        };
+    }
+
+    if options.mode == Mode::Eax {
+        let eax = Eax::new(key_bytes, options.tag_size)?;
+        return if encrypt {
+            eax::encrypt_file(&eax, &iv_bytes, &aad, input_file, open_output()?)
+        } else {
+            eax::decrypt_file(&eax, &iv_bytes, &aad, input_file, &output_file_path)
+        };
+    }
+
+    // AES-SIV splits the key into an S2V and a CTR key itself
+    if options.mode == Mode::Siv {
+        let siv = Siv::new(key_bytes)?;
+        let mut ad: Vec<&[u8]> = Vec::new();
+        if !authenticated.is_empty() {
+            ad.push(&authenticated);
+        }
+        ad.extend(options.aad.iter().map(|aad| &aad[..]));
+        return if encrypt {
+            siv::encrypt_file(&siv, &ad, input_file, open_output()?)
+        } else {
+            siv::decrypt_file(&siv, &ad, input_file, &output_file_path)
+        };
+    }
+
+    // Determine round keys and round count
+    let (keys, nr) = expand_key(key_bytes)?;
+
+    let iv_bytes = Arc::new(iv_bytes);
+    let keys = Arc::new(keys);
+
+    match options.mode {
+        Mode::Ctr => {
+            let (layout, endianness) = (options.counter_layout, options.counter_endianness);
+            let len = input_file.metadata()?.len() - input_file.stream_position()?;
+            let blocks = len.div_ceil(BLOCK_SIZE as u64);
+            // Checked before the output file is opened, which truncates it
+            check_counter_range(&iv_bytes, layout, endianness, 0, blocks)?;
+            process_file_chunked(
+                input_file,
+                open_output()?,
+                0,
+                move |chunk_id, _, chunk, _| {
+                    let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+                    process_chunk_with_layout(
+                        chunk,
+                        &keys,
+                        &iv_bytes,
+                        nr,
+                        starting_block,
+                        layout,
+                        endianness,
+                    );
+                    Ok(())
+                },
+            )
+        }
+        Mode::Cbc if encrypt => cbc::encrypt_file(&keys, nr, &iv_bytes, input_file, open_output()?),
+        Mode::Cbc => cbc::decrypt_file(keys, nr, iv_bytes, input_file, &output_file_path),
+        Mode::Cfb128 | Mode::Cfb8 | Mode::Cfb1 => {
+            let segment = CfbSegment::for_mode(options.mode);
+            if encrypt {
+                cfb::encrypt_file(&keys, nr, &iv_bytes, segment, input_file, open_output()?)
+            } else {
+                cfb::decrypt_file(keys, nr, iv_bytes, segment, input_file, open_output()?)
+            }
+        }
+        Mode::Ofb => ofb::process_file(&keys, nr, &iv_bytes, input_file, open_output()?),
+        Mode::Xts
+        | Mode::GcmSiv
+        | Mode::Siv
+        | Mode::Ccm
+        | Mode::Ocb
+        | Mode::Eax
+        | Mode::Kw
+        | Mode::Kwp => {
+            unreachable!("handled above")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    const LAYOUTS: [CounterLayout; 4] = [
+        CounterLayout::Full128,
+        CounterLayout::Split64,
+        CounterLayout::Split96 { check_wrap: true },
+        CounterLayout::Split96 { check_wrap: false },
+    ];
+
+    /// A counter block with 16 blocks left in the counter field of `layout`, the
+    /// nonce in front of it is all ones so that a carry into it would show
+    fn counter_with_16_blocks_left(
+        layout: CounterLayout,
+        endianness: CounterEndianness,
+    ) -> [u8; BLOCK_SIZE] {
+        let mut counter = [0xff; BLOCK_SIZE];
+        match endianness {
+            CounterEndianness::Big => counter[BLOCK_SIZE - 1] = 0xf0,
+            CounterEndianness::Little => counter[BLOCK_SIZE - layout.counter_size()] = 0xf0,
+        }
+        counter
+    }
+
+    #[test]
+    fn counter_range_ends_at_last_block() {
+        for &endianness in [CounterEndianness::Big, CounterEndianness::Little].iter() {
+            for &layout in LAYOUTS.iter() {
+                let counter = counter_with_16_blocks_left(layout, endianness);
+                let check = |start, blocks| {
+                    check_counter_range(&counter, layout, endianness, start, blocks)
+                };
+                assert!(check(0, 16).is_ok(), "{:?}", layout);
+                assert!(check(15, 1).is_ok(), "{:?}", layout);
+                assert!(check(16, 0).is_ok(), "{:?}", layout);
+                if layout.check_wrap() {
+                    assert!(matches!(check(0, 17), Err(AesError::CounterOverflow)));
+                    assert!(matches!(check(16, 1), Err(AesError::CounterOverflow)));
+                } else {
+                    assert!(check(0, 17).is_ok());
+                    assert!(check(16, 1).is_ok());
+                }
+            }
+        }
+    }
+
+    /// A zero counter field allows 2^(8 * counter size) blocks
+    #[test]
+    fn counter_range_of_zero_counter() {
+        let zero = [0u8; BLOCK_SIZE];
+        let check = |layout, start, blocks| {
+            check_counter_range(&zero, layout, CounterEndianness::Big, start, blocks)
+        };
+        assert!(check(CounterLayout::Full128, u64::MAX, u64::MAX).is_ok());
+        assert!(check(CounterLayout::Split64, 1, u64::MAX).is_ok());
+        assert!(check(CounterLayout::Split64, 2, u64::MAX).is_err());
+        let split96 = CounterLayout::Split96 { check_wrap: true };
+        assert!(check(split96, 0, 1 << 32).is_ok());
+        assert!(check(split96, 0, (1 << 32) + 1).is_err());
+        let wrapping = CounterLayout::Split96 { check_wrap: false };
+        assert!(check(wrapping, 0, (1 << 32) + 1).is_ok());
+    }
+
+    /// Keystream of AES-128 with the key 000102... for a little-endian counter field
+    /// starting at ff00..., the second block has to use 0001... after the carry
+    #[test]
+    fn little_endian_keystream_carries_into_next_byte() {
+        let vectors = [
+            (
+                CounterLayout::Full128,
+                "ff000000000000000000000000000000",
+                "e703905ae4398796f01495329e43dac79eb1b63c7efe31c9a46bb987baaf3908",
+            ),
+            (
+                CounterLayout::Split64,
+                "1111111111111111ff00000000000000",
+                "02e154c6f298eeb314f3ae035b1cf7caaa7895f726094c17e6746dc2b3f70c12",
+            ),
+            (
+                CounterLayout::Split96 { check_wrap: true },
+                "111111111111111111111111ff000000",
+                "4198d576335017bb729ea2551c04763835c56cd2c5a403d54a6624a09579c794",
+            ),
+        ];
+        let key: Vec<u8> = (0..16).collect();
+        let (keys, nr) = expand_key(&key).unwrap();
+        for (layout, counter, keystream) in vectors.iter() {
+            let counter = hex::decode(counter).unwrap();
+            let mut blocks = [0u8; 2 * BLOCK_SIZE];
+            let endianness = CounterEndianness::Little;
+            process_chunk_with_layout(&mut blocks, &keys, &counter, nr, 0, *layout, endianness);
+            assert_eq!(hex::encode(&blocks[..]), *keystream, "{:?}", layout);
+
+            // Starting at the second block gives the same keystream
+            let mut block = [0u8; BLOCK_SIZE];
+            process_chunk_with_layout(&mut block, &keys, &counter, nr, 1, *layout, endianness);
+            assert_eq!(block[..], blocks[BLOCK_SIZE..]);
+        }
+    }
+
+    /// A fresh IV leaves the whole counter range of split layouts available
+    #[test]
+    fn fresh_iv_starts_counter_field() {
+        let big = CounterEndianness::Big;
+        let mut iv = [0xaa; BLOCK_SIZE];
+        CounterLayout::Split64.start_counter(&mut iv, big);
+        assert_eq!(iv[..8], [0xaa; 8]);
+        assert_eq!(iv[8..], [0; 8]);
+        let split96 = CounterLayout::Split96 { check_wrap: true };
+        split96.start_counter(&mut iv, big);
+        assert_eq!(iv[12..], [0, 0, 0, 1]);
+        split96.start_counter(&mut iv, CounterEndianness::Little);
+        assert_eq!(iv[12..], [1, 0, 0, 0]);
+        assert_eq!(
+            split96.blocks_left(&iv, CounterEndianness::Little),
+            (1 << 32) - 1
+        );
+
+        split96.fresh_iv(&mut iv, big).unwrap();
+        assert_eq!(iv[12..], [0, 0, 0, 1]);
+        let mut full = [0u8; BLOCK_SIZE];
+        CounterLayout::Full128.fresh_iv(&mut full, big).unwrap();
+        assert_ne!(full, [0u8; BLOCK_SIZE]);
+    }
+
+    /// A temporary file left behind by a crashed run does not block later runs
+    #[test]
+    fn replace_atomically_removes_stale_temporary_file() {
+        let dir = std::env::temp_dir();
+        let name = format!("replace-atomically-{}", std::process::id());
+        let output_path = dir.join(&name);
+        let temporary_path = dir.join(format!(".{}.tmp", name));
+        fs::write(&temporary_path, b"stale").unwrap();
+        replace_atomically(&output_path, |mut file| Ok(file.write_all(b"new")?)).unwrap();
+        assert_eq!(fs::read(&output_path).unwrap(), b"new");
+        assert!(!temporary_path.exists());
+        fs::remove_file(&output_path).unwrap();
+    }
+
+    /// RFC 3686 test vectors 1 to 3, AES-128 with `nonce || IV || counter` and the
+    /// counter starting at 1
+    #[test]
+    fn rfc3686_vectors() {
+        let vectors = [
+            (
+                "AE6852F8121067CC4BF7A5765577F39E",
+                "00000030000000000000000000000001",
+                "53696E676C6520626C6F636B206D7367",
+                "E4095D4FB7A7B3792D6175A3261311B8",
+            ),
+            (
+                "7E24067817FAE0D743D6CE1F32539163",
+                "006CB6DBC0543B59DA48D90B00000001",
+                "000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F",
+                "5104A106168A72D9790D41EE8EDAD388EB2E1EFC46DA57C8FCE630DF9141BE28",
+            ),
+            (
+                "7691BE035E5020A8AC6E618529F9A0DC",
+                "00E0017B27777F3F4A1786F000000001",
+                concat!(
+                    "000102030405060708090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F",
+                    "20212223"
+                ),
+                concat!(
+                    "C1CF48A89F2FFDD9CF4652E9EFDB72D74540A42BDE6D7836D59A5CEAAEF31053",
+                    "25B2072F"
+                ),
+            ),
+        ];
+        let layout = CounterLayout::Split96 { check_wrap: true };
+        for (key, counter, plaintext, ciphertext) in vectors.iter() {
+            let (keys, nr) = expand_key(&unhex(key)).unwrap();
+            let counter = unhex(counter);
+            let mut data = unhex(plaintext);
+            let blocks = data.len().div_ceil(BLOCK_SIZE) as u64;
+            check_counter_range(&counter, layout, CounterEndianness::Big, 0, blocks).unwrap();
+            process_chunk_with_layout(
+                &mut data,
+                &keys,
+                &counter,
+                nr,
+                0,
+                layout,
+                CounterEndianness::Big,
+            );
+            assert_eq!(hex::encode_upper(&data), *ciphertext);
+        }
+    }
 }
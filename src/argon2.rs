@@ -0,0 +1,353 @@
+use crate::blake2b::Blake2b;
+use crate::digest::Digest;
+use crate::secret::{self, SecretBytes};
+
+/// Number of 64-bit words in a memory block of 1 KiB
+const BLOCK_WORDS: usize = 128;
+/// Every lane is split into four slices, synchronization points between the lanes
+const SYNC_POINTS: usize = 4;
+const VERSION: u32 = 0x13;
+/// Type identifier of Argon2id
+const ARGON2ID: u32 = 2;
+
+type Block = [u64; BLOCK_WORDS];
+
+/// Cost parameters of Argon2id
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Argon2Params {
+    /// Memory size in KiB, at least 8 per lane
+    pub memory_kib: u32,
+    /// Number of passes over the memory
+    pub iterations: u32,
+    /// Degree of parallelism, the lanes are computed one after another here
+    pub lanes: u32,
+}
+
+/// Argon2id (RFC 9106) without secret or associated data, fills `output`
+pub fn argon2id(password: &[u8], salt: &[u8], params: &Argon2Params, output: &mut [u8]) {
+    argon2id_keyed(password, salt, &[], &[], params, output)
+}
+
+/// Argon2id (RFC 9106) with an optional secret and associated data, fills `output`
+pub fn argon2id_keyed(
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    associated_data: &[u8],
+    params: &Argon2Params,
+    output: &mut [u8],
+) {
+    let lanes = params.lanes as usize;
+    let segment_length = params.memory_kib as usize / (SYNC_POINTS * lanes);
+    let lane_length = segment_length * SYNC_POINTS;
+    let memory_blocks = lane_length * lanes;
+
+    let mut h0 = Blake2b::new();
+    for value in &[
+        params.lanes,
+        output.len() as u32,
+        params.memory_kib,
+        params.iterations,
+        VERSION,
+        ARGON2ID,
+    ] {
+        h0.update(&value.to_le_bytes());
+    }
+    for input in &[password, salt, secret, associated_data] {
+        h0.update(&(input.len() as u32).to_le_bytes());
+        h0.update(input);
+    }
+    let h0 = SecretBytes::new(h0.finalize());
+
+    let mut memory = vec![[0u64; BLOCK_WORDS]; memory_blocks];
+    let mut seed = [0u8; 72];
+    seed[..64].copy_from_slice(&h0);
+    for lane in 0..lanes {
+        seed[68..].copy_from_slice(&(lane as u32).to_le_bytes());
+        for column in 0..2 {
+            seed[64..68].copy_from_slice(&(column as u32).to_le_bytes());
+            let mut bytes = [0u8; 8 * BLOCK_WORDS];
+            hash_long(&seed, &mut bytes);
+            memory[lane * lane_length + column] = block_from_bytes(&bytes);
+            secret::wipe(&mut bytes);
+        }
+    }
+
+    let instance = Instance {
+        lanes,
+        segment_length,
+        lane_length,
+        memory_blocks,
+        iterations: params.iterations,
+    };
+    for pass in 0..params.iterations {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                instance.fill_segment(&mut memory, pass, lane, slice);
+            }
+        }
+    }
+
+    let mut last = memory[lane_length - 1];
+    for lane in 1..lanes {
+        for (a, b) in last
+            .iter_mut()
+            .zip(memory[lane * lane_length + lane_length - 1].iter())
+        {
+            *a ^= b;
+        }
+    }
+    let bytes = SecretBytes::new(last.iter().flat_map(|word| word.to_le_bytes()).collect());
+    hash_long(&bytes, output);
+
+    // Every memory block is derived from the password
+    for block in memory.iter_mut() {
+        secret::wipe(block);
+    }
+    secret::wipe(&mut last);
+    secret::wipe(&mut seed);
+}
+
+/// Memory layout shared by all segments
+struct Instance {
+    lanes: usize,
+    segment_length: usize,
+    lane_length: usize,
+    memory_blocks: usize,
+    iterations: u32,
+}
+
+impl Instance {
+    fn fill_segment(&self, memory: &mut [Block], pass: u32, lane: usize, slice: usize) {
+        // Argon2id uses data independent addressing for the first half of the first pass
+        let data_independent = pass == 0 && slice < SYNC_POINTS / 2;
+        let mut input_block = [0u64; BLOCK_WORDS];
+        let mut address_block = [0u64; BLOCK_WORDS];
+        if data_independent {
+            input_block[0] = pass as u64;
+            input_block[1] = lane as u64;
+            input_block[2] = slice as u64;
+            input_block[3] = self.memory_blocks as u64;
+            input_block[4] = self.iterations as u64;
+            input_block[5] = ARGON2ID as u64;
+        }
+
+        // The first two blocks of every lane are already filled
+        let starting_index = if pass == 0 && slice == 0 {
+            if data_independent {
+                next_addresses(&mut input_block, &mut address_block);
+            }
+            2
+        } else {
+            0
+        };
+
+        for index in starting_index..self.segment_length {
+            let current = lane * self.lane_length + slice * self.segment_length + index;
+            let previous = if current.is_multiple_of(self.lane_length) {
+                current + self.lane_length - 1
+            } else {
+                current - 1
+            };
+
+            let pseudo_random = if data_independent {
+                if index % BLOCK_WORDS == 0 {
+                    next_addresses(&mut input_block, &mut address_block);
+                }
+                address_block[index % BLOCK_WORDS]
+            } else {
+                memory[previous][0]
+            };
+
+            let reference_lane = if pass == 0 && slice == 0 {
+                lane
+            } else {
+                (pseudo_random >> 32) as usize % self.lanes
+            };
+            let reference_index = self.reference_index(
+                pass,
+                slice,
+                index,
+                pseudo_random as u32,
+                reference_lane == lane,
+            );
+            let reference = reference_lane * self.lane_length + reference_index;
+
+            let block = compress(&memory[previous], &memory[reference]);
+            if pass == 0 {
+                memory[current] = block;
+            } else {
+                for (a, b) in memory[current].iter_mut().zip(block.iter()) {
+                    *a ^= b;
+                }
+            }
+        }
+    }
+
+    /// Maps the pseudo-random value to a block of the reference lane that is already
+    /// computed and not in a segment being computed concurrently
+    fn reference_index(
+        &self,
+        pass: u32,
+        slice: usize,
+        index: usize,
+        pseudo_random: u32,
+        same_lane: bool,
+    ) -> usize {
+        let finished_slices = if pass == 0 {
+            slice * self.segment_length
+        } else {
+            self.lane_length - self.segment_length
+        };
+        let reference_area_size = if same_lane {
+            finished_slices + index - 1
+        } else if index == 0 {
+            finished_slices - 1
+        } else {
+            finished_slices
+        };
+
+        let x = (pseudo_random as u64 * pseudo_random as u64) >> 32;
+        let y = (reference_area_size as u64 * x) >> 32;
+        let relative_position = reference_area_size - 1 - y as usize;
+
+        let start_position = if pass != 0 && slice != SYNC_POINTS - 1 {
+            (slice + 1) * self.segment_length
+        } else {
+            0
+        };
+        (start_position + relative_position) % self.lane_length
+    }
+}
+
+/// Generates the next 128 reference addresses for data independent addressing
+fn next_addresses(input_block: &mut Block, address_block: &mut Block) {
+    let zero = [0u64; BLOCK_WORDS];
+    input_block[6] += 1;
+    *address_block = compress(&zero, &compress(&zero, input_block));
+}
+
+/// The compression function G, `P` applied to the rows and then the columns of `x ^ y`
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = [0u64; BLOCK_WORDS];
+    for (i, word) in r.iter_mut().enumerate() {
+        *word = x[i] ^ y[i];
+    }
+    let mut q = r;
+    for row in 0..8 {
+        let mut indices = [0usize; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = 16 * row + i;
+        }
+        permute(&mut q, &indices);
+    }
+    for column in 0..8 {
+        let mut indices = [0usize; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = 2 * column + 16 * (i / 2) + i % 2;
+        }
+        permute(&mut q, &indices);
+    }
+    for (a, b) in q.iter_mut().zip(r.iter()) {
+        *a ^= b;
+    }
+    q
+}
+
+/// The BLAKE2b round without message words on the 16 words at `indices`
+fn permute(block: &mut Block, indices: &[usize; 16]) {
+    let mut v = [0u64; 16];
+    for (word, &index) in v.iter_mut().zip(indices.iter()) {
+        *word = block[index];
+    }
+    gb(&mut v, 0, 4, 8, 12);
+    gb(&mut v, 1, 5, 9, 13);
+    gb(&mut v, 2, 6, 10, 14);
+    gb(&mut v, 3, 7, 11, 15);
+    gb(&mut v, 0, 5, 10, 15);
+    gb(&mut v, 1, 6, 11, 12);
+    gb(&mut v, 2, 7, 8, 13);
+    gb(&mut v, 3, 4, 9, 14);
+    for (word, &index) in v.iter().zip(indices.iter()) {
+        block[index] = *word;
+    }
+}
+
+/// The BLAKE2b mixing function with the additions replaced by the multiplication
+/// hardened `a + b + 2 * lo(a) * lo(b)`
+#[inline]
+fn gb(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize) {
+    v[a] = bla_mka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = bla_mka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = bla_mka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = bla_mka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[inline]
+fn bla_mka(x: u64, y: u64) -> u64 {
+    let product = (x & 0xffff_ffff).wrapping_mul(y & 0xffff_ffff);
+    x.wrapping_add(y).wrapping_add(product.wrapping_mul(2))
+}
+
+/// The variable length hash function H' built from BLAKE2b, fills `output`
+fn hash_long(input: &[u8], output: &mut [u8]) {
+    let len = (output.len() as u32).to_le_bytes();
+    if output.len() <= 64 {
+        let mut hash = Blake2b::with_output_size(output.len());
+        hash.update(&len);
+        hash.update(input);
+        output.copy_from_slice(&hash.finalize());
+        return;
+    }
+
+    // 32 bytes of every intermediate hash are used, the last hash fills the rest
+    let mut hash = Blake2b::new();
+    hash.update(&len);
+    hash.update(input);
+    let mut v = hash.finalize();
+    output[..32].copy_from_slice(&v[..32]);
+    let mut position = 32;
+    while output.len() - position > 64 {
+        v = Blake2b::digest(&v);
+        output[position..position + 32].copy_from_slice(&v[..32]);
+        position += 32;
+    }
+    let mut last = Blake2b::with_output_size(output.len() - position);
+    last.update(&v);
+    output[position..].copy_from_slice(&last.finalize());
+}
+
+fn block_from_bytes(bytes: &[u8]) -> Block {
+    let mut block = [0u64; BLOCK_WORDS];
+    for (word, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+        let mut le = [0u8; 8];
+        le.copy_from_slice(chunk);
+        *word = u64::from_le_bytes(le);
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 9106 section 5.3, Argon2id with secret and associated data
+    #[test]
+    fn rfc9106_argon2id() {
+        let params = Argon2Params {
+            memory_kib: 32,
+            iterations: 3,
+            lanes: 4,
+        };
+        let mut output = [0u8; 32];
+        argon2id_keyed(&[1; 32], &[2; 16], &[3; 8], &[4; 12], &params, &mut output);
+        assert_eq!(
+            hex::encode(output),
+            "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::digest::Digest;
+
+/// Initialization vector, the same as the initial hash value of SHA-512
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+/// Message word permutations, rounds 10 and 11 reuse the first two
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// BLAKE2b (RFC 7693) without a key, as used by Argon2
+#[derive(Clone)]
+pub struct Blake2b {
+    state: [u64; 8],
+    buffer: [u8; 128],
+    buffered: usize,
+    len: u128,
+    output_size: usize,
+}
+
+impl Blake2b {
+    /// Creates a hash with an output of 1 to 64 bytes
+    pub fn with_output_size(output_size: usize) -> Blake2b {
+        assert!((1..=64).contains(&output_size), "Unsupported output size");
+        let mut state = IV;
+        state[0] ^= 0x0101_0000 ^ output_size as u64;
+        Blake2b {
+            state,
+            buffer: [0u8; 128],
+            buffered: 0,
+            len: 0,
+            output_size,
+        }
+    }
+}
+
+impl Digest for Blake2b {
+    const BLOCK_SIZE: usize = 128;
+    const OUTPUT_SIZE: usize = 64;
+
+    fn new() -> Blake2b {
+        Blake2b::with_output_size(64)
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block is compressed with a flag, so a full buffer is only
+            // compressed once more data follows
+            if self.buffered == 128 {
+                self.len += 128;
+                compress(&mut self.state, &self.buffer, self.len, false);
+                self.buffered = 0;
+            }
+            let n = (128 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+        }
+    }
+
+    fn finalize(mut self) -> Vec<u8> {
+        self.len += self.buffered as u128;
+        for byte in &mut self.buffer[self.buffered..] {
+            *byte = 0;
+        }
+        compress(&mut self.state, &self.buffer, self.len, true);
+        let mut output: Vec<u8> = self
+            .state
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        output.truncate(self.output_size);
+        output
+    }
+}
+
+fn compress(state: &mut [u64; 8], block: &[u8; 128], len: u128, last: bool) {
+    let mut m = [0u64; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks_exact(8)) {
+        let mut le = [0u8; 8];
+        le.copy_from_slice(bytes);
+        *word = u64::from_le_bytes(le);
+    }
+
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(state);
+    v[8..].copy_from_slice(&IV);
+    v[12] ^= len as u64;
+    v[13] ^= (len >> 64) as u64;
+    if last {
+        v[14] = !v[14];
+    }
+
+    for round in 0..12 {
+        let s = &SIGMA[round % 10];
+        g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for i in 0..8 {
+        state[i] ^= v[i] ^ v[i + 8];
+    }
+}
+
+#[inline]
+fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 7693 Appendix A, BLAKE2b-512 of "abc"
+    #[test]
+    fn rfc7693_abc() {
+        assert_eq!(
+            hex::encode(Blake2b::digest(b"abc")),
+            concat!(
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1",
+                "7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+            )
+        );
+    }
+
+    /// A full last block must be compressed with the final flag, and shorter
+    /// outputs are a different hash rather than a truncation
+    #[test]
+    fn block_boundary_and_output_size() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut hash = Blake2b::new();
+        for part in data.chunks(128) {
+            hash.update(part);
+        }
+        assert_eq!(
+            hex::encode(hash.finalize()),
+            concat!(
+                "1ecc896f34d3f9cac484c73f75f6a5fb58ee6784be41b35f46067b9c65c63a67",
+                "94d3d744112c653f73dd7deb6666204c5a9bfa5b46081fc10fdbe7884fa5cbf8"
+            )
+        );
+
+        let mut hash = Blake2b::with_output_size(32);
+        hash.update(b"abc");
+        assert_eq!(
+            hex::encode(hash.finalize()),
+            "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"
+        );
+    }
+}
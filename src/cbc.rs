@@ -0,0 +1,285 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, inv_aes_v2, open_output_file, process_file_chunked, read_full, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::{self, SecretBytes};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Encrypts the input file in CBC mode with PKCS#7 padding (`openssl enc -aes-*-cbc`).
+/// Every block depends on the previous ciphertext block, so this runs sequentially.
+/// Both files are processed from their current position on.
+pub fn encrypt_file(
+    keys: &[u8],
+    nr: usize,
+    iv: &[u8],
+    input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, input_file);
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, output_file);
+
+    let mut chain = [0u8; BLOCK_SIZE];
+    chain.copy_from_slice(iv);
+    // One spare block for the padding of the last chunk
+    let mut chunk = vec![0u8; CHUNK_SIZE + BLOCK_SIZE];
+    loop {
+        let bytes_read = read_full(&mut reader, &mut chunk[..CHUNK_SIZE])?;
+        let is_last = bytes_read < CHUNK_SIZE;
+        let len = if is_last {
+            pad_pkcs7(&mut chunk, bytes_read)
+        } else {
+            bytes_read
+        };
+        encrypt_blocks(&mut chunk[..len], keys, nr, &mut chain);
+        writer.write_all(&chunk[..len])?;
+        if is_last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypts a CBC encrypted file and strips the PKCS#7 padding.
+/// A plaintext block only needs the previous ciphertext block, so the chunks are
+/// decrypted in parallel on the thread pool. The padding is checked before the
+/// output file is created, so a wrong key or a damaged file leaves no output.
+pub fn decrypt_file(
+    keys: Arc<SecretBytes>,
+    nr: usize,
+    iv: Arc<Vec<u8>>,
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+    if len == 0 || !len.is_multiple_of(BLOCK_SIZE as u64) {
+        return Err(AesError::InvalidLength(len));
+    }
+
+    // The last block is chained to the block before it, or to the IV
+    let mut chain = [0u8; BLOCK_SIZE];
+    let mut last_block = [0u8; BLOCK_SIZE];
+    if len == BLOCK_SIZE as u64 {
+        chain.copy_from_slice(&iv);
+    } else {
+        input_file.seek(SeekFrom::Start(input_offset + len - 2 * BLOCK_SIZE as u64))?;
+        input_file.read_exact(&mut chain)?;
+    }
+    input_file.seek(SeekFrom::Start(input_offset + len - BLOCK_SIZE as u64))?;
+    input_file.read_exact(&mut last_block)?;
+    decrypt_blocks(&mut last_block, &keys, nr, &mut chain);
+    let padding = strip_pkcs7(&last_block);
+    secret::wipe(&mut last_block);
+    padding?;
+    input_file.seek(SeekFrom::Start(input_offset))?;
+
+    process_file_chunked(
+        input_file,
+        open_output_file(output_file_path)?,
+        BLOCK_SIZE,
+        move |_, previous, chunk, is_last| {
+            let mut chain = [0u8; BLOCK_SIZE];
+            if previous.is_empty() {
+                chain.copy_from_slice(&iv);
+            } else {
+                chain.copy_from_slice(previous);
+            }
+            decrypt_blocks(chunk, &keys, nr, &mut chain);
+            if is_last {
+                let len = strip_pkcs7(chunk)?;
+                chunk.truncate(len);
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Encrypts whole blocks in place, `chain` holds the IV or last ciphertext block
+pub(crate) fn encrypt_blocks(
+    data: &mut [u8],
+    keys: &[u8],
+    nr: usize,
+    chain: &mut [u8; BLOCK_SIZE],
+) {
+    for block in data.chunks_exact_mut(BLOCK_SIZE) {
+        for (byte, c) in block.iter_mut().zip(chain.iter()) {
+            *byte ^= c;
+        }
+        aes_v2(block, keys, nr);
+        chain.copy_from_slice(block);
+    }
+}
+
+/// Decrypts whole blocks in place, `chain` holds the IV or previous ciphertext block
+pub(crate) fn decrypt_blocks(
+    data: &mut [u8],
+    keys: &[u8],
+    nr: usize,
+    chain: &mut [u8; BLOCK_SIZE],
+) {
+    let mut cipher_block = [0u8; BLOCK_SIZE];
+    for block in data.chunks_exact_mut(BLOCK_SIZE) {
+        cipher_block.copy_from_slice(block);
+        inv_aes_v2(block, keys, nr);
+        for (byte, c) in block.iter_mut().zip(chain.iter()) {
+            *byte ^= c;
+        }
+        chain.copy_from_slice(&cipher_block);
+    }
+}
+
+/// Appends PKCS#7 padding behind the first `len` bytes of `buf` and returns the padded length
+pub(crate) fn pad_pkcs7(buf: &mut [u8], len: usize) -> usize {
+    let pad = BLOCK_SIZE - len % BLOCK_SIZE;
+    for byte in &mut buf[len..len + pad] {
+        *byte = pad as u8;
+    }
+    len + pad
+}
+
+/// Validates the PKCS#7 padding at the end of `data` and returns the unpadded length.
+/// All padding bytes are inspected regardless of where a mismatch occurs.
+pub(crate) fn strip_pkcs7(data: &[u8]) -> Result<usize, AesError> {
+    if data.len() < BLOCK_SIZE {
+        return Err(AesError::BadPadding);
+    }
+    let last_block = &data[data.len() - BLOCK_SIZE..];
+    let pad = last_block[BLOCK_SIZE - 1];
+    let mut invalid = (pad == 0 || pad as usize > BLOCK_SIZE) as u8;
+    for (i, byte) in last_block.iter().enumerate() {
+        let in_padding = (BLOCK_SIZE - i <= pad as usize) as u8;
+        invalid |= in_padding & (*byte != pad) as u8;
+    }
+    if invalid != 0 {
+        return Err(AesError::BadPadding);
+    }
+    Ok(data.len() - pad as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ctr_optimized::expand_key;
+    use crate::test_util::unhex;
+    use std::fs;
+
+    /// NIST SP 800-38A F.2.1 to F.2.6, the blocks are encrypted without padding
+    #[test]
+    fn sp800_38a_cbc() {
+        let vectors = [
+            (
+                "2b7e151628aed2a6abf7158809cf4f3c",
+                concat!(
+                    "7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2",
+                    "73bed6b8e3c1743b7116e69e222295163ff1caa1681fac09120eca307586e1a7"
+                ),
+            ),
+            (
+                "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+                concat!(
+                    "4f021db243bc633d7178183a9fa071e8b4d9ada9ad7dedf4e5e738763f69145a",
+                    "571b242012fb7ae07fa9baac3df102e008b0e27988598881d920a9e64f5615cd"
+                ),
+            ),
+            (
+                "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+                concat!(
+                    "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d",
+                    "39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b"
+                ),
+            ),
+        ];
+        let plaintext = unhex(concat!(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+        ));
+        let iv = unhex("000102030405060708090a0b0c0d0e0f");
+        for (key, ciphertext) in vectors.iter() {
+            let (keys, nr) = expand_key(&unhex(key)).unwrap();
+            let mut data = plaintext.clone();
+            let mut chain = [0u8; BLOCK_SIZE];
+            chain.copy_from_slice(&iv);
+            encrypt_blocks(&mut data, &keys, nr, &mut chain);
+            assert_eq!(hex::encode(&data), *ciphertext);
+
+            // Decrypted in two calls, the chain carries over
+            chain.copy_from_slice(&iv);
+            let (first, second) = data.split_at_mut(BLOCK_SIZE);
+            decrypt_blocks(first, &keys, nr, &mut chain);
+            decrypt_blocks(second, &keys, nr, &mut chain);
+            assert_eq!(data, plaintext);
+        }
+    }
+
+    #[test]
+    fn pkcs7_padding() {
+        let mut buf = [0u8; 2 * BLOCK_SIZE];
+        assert_eq!(pad_pkcs7(&mut buf, 13), BLOCK_SIZE);
+        assert_eq!(buf[13..BLOCK_SIZE], [3, 3, 3]);
+        assert_eq!(strip_pkcs7(&buf[..BLOCK_SIZE]).unwrap(), 13);
+        // A full block of padding follows a plaintext that fills its last block
+        assert_eq!(pad_pkcs7(&mut buf, BLOCK_SIZE), 2 * BLOCK_SIZE);
+        assert_eq!(buf[BLOCK_SIZE..], [16; BLOCK_SIZE]);
+        assert_eq!(strip_pkcs7(&buf).unwrap(), BLOCK_SIZE);
+    }
+
+    #[test]
+    fn pkcs7_rejects_bad_padding() {
+        let mut zero_pad = [0x41; BLOCK_SIZE];
+        zero_pad[BLOCK_SIZE - 1] = 0;
+        let mut too_large = [0x11; BLOCK_SIZE];
+        too_large[BLOCK_SIZE - 1] = 17;
+        let mut mismatch = [0x41; BLOCK_SIZE];
+        mismatch[BLOCK_SIZE - 3..].copy_from_slice(&[4, 3, 3]);
+        mismatch[BLOCK_SIZE - 4] = 3;
+        for block in [zero_pad, too_large, mismatch].iter() {
+            assert!(matches!(strip_pkcs7(block), Err(AesError::BadPadding)));
+        }
+        assert!(matches!(strip_pkcs7(&[1; 15]), Err(AesError::BadPadding)));
+    }
+
+    /// Decrypts `ciphertext` from a file and returns the result and whether the
+    /// output file was created
+    fn decrypt(name: &str, ciphertext: &[u8]) -> (Result<(), AesError>, bool) {
+        let dir = std::env::temp_dir();
+        let input_path = dir.join(format!("cbc-{}-{}.in", name, std::process::id()));
+        let output_path = dir.join(format!("cbc-{}-{}.out", name, std::process::id()));
+        fs::write(&input_path, ciphertext).unwrap();
+        let (keys, nr) = expand_key(&[7; 16]).unwrap();
+        let input_file = File::open(&input_path).unwrap();
+        let iv = Arc::new(vec![0u8; BLOCK_SIZE]);
+        let result = decrypt_file(Arc::new(keys), nr, iv, input_file, &output_path);
+        let created = output_path.exists();
+        let _ = fs::remove_file(&input_path);
+        let _ = fs::remove_file(&output_path);
+        (result, created)
+    }
+
+    /// Neither a wrong length nor a bad padding leaves an output file behind
+    #[test]
+    fn decrypt_file_rejects_before_writing() {
+        let (result, created) = decrypt("length", &[0; 2 * BLOCK_SIZE + 1]);
+        assert!(matches!(result, Err(AesError::InvalidLength(33))));
+        assert!(!created);
+
+        // Three blocks whose last one decrypts to a zero pad byte
+        let (keys, nr) = expand_key(&[7; 16]).unwrap();
+        let mut data = [0x41u8; 3 * BLOCK_SIZE];
+        data[3 * BLOCK_SIZE - 1] = 0;
+        encrypt_blocks(&mut data, &keys, nr, &mut [0u8; BLOCK_SIZE]);
+        let (result, created) = decrypt("padding", &data);
+        assert!(matches!(result, Err(AesError::BadPadding)));
+        assert!(!created);
+
+        let mut buf = [0x41u8; 2 * BLOCK_SIZE];
+        let len = pad_pkcs7(&mut buf, 20);
+        encrypt_blocks(&mut buf[..len], &keys, nr, &mut [0u8; BLOCK_SIZE]);
+        let (result, created) = decrypt("valid", &buf[..len]);
+        assert!(result.is_ok());
+        assert!(created);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, ct_eq, expand_key, for_each_chunk, open_output_file, process_chunk,
+    process_payload_chunked, Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Shortest and longest allowed nonce in bytes
+pub const NONCE_SIZES: (usize, usize) = (7, 13);
+
+/// AES-CCM (RFC 3610, NIST SP 800-38C), CBC-MAC combined with CTR
+pub struct Ccm {
+    keys: SecretBytes,
+    nr: usize,
+    tag_size: usize,
+}
+
+impl Ccm {
+    /// Creates a cipher from an AES key and a tag length of 4, 6, ..., 16 bytes
+    pub fn new(key: &[u8], tag_size: usize) -> Result<Ccm, AesError> {
+        if !(4..=16).contains(&tag_size) || tag_size & 1 != 0 {
+            return Err(AesError::InvalidTagLength(tag_size));
+        }
+        let (keys, nr) = expand_key(key)?;
+        Ok(Ccm { keys, nr, tag_size })
+    }
+
+    /// Encrypts `data` in place and returns the tag
+    pub fn encrypt(&self, nonce: &[u8], aad: &[u8], data: &mut [u8]) -> Result<Vec<u8>, AesError> {
+        let mut mac = self.start_mac(nonce, aad, data.len() as u64)?;
+        mac.update(data);
+        let tag = self.finish_tag(nonce, mac);
+        self.apply_keystream(nonce, data, 0);
+        Ok(tag)
+    }
+
+    /// Decrypts `data` in place if `tag` is valid, otherwise `data` is wiped
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), AesError> {
+        let mut mac = self.start_mac(nonce, aad, data.len() as u64)?;
+        self.apply_keystream(nonce, data, 0);
+        mac.update(data);
+        if !ct_eq(&self.finish_tag(nonce, mac), tag) {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+            return Err(AesError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    /// Formats `B0` and the associated data and feeds them into the CBC-MAC
+    fn start_mac(&self, nonce: &[u8], aad: &[u8], len: u64) -> Result<CbcMac<'_>, AesError> {
+        if nonce.len() < NONCE_SIZES.0 || nonce.len() > NONCE_SIZES.1 {
+            return Err(AesError::InvalidNonceLength(nonce.len()));
+        }
+        // The message length has to fit into the remaining q bytes
+        let q = 15 - nonce.len();
+        if q < 8 && len >> (8 * q) != 0 {
+            return Err(AesError::InvalidLength(len));
+        }
+
+        let mut b0 = [0u8; BLOCK_SIZE];
+        b0[0] = (((!aad.is_empty()) as u8) << 6)
+            | (((self.tag_size - 2) / 2) << 3) as u8
+            | (q - 1) as u8;
+        b0[1..1 + nonce.len()].copy_from_slice(nonce);
+        b0[1 + nonce.len()..].copy_from_slice(&len.to_be_bytes()[8 - q..]);
+
+        let mut mac = CbcMac {
+            ccm: self,
+            state: [0u8; BLOCK_SIZE],
+            buffered: 0,
+        };
+        mac.update(&b0);
+        if !aad.is_empty() {
+            let aad_len = aad.len() as u64;
+            if aad_len < 0xff00 {
+                mac.update(&(aad_len as u16).to_be_bytes());
+            } else if aad_len <= u32::MAX as u64 {
+                mac.update(&[0xff, 0xfe]);
+                mac.update(&(aad_len as u32).to_be_bytes());
+            } else {
+                mac.update(&[0xff, 0xff]);
+                mac.update(&aad_len.to_be_bytes());
+            }
+            mac.update(aad);
+            mac.pad();
+        }
+        Ok(mac)
+    }
+
+    /// Encrypts the CBC-MAC with the first keystream block `S0`
+    fn finish_tag(&self, nonce: &[u8], mut mac: CbcMac) -> Vec<u8> {
+        mac.pad();
+        let mut s0 = counter_block(nonce, 0);
+        aes_v2(&mut s0, &self.keys, self.nr);
+        mac.state
+            .iter()
+            .zip(s0.iter())
+            .take(self.tag_size)
+            .map(|(t, s)| t ^ s)
+            .collect()
+    }
+
+    /// CTR starting at counter block 1, block 0 is reserved for the tag
+    fn apply_keystream(&self, nonce: &[u8], data: &mut [u8], starting_block: u64) {
+        let counter = counter_block(nonce, 1);
+        process_chunk(data, &self.keys, &counter, self.nr, starting_block);
+    }
+}
+
+/// Counter block `flags || N || [i]_q`
+fn counter_block(nonce: &[u8], i: u64) -> [u8; BLOCK_SIZE] {
+    let q = 15 - nonce.len();
+    let mut block = [0u8; BLOCK_SIZE];
+    block[0] = (q - 1) as u8;
+    block[1..1 + nonce.len()].copy_from_slice(nonce);
+    block[1 + nonce.len()..].copy_from_slice(&i.to_be_bytes()[8 - q..]);
+    block
+}
+
+/// Running CBC-MAC with a zero IV, incomplete blocks are padded with zeros
+struct CbcMac<'a> {
+    ccm: &'a Ccm,
+    state: [u8; BLOCK_SIZE],
+    buffered: usize,
+}
+
+impl<'a> CbcMac<'a> {
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.state[self.buffered] ^= byte;
+            self.buffered += 1;
+            if self.buffered == BLOCK_SIZE {
+                aes_v2(&mut self.state, &self.ccm.keys, self.ccm.nr);
+                self.buffered = 0;
+            }
+        }
+    }
+
+    /// Completes the current block with zero padding
+    fn pad(&mut self) {
+        if self.buffered > 0 {
+            aes_v2(&mut self.state, &self.ccm.keys, self.ccm.nr);
+            self.buffered = 0;
+        }
+    }
+}
+
+/// Encrypts a file into `C || T`. The CBC-MAC over the plaintext is computed in a
+/// first sequential pass, the CTR pass then runs on the thread pool.
+pub fn encrypt_file(
+    ccm: &Ccm,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    mut output_file: File,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+
+    let mut mac = ccm.start_mac(nonce, aad, len)?;
+    for_each_chunk(&input_file, len, |chunk, _| mac.update(chunk))?;
+    let tag = ccm.finish_tag(nonce, mac);
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: output_file.stream_position()?,
+    };
+    let (keys, nr, counter) = (ccm.keys.clone(), ccm.nr, counter_block(nonce, 1));
+    process_payload_chunked(
+        input_file,
+        output_file.try_clone()?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk(chunk, &keys, &counter, nr, starting_block);
+            Ok(())
+        },
+    )?;
+    output_file.seek(SeekFrom::Start(payload.output_offset + len))?;
+    output_file.write_all(&tag)?;
+    Ok(())
+}
+
+/// Decrypts a file written by `encrypt_file`. The tag is verified in a first pass
+/// without writing anything, the decryption pass then runs on the thread pool.
+/// The output file is only created once the tag matches.
+pub fn decrypt_file(
+    ccm: &Ccm,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let input_len = input_file.metadata()?.len() - input_offset;
+    if input_len < ccm.tag_size as u64 {
+        return Err(AesError::InvalidLength(input_len));
+    }
+    let len = input_len - ccm.tag_size as u64;
+
+    let mut tag = vec![0u8; ccm.tag_size];
+    input_file.seek(SeekFrom::Start(input_offset + len))?;
+    input_file.read_exact(&mut tag)?;
+    input_file.seek(SeekFrom::Start(input_offset))?;
+
+    let mut mac = ccm.start_mac(nonce, aad, len)?;
+    for_each_chunk(&input_file, len, |chunk, starting_block| {
+        ccm.apply_keystream(nonce, chunk, starting_block);
+        mac.update(chunk);
+    })?;
+    if !ct_eq(&ccm.finish_tag(nonce, mac), &tag) {
+        return Err(AesError::AuthenticationFailed);
+    }
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: 0,
+    };
+    let (keys, nr, counter) = (ccm.keys.clone(), ccm.nr, counter_block(nonce, 1));
+    process_payload_chunked(
+        input_file,
+        open_output_file(output_file_path)?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk(chunk, &keys, &counter, nr, starting_block);
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// NIST SP 800-38C Appendix C, Examples 1 to 3: 7, 8 and 12 byte nonces with
+    /// 4, 6 and 8 byte tags
+    #[test]
+    fn sp800_38c_examples() {
+        let key = hex::decode("404142434445464748494a4b4c4d4e4f").unwrap();
+        let cases = [
+            (
+                4,
+                "10111213141516",
+                "0001020304050607",
+                "20212223",
+                "7162015b4dac255d",
+            ),
+            (
+                6,
+                "1011121314151617",
+                "000102030405060708090a0b0c0d0e0f",
+                "202122232425262728292a2b2c2d2e2f",
+                "d2a1f0e051ea5f62081a7792073d593d1fc64fbfaccd",
+            ),
+            (
+                8,
+                "101112131415161718191a1b",
+                "000102030405060708090a0b0c0d0e0f10111213",
+                "202122232425262728292a2b2c2d2e2f3031323334353637",
+                "e3b201a9f5b71a7a9b1ceaeccd97e70b6176aad9a4428aa5484392fbc1b09951",
+            ),
+        ];
+        for (tag_size, nonce, aad, plaintext, result) in cases.iter() {
+            let ccm = Ccm::new(&key, *tag_size).unwrap();
+            let (nonce, aad) = (hex::decode(nonce).unwrap(), hex::decode(aad).unwrap());
+            let mut data = hex::decode(plaintext).unwrap();
+            let tag = ccm.encrypt(&nonce, &aad, &mut data).unwrap();
+            assert_eq!(hex::encode(&data) + &hex::encode(&tag), *result);
+
+            ccm.decrypt(&nonce, &aad, &mut data, &tag).unwrap();
+            assert_eq!(hex::encode(&data), *plaintext);
+        }
+    }
+
+    /// Tag and nonce lengths outside of CCM's ranges, a message too long for the
+    /// length field of a 13 byte nonce and a truncated tag are rejected
+    #[test]
+    fn rejects_invalid_lengths() {
+        for &tag_size in [2usize, 5, 18].iter() {
+            let result = Ccm::new(&[1; 16], tag_size);
+            assert!(matches!(result, Err(AesError::InvalidTagLength(_))));
+        }
+        let ccm = Ccm::new(&[1; 16], 8).unwrap();
+        for &nonce_len in [6usize, 14].iter() {
+            let result = ccm.encrypt(&vec![2; nonce_len], b"", &mut [0; 16]);
+            assert!(matches!(result, Err(AesError::InvalidNonceLength(_))));
+        }
+        let mut data = vec![0u8; 1 << 16];
+        let result = ccm.encrypt(&[2; 13], b"", &mut data);
+        assert!(matches!(result, Err(AesError::InvalidLength(65536))));
+        assert!(ccm.encrypt(&[2; 13], b"", &mut data[..65535]).is_ok());
+
+        let mut data = *b"attack at dawn";
+        let tag = ccm.encrypt(&[2; 13], b"header", &mut data).unwrap();
+        let result = ccm.decrypt(&[2; 13], b"header", &mut data, &tag[..4]);
+        assert!(matches!(result, Err(AesError::AuthenticationFailed)));
+        assert_eq!(data, [0; 14]);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, process_file_chunked, process_file_sequential, Mode, BLOCK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::sync::Arc;
+
+/// Number of bits fed back into the shift register per cipher call
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CfbSegment {
+    Bits1,
+    Bits8,
+    Bits128,
+}
+
+impl CfbSegment {
+    /// Segment size of a CFB mode, panics for other modes
+    pub fn for_mode(mode: Mode) -> CfbSegment {
+        match mode {
+            Mode::Cfb1 => CfbSegment::Bits1,
+            Mode::Cfb8 => CfbSegment::Bits8,
+            Mode::Cfb128 => CfbSegment::Bits128,
+            _ => panic!("Not a CFB mode"),
+        }
+    }
+}
+
+/// Encrypts the input file in CFB mode (`openssl enc -aes-*-cfb`, `-cfb8`, `-cfb1`).
+/// Every segment depends on the previous ciphertext, so this runs sequentially.
+pub fn encrypt_file(
+    keys: &[u8],
+    nr: usize,
+    iv: &[u8],
+    segment: CfbSegment,
+    input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    let mut register = [0u8; BLOCK_SIZE];
+    register.copy_from_slice(iv);
+    process_file_sequential(input_file, output_file, |chunk| {
+        cfb_segments(chunk, keys, nr, &mut register, segment, true)
+    })
+}
+
+/// Decrypts a CFB encrypted file. The shift register of any position only holds
+/// the preceding 16 ciphertext bytes, so the chunks are decrypted in parallel.
+pub fn decrypt_file(
+    keys: Arc<SecretBytes>,
+    nr: usize,
+    iv: Arc<Vec<u8>>,
+    segment: CfbSegment,
+    input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    process_file_chunked(
+        input_file,
+        output_file,
+        BLOCK_SIZE,
+        move |_, previous, chunk, _| {
+            let mut register = [0u8; BLOCK_SIZE];
+            if previous.is_empty() {
+                register.copy_from_slice(&iv);
+            } else {
+                register.copy_from_slice(previous);
+            }
+            cfb_segments(chunk, &keys, nr, &mut register, segment, false);
+            Ok(())
+        },
+    )
+}
+
+/// Runs CFB over `data` in place. `register` holds the IV or the last 16 ciphertext
+/// bytes and is updated for the next call. Only the last call may end in the middle
+/// of a block.
+pub(crate) fn cfb_segments(
+    data: &mut [u8],
+    keys: &[u8],
+    nr: usize,
+    register: &mut [u8; BLOCK_SIZE],
+    segment: CfbSegment,
+    encrypt: bool,
+) {
+    let mut keystream = [0u8; BLOCK_SIZE];
+    match segment {
+        CfbSegment::Bits128 => {
+            for block in data.chunks_mut(BLOCK_SIZE) {
+                keystream.copy_from_slice(register);
+                aes_v2(&mut keystream, keys, nr);
+                if !encrypt {
+                    register[..block.len()].copy_from_slice(block);
+                }
+                for (byte, k) in block.iter_mut().zip(keystream.iter()) {
+                    *byte ^= k;
+                }
+                if encrypt {
+                    register[..block.len()].copy_from_slice(block);
+                }
+            }
+        }
+        CfbSegment::Bits8 => {
+            for byte in data.iter_mut() {
+                keystream.copy_from_slice(register);
+                aes_v2(&mut keystream, keys, nr);
+                let cipher_byte = if encrypt { *byte ^ keystream[0] } else { *byte };
+                *byte ^= keystream[0];
+                register.copy_within(1.., 0);
+                register[BLOCK_SIZE - 1] = cipher_byte;
+            }
+        }
+        CfbSegment::Bits1 => {
+            for byte in data.iter_mut() {
+                let mut out = 0u8;
+                for bit in (0..8).rev() {
+                    keystream.copy_from_slice(register);
+                    aes_v2(&mut keystream, keys, nr);
+                    let in_bit = (*byte >> bit) & 1;
+                    let out_bit = in_bit ^ (keystream[0] >> 7);
+                    out |= out_bit << bit;
+                    let cipher_bit = if encrypt { out_bit } else { in_bit };
+                    let shifted = (u128::from_be_bytes(*register) << 1) | cipher_bit as u128;
+                    *register = shifted.to_be_bytes();
+                }
+                *byte = out;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ctr_optimized::expand_key;
+
+    /// Keys of NIST SP 800-38A for AES-128, AES-192 and AES-256
+    const KEYS: [&str; 3] = [
+        "2b7e151628aed2a6abf7158809cf4f3c",
+        "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+        "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+    ];
+    const IV: &str = "000102030405060708090a0b0c0d0e0f";
+    const PLAINTEXT: &str = concat!(
+        "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+        "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+    );
+
+    /// Encrypts the start of the plaintext under each key, once in one call and
+    /// once split after every byte, and decrypts the ciphertexts again
+    fn check(segment: CfbSegment, ciphertexts: [&str; 3]) {
+        for (key, ciphertext) in KEYS.iter().zip(ciphertexts.iter()) {
+            let (keys, nr) = expand_key(&hex::decode(key).unwrap()).unwrap();
+            let plaintext = &hex::decode(PLAINTEXT).unwrap()[..ciphertext.len() / 2];
+            let mut iv = [0u8; BLOCK_SIZE];
+            iv.copy_from_slice(&hex::decode(IV).unwrap());
+
+            let mut data = plaintext.to_vec();
+            let mut register = iv;
+            cfb_segments(&mut data, &keys, nr, &mut register, segment, true);
+            assert_eq!(hex::encode(&data), *ciphertext);
+
+            let mut register = iv;
+            for byte in data.chunks_mut(if segment == CfbSegment::Bits128 {
+                16
+            } else {
+                1
+            }) {
+                cfb_segments(byte, &keys, nr, &mut register, segment, false);
+            }
+            assert_eq!(data, plaintext);
+        }
+    }
+
+    /// NIST SP 800-38A F.3.1 to F.3.6, i.e. the first 16 bits of the plaintext
+    #[test]
+    fn sp800_38a_cfb1() {
+        check(CfbSegment::Bits1, ["68b3", "9359", "9029"]);
+    }
+
+    /// NIST SP 800-38A F.3.7 to F.3.12
+    #[test]
+    fn sp800_38a_cfb8() {
+        check(
+            CfbSegment::Bits8,
+            [
+                "3b79424c9c0dd436bace9e0ed4586a4f32b9",
+                "cda2521ef0a905ca44cd057cbf0d47a0678a",
+                "dc1f1a8520a64db55fcc8ac554844e889700",
+            ],
+        );
+    }
+
+    /// NIST SP 800-38A F.3.13 to F.3.18
+    #[test]
+    fn sp800_38a_cfb128() {
+        check(
+            CfbSegment::Bits128,
+            [
+                concat!(
+                    "3b3fd92eb72dad20333449f8e83cfb4ac8a64537a0b3a93fcde3cdad9f1ce58b",
+                    "26751f67a3cbb140b1808cf187a4f4dfc04b05357c5d1c0eeac4c66f9ff7f2e6"
+                ),
+                concat!(
+                    "cdc80d6fddf18cab34c25909c99a417467ce7f7f81173621961a2b70171d3d7a",
+                    "2e1e8a1dd59b88b1c8e60fed1efac4c9c05f9f9ca9834fa042ae8fba584b09ff"
+                ),
+                concat!(
+                    "dc7e84bfda79164b7ecd8486985d386039ffed143b28b1c832113c6331e5407b",
+                    "df10132415e54b92a13ed0a8267ae2f975a385741ab9cef82031623d55b1e471"
+                ),
+            ],
+        );
+    }
+}
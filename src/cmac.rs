@@ -0,0 +1,135 @@
+use crate::aes_ctr_optimized::{aes_v2, expand_key, BLOCK_SIZE};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+
+/// AES-CMAC (NIST SP 800-38B, RFC 4493), also known as OMAC1
+pub struct Cmac {
+    keys: SecretBytes,
+    nr: usize,
+    k1: [u8; BLOCK_SIZE],
+    k2: [u8; BLOCK_SIZE],
+}
+
+impl Cmac {
+    /// Creates a CMAC instance from an AES-128, AES-192 or AES-256 key
+    pub fn new(key: &[u8]) -> Result<Cmac, AesError> {
+        let (keys, nr) = expand_key(key)?;
+        let mut l = [0u8; BLOCK_SIZE];
+        aes_v2(&mut l, &keys, nr);
+        let k1 = dbl(&l);
+        let k2 = dbl(&k1);
+        Ok(Cmac { keys, nr, k1, k2 })
+    }
+
+    /// Starts an incremental computation
+    pub fn start(&self) -> CmacState<'_> {
+        CmacState {
+            cmac: self,
+            state: [0u8; BLOCK_SIZE],
+            buffer: [0u8; BLOCK_SIZE],
+            buffered: 0,
+        }
+    }
+
+    /// Computes the MAC of `data` in one go
+    pub fn mac(&self, data: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut state = self.start();
+        state.update(data);
+        state.finalize()
+    }
+
+    /// Encrypts a single block with the MAC key
+    pub(crate) fn encrypt_block(&self, block: &mut [u8]) {
+        aes_v2(block, &self.keys, self.nr);
+    }
+}
+
+/// Running CMAC computation, the last block is held back until `finalize`
+pub struct CmacState<'a> {
+    cmac: &'a Cmac,
+    state: [u8; BLOCK_SIZE],
+    buffer: [u8; BLOCK_SIZE],
+    buffered: usize,
+}
+
+impl<'a> CmacState<'a> {
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.buffered == BLOCK_SIZE {
+                for (s, b) in self.state.iter_mut().zip(self.buffer.iter()) {
+                    *s ^= b;
+                }
+                self.cmac.encrypt_block(&mut self.state);
+                self.buffered = 0;
+            }
+            let n = (BLOCK_SIZE - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; BLOCK_SIZE] {
+        let subkey = if self.buffered == BLOCK_SIZE {
+            self.cmac.k1
+        } else {
+            self.buffer[self.buffered] = 0x80;
+            for byte in &mut self.buffer[self.buffered + 1..] {
+                *byte = 0;
+            }
+            self.cmac.k2
+        };
+        for ((s, b), k) in self
+            .state
+            .iter_mut()
+            .zip(self.buffer.iter())
+            .zip(subkey.iter())
+        {
+            *s ^= b ^ k;
+        }
+        self.cmac.encrypt_block(&mut self.state);
+        self.state
+    }
+}
+
+/// Doubling in GF(2^128) with the big-endian convention of CMAC and S2V
+pub(crate) fn dbl(block: &[u8; BLOCK_SIZE]) -> [u8; BLOCK_SIZE] {
+    let value = u128::from_be_bytes(*block);
+    let carry = (value >> 127) as u8;
+    ((value << 1) ^ (0x87 * carry as u128)).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    /// RFC 4493 section 4, the subkeys and the MACs of the first 0, 16, 40 and 64
+    /// bytes of the message
+    #[test]
+    fn rfc4493_vectors() {
+        let cmac = Cmac::new(&unhex("2b7e151628aed2a6abf7158809cf4f3c")).unwrap();
+        assert_eq!(hex::encode(cmac.k1), "fbeed618357133667c85e08f7236a8de");
+        assert_eq!(hex::encode(cmac.k2), "f7ddac306ae266ccf90bc11ee46d513b");
+        let message = unhex(concat!(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+        ));
+        let cases = [
+            (0, "bb1d6929e95937287fa37d129b756746"),
+            (16, "070a16b46b4d4144f79bdd9dd04a287c"),
+            (40, "dfa66747de9ae63030ca32611497c827"),
+            (64, "51f0bebf7e3b9d92fc49741779363cfe"),
+        ];
+        for (len, mac) in cases.iter() {
+            assert_eq!(hex::encode(cmac.mac(&message[..*len])), *mac);
+
+            // Fed in odd parts, a full last block is held back until finalize
+            let mut state = cmac.start();
+            message[..*len]
+                .chunks(7)
+                .for_each(|part| state.update(part));
+            assert_eq!(hex::encode(state.finalize()), *mac);
+        }
+    }
+}
@@ -0,0 +1,482 @@
+use crate::aes_ctr_optimized::{
+    read_full, CipherOptions, CounterEndianness, CounterLayout, Mode, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::gcm_siv;
+use crate::kcv::KCV_SIZE;
+use crate::kw;
+use crate::master_key::{self, FileDerivation};
+use crate::passphrase::{Kdf, SALT_SIZE};
+use crate::siv;
+use std::io::Read;
+
+/// Magic at the start of files in the container format
+pub const MAGIC: &[u8; 6] = b"AESCTR";
+/// Version of the container format written by this tool
+pub const VERSION: u8 = 1;
+/// Identifier of the block cipher, so far only AES
+const CIPHER_AES: u8 = 1;
+/// Magic, version, cipher, mode, key size, chunk size, tag size, sector size,
+/// counter layout, counter endianness and IV length
+const FIXED_SIZE: usize = MAGIC.len() + 1 + 1 + 1 + 2 + 4 + 1 + 4 + 1 + 1 + 1;
+/// Takes the place of the KDF identifier when the key is derived from a master key,
+/// far away from the identifiers of the passphrase KDFs
+const MASTER_KEY_ID: u8 = 0xff;
+
+/// Settings stored in front of the ciphertext, i.e. everything decryption needs
+/// besides the key or passphrase. All integers are big-endian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub mode: Mode,
+    /// Key size in bits, twice the AES key size for XTS and SIV
+    pub key_size: u16,
+    /// Size of the chunks the payload was processed in
+    pub chunk_size: u32,
+    /// Length of the authentication tag (or synthetic IV) in bytes, 0 for modes
+    /// without authentication
+    pub tag_size: u8,
+    /// Size of an XTS data unit in bytes, 0 for the other modes
+    pub sector_size: u32,
+    pub counter_layout: CounterLayout,
+    pub counter_endianness: CounterEndianness,
+    /// IV or nonce, empty for XTS and SIV
+    pub iv: Vec<u8>,
+    /// Key check value, detects a wrong key or passphrase before decrypting
+    pub kcv: Vec<u8>,
+    /// Key derivation function and salt if the key is derived from a passphrase
+    pub kdf: Option<(Kdf, [u8; SALT_SIZE])>,
+    /// Salt and relative path if key and IV are derived from a master key
+    pub master: Option<FileDerivation>,
+    /// The data key wrapped under key encryption keys for envelope encryption, empty
+    /// if the payload is encrypted with the given key directly
+    pub recipients: Vec<Recipient>,
+}
+
+/// The data key wrapped under one key encryption key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recipient {
+    /// Key check value of the key encryption key, to find its entry
+    pub kcv: [u8; KCV_SIZE],
+    /// The data key wrapped with AES-KW
+    pub wrapped_key: Vec<u8>,
+}
+
+impl Header {
+    /// The header for encrypting with `options`, settings of other modes are zeroed
+    pub fn new(
+        options: &CipherOptions,
+        key_size: u16,
+        iv: &[u8],
+        kcv: Vec<u8>,
+        kdf: Option<(Kdf, [u8; SALT_SIZE])>,
+        master: Option<FileDerivation>,
+        recipients: Vec<Recipient>,
+    ) -> Header {
+        let tag_size = match options.mode {
+            Mode::GcmSiv => gcm_siv::TAG_SIZE,
+            Mode::Siv => siv::SIV_SIZE,
+            Mode::Ccm | Mode::Ocb | Mode::Eax => options.tag_size,
+            _ => 0,
+        };
+        let (counter_layout, counter_endianness) = if options.mode == Mode::Ctr {
+            (options.counter_layout, options.counter_endianness)
+        } else {
+            (CounterLayout::Full128, CounterEndianness::Big)
+        };
+        Header {
+            mode: options.mode,
+            key_size,
+            chunk_size: CHUNK_SIZE as u32,
+            tag_size: tag_size as u8,
+            sector_size: if options.mode == Mode::Xts {
+                options.sector_size as u32
+            } else {
+                0
+            },
+            counter_layout,
+            counter_endianness,
+            iv: iv.to_vec(),
+            kcv,
+            kdf,
+            master,
+            recipients,
+        }
+    }
+
+    /// Takes over the mode and its settings for decryption
+    pub fn apply(&self, options: &mut CipherOptions) {
+        options.mode = self.mode;
+        options.tag_size = self.tag_size as usize;
+        options.sector_size = self.sector_size as usize;
+        options.counter_layout = self.counter_layout;
+        options.counter_endianness = self.counter_endianness;
+    }
+
+    /// Serializes the header, the KDF block is only present with a passphrase or master
+    /// key and the wrapped data keys come last
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.authenticated_bytes();
+        header.push(self.recipients.len() as u8);
+        for recipient in self.recipients.iter() {
+            header.extend_from_slice(&recipient.kcv);
+            header.extend_from_slice(&recipient.wrapped_key);
+        }
+        header
+    }
+
+    /// The header without the wrapped data keys, which is what authenticated modes
+    /// take as associated data. The wrapped keys are protected by key wrap itself,
+    /// so they can be replaced without touching the payload.
+    pub fn authenticated_bytes(&self) -> Vec<u8> {
+        let mut header =
+            Vec::with_capacity(FIXED_SIZE + self.iv.len() + self.kcv.len() + 1 + 12 + SALT_SIZE);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.push(CIPHER_AES);
+        header.push(mode_id(self.mode));
+        header.extend_from_slice(&self.key_size.to_be_bytes());
+        header.extend_from_slice(&self.chunk_size.to_be_bytes());
+        header.push(self.tag_size);
+        header.extend_from_slice(&self.sector_size.to_be_bytes());
+        header.push(layout_id(self.counter_layout));
+        header.push(match self.counter_endianness {
+            CounterEndianness::Big => 0,
+            CounterEndianness::Little => 1,
+        });
+        header.push(self.iv.len() as u8);
+        header.extend_from_slice(&self.iv);
+        header.extend_from_slice(&self.kcv);
+        match (self.kdf, &self.master) {
+            (Some((kdf, salt)), _) => {
+                let (id, params) = kdf.encode();
+                header.push(id);
+                for param in params.iter() {
+                    header.extend_from_slice(&param.to_be_bytes());
+                }
+                header.extend_from_slice(&salt);
+            }
+            (None, Some(master)) => {
+                header.push(MASTER_KEY_ID);
+                header.extend_from_slice(&master.salt);
+                header.extend_from_slice(&(master.path.len() as u16).to_be_bytes());
+                header.extend_from_slice(master.path.as_bytes());
+            }
+            (None, None) => header.push(0),
+        }
+        header
+    }
+
+    /// Reads and validates the header, leaving `reader` at the start of the payload
+    pub fn read<R: Read>(reader: &mut R) -> Result<Header, AesError> {
+        let mut fixed = [0u8; FIXED_SIZE];
+        if read_full(reader, &mut fixed)? < FIXED_SIZE || &fixed[..MAGIC.len()] != MAGIC {
+            return Err(AesError::InvalidHeader);
+        }
+        let fields = &fixed[MAGIC.len()..];
+        if fields[0] != VERSION {
+            return Err(AesError::UnsupportedVersion(fields[0]));
+        }
+        let mode = match mode_from_id(fields[2]) {
+            Some(mode) if fields[1] == CIPHER_AES => mode,
+            _ => return Err(AesError::InvalidHeader),
+        };
+        let key_size = u16::from_be_bytes([fields[3], fields[4]]);
+        let chunk_size = u32::from_be_bytes([fields[5], fields[6], fields[7], fields[8]]);
+        let tag_size = fields[9];
+        let sector_size = u32::from_be_bytes([fields[10], fields[11], fields[12], fields[13]]);
+        let counter_layout = layout_from_id(fields[14]).ok_or(AesError::InvalidHeader)?;
+        let counter_endianness = match fields[15] {
+            0 => CounterEndianness::Big,
+            1 => CounterEndianness::Little,
+            _ => return Err(AesError::InvalidHeader),
+        };
+        let key_factor = if mode.double_key() { 2 } else { 1 };
+        let (iv_min, iv_max) = mode.iv_sizes();
+        let iv_len = fields[16] as usize;
+        if ![128, 192, 256]
+            .iter()
+            .any(|size| key_size == size * key_factor)
+            || chunk_size == 0
+            || iv_len < iv_min
+            || iv_len > iv_max
+            || !valid_tag_size(mode, tag_size as usize)
+            || !valid_sector_size(mode, sector_size)
+        {
+            return Err(AesError::InvalidHeader);
+        }
+
+        // IV, the key check values of the AES keys and the KDF identifier, followed by
+        // its parameters and the salt, or by the salt and path of a master key
+        let mut rest = vec![0u8; iv_len + KCV_SIZE * key_factor as usize + 1];
+        if read_full(reader, &mut rest)? < rest.len() {
+            return Err(AesError::InvalidHeader);
+        }
+        let kdf_id = rest.pop().unwrap_or(0);
+        let kcv = rest.split_off(iv_len);
+        let mut master = None;
+        let kdf = if kdf_id == 0 {
+            None
+        } else if kdf_id == MASTER_KEY_ID {
+            let mut block = [0u8; master_key::SALT_SIZE + 2];
+            if read_full(reader, &mut block)? < block.len() {
+                return Err(AesError::InvalidHeader);
+            }
+            let (salt_bytes, len) = block.split_at(master_key::SALT_SIZE);
+            let mut path = vec![0u8; u16::from_be_bytes([len[0], len[1]]) as usize];
+            if read_full(reader, &mut path)? < path.len() {
+                return Err(AesError::InvalidHeader);
+            }
+            let mut salt = [0u8; master_key::SALT_SIZE];
+            salt.copy_from_slice(salt_bytes);
+            master = Some(FileDerivation {
+                salt,
+                path: String::from_utf8(path).map_err(|_| AesError::InvalidHeader)?,
+            });
+            None
+        } else {
+            let mut block = [0u8; 12 + SALT_SIZE];
+            if read_full(reader, &mut block)? < block.len() {
+                return Err(AesError::InvalidHeader);
+            }
+            let mut params = [0u32; 3];
+            for (param, bytes) in params.iter_mut().zip(block.chunks_exact(4)) {
+                *param = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+            let kdf = Kdf::decode(kdf_id, params).ok_or(AesError::InvalidHeader)?;
+            let mut salt = [0u8; SALT_SIZE];
+            salt.copy_from_slice(&block[12..]);
+            Some((kdf, salt))
+        };
+
+        // The wrapped data keys, each as long as the data key plus one semiblock
+        let mut count = [0u8; 1];
+        if read_full(reader, &mut count)? < 1 {
+            return Err(AesError::InvalidHeader);
+        }
+        let mut recipients = Vec::with_capacity(count[0] as usize);
+        for _ in 0..count[0] {
+            let mut entry = vec![0u8; KCV_SIZE + key_size as usize / 8 + kw::SEMIBLOCK_SIZE];
+            if read_full(reader, &mut entry)? < entry.len() {
+                return Err(AesError::InvalidHeader);
+            }
+            let mut kcv = [0u8; KCV_SIZE];
+            kcv.copy_from_slice(&entry[..KCV_SIZE]);
+            recipients.push(Recipient {
+                kcv,
+                wrapped_key: entry.split_off(KCV_SIZE),
+            });
+        }
+
+        Ok(Header {
+            mode,
+            key_size,
+            chunk_size,
+            tag_size,
+            sector_size,
+            counter_layout,
+            counter_endianness,
+            iv: rest,
+            kcv,
+            kdf,
+            master,
+            recipients,
+        })
+    }
+}
+
+/// Tag lengths `mode` accepts, 0 for the modes without authentication
+fn valid_tag_size(mode: Mode, tag_size: usize) -> bool {
+    match mode {
+        Mode::GcmSiv => tag_size == gcm_siv::TAG_SIZE,
+        Mode::Siv => tag_size == siv::SIV_SIZE,
+        Mode::Ccm => (4..=16).contains(&tag_size) && tag_size & 1 == 0,
+        Mode::Ocb | Mode::Eax => (1..=16).contains(&tag_size),
+        _ => tag_size == 0,
+    }
+}
+
+/// XTS data units of 512 or 4096 bytes, 0 for the other modes
+fn valid_sector_size(mode: Mode, sector_size: u32) -> bool {
+    match mode {
+        Mode::Xts => sector_size == 512 || sector_size == 4096,
+        _ => sector_size == 0,
+    }
+}
+
+/// Identifiers of the modes in the header, key wrap has no container
+fn mode_id(mode: Mode) -> u8 {
+    match mode {
+        Mode::Ctr => 1,
+        Mode::Cbc => 2,
+        Mode::Xts => 3,
+        Mode::Cfb128 => 4,
+        Mode::Cfb8 => 5,
+        Mode::Cfb1 => 6,
+        Mode::Ofb => 7,
+        Mode::GcmSiv => 8,
+        Mode::Siv => 9,
+        Mode::Ccm => 10,
+        Mode::Ocb => 11,
+        Mode::Eax => 12,
+        Mode::Kw | Mode::Kwp => unreachable!("key wrap writes no container"),
+    }
+}
+
+fn mode_from_id(id: u8) -> Option<Mode> {
+    match id {
+        1 => Some(Mode::Ctr),
+        2 => Some(Mode::Cbc),
+        3 => Some(Mode::Xts),
+        4 => Some(Mode::Cfb128),
+        5 => Some(Mode::Cfb8),
+        6 => Some(Mode::Cfb1),
+        7 => Some(Mode::Ofb),
+        8 => Some(Mode::GcmSiv),
+        9 => Some(Mode::Siv),
+        10 => Some(Mode::Ccm),
+        11 => Some(Mode::Ocb),
+        12 => Some(Mode::Eax),
+        _ => None,
+    }
+}
+
+fn layout_id(layout: CounterLayout) -> u8 {
+    match layout {
+        CounterLayout::Full128 => 0,
+        CounterLayout::Split64 => 1,
+        CounterLayout::Split96 { check_wrap: true } => 2,
+        CounterLayout::Split96 { check_wrap: false } => 3,
+    }
+}
+
+fn layout_from_id(id: u8) -> Option<CounterLayout> {
+    match id {
+        0 => Some(CounterLayout::Full128),
+        1 => Some(CounterLayout::Split64),
+        2 => Some(CounterLayout::Split96 { check_wrap: true }),
+        3 => Some(CounterLayout::Split96 { check_wrap: false }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrypt::ScryptParams;
+
+    /// Offsets of the tag size, sector size and IV length in the serialized header
+    const TAG_SIZE_OFFSET: usize = 15;
+    const SECTOR_SIZE_OFFSET: usize = 16;
+    const IV_LEN_OFFSET: usize = 22;
+
+    fn header(mode: Mode, key_size: u16, tag_size: u8, sector_size: u32, iv: &[u8]) -> Header {
+        let key_factor = if mode.double_key() { 2 } else { 1 };
+        Header {
+            mode,
+            key_size,
+            chunk_size: CHUNK_SIZE as u32,
+            tag_size,
+            sector_size,
+            counter_layout: CounterLayout::Full128,
+            counter_endianness: CounterEndianness::Big,
+            iv: iv.to_vec(),
+            kcv: vec![7; KCV_SIZE * key_factor],
+            kdf: None,
+            master: None,
+            recipients: Vec::new(),
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Result<Header, AesError> {
+        Header::read(&mut &bytes[..])
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut ctr = header(Mode::Ctr, 256, 0, 0, &[1; 16]);
+        ctr.counter_layout = CounterLayout::Split96 { check_wrap: false };
+        ctr.counter_endianness = CounterEndianness::Little;
+        ctr.kdf = Some((
+            Kdf::Scrypt(ScryptParams {
+                log_n: 15,
+                r: 8,
+                p: 1,
+            }),
+            [2; SALT_SIZE],
+        ));
+        let mut xts = header(Mode::Xts, 512, 0, 4096, &[]);
+        xts.master = Some(FileDerivation {
+            salt: [3; master_key::SALT_SIZE],
+            path: "dir/file.bin".to_string(),
+        });
+        let mut ccm = header(Mode::Ccm, 128, 12, 0, &[4; 13]);
+        ccm.recipients = vec![
+            Recipient {
+                kcv: [5; KCV_SIZE],
+                wrapped_key: vec![6; 16 + kw::SEMIBLOCK_SIZE],
+            };
+            2
+        ];
+        let siv = header(Mode::Siv, 512, siv::SIV_SIZE as u8, 0, &[]);
+
+        for header in [ctr, xts, ccm, siv].iter() {
+            let mut bytes = header.to_bytes();
+            bytes.extend_from_slice(b"payload");
+            let mut reader = &bytes[..];
+            assert_eq!(Header::read(&mut reader).unwrap(), *header);
+            assert_eq!(reader, b"payload");
+        }
+    }
+
+    /// Sector sizes other than 512 and 4096 would make XTS divide by zero or
+    /// process the file as one huge sector, and the other modes have none
+    #[test]
+    fn rejects_invalid_sector_size() {
+        let bytes = header(Mode::Xts, 256, 0, 512, &[]).to_bytes();
+        assert!(read(&bytes).is_ok());
+        for &sector_size in [0u32, 1024, 0xffff_ffff].iter() {
+            let mut bytes = bytes.clone();
+            bytes[SECTOR_SIZE_OFFSET..SECTOR_SIZE_OFFSET + 4]
+                .copy_from_slice(&sector_size.to_be_bytes());
+            assert!(matches!(read(&bytes), Err(AesError::InvalidHeader)));
+        }
+
+        let mut bytes = header(Mode::Ctr, 128, 0, 0, &[1; 16]).to_bytes();
+        bytes[SECTOR_SIZE_OFFSET..SECTOR_SIZE_OFFSET + 4].copy_from_slice(&512u32.to_be_bytes());
+        assert!(matches!(read(&bytes), Err(AesError::InvalidHeader)));
+    }
+
+    #[test]
+    fn rejects_invalid_tag_size() {
+        let cases = [
+            (header(Mode::Ctr, 128, 0, 0, &[1; 16]), 16),
+            (header(Mode::GcmSiv, 128, 16, 0, &[1; 12]), 12),
+            (header(Mode::Siv, 256, 16, 0, &[]), 8),
+            (header(Mode::Ccm, 128, 8, 0, &[1; 12]), 5),
+            (header(Mode::Ocb, 128, 16, 0, &[1; 12]), 0),
+            (header(Mode::Eax, 128, 16, 0, &[1; 16]), 17),
+        ];
+        for (header, tag_size) in cases.iter() {
+            let mut bytes = header.to_bytes();
+            assert!(read(&bytes).is_ok());
+            bytes[TAG_SIZE_OFFSET] = *tag_size;
+            assert!(matches!(read(&bytes), Err(AesError::InvalidHeader)));
+        }
+    }
+
+    /// IV lengths outside of the mode's range are rejected before the IV is read
+    #[test]
+    fn rejects_invalid_iv_length() {
+        let cases = [
+            (header(Mode::Cbc, 128, 0, 0, &[1; 16]), 12),
+            (header(Mode::Xts, 256, 0, 512, &[]), 16),
+            (header(Mode::GcmSiv, 128, 16, 0, &[1; 12]), 16),
+            (header(Mode::Ccm, 128, 8, 0, &[1; 12]), 6),
+        ];
+        for (header, iv_len) in cases.iter() {
+            let mut bytes = header.to_bytes();
+            bytes[IV_LEN_OFFSET] = *iv_len;
+            assert!(matches!(read(&bytes), Err(AesError::InvalidHeader)));
+        }
+    }
+}
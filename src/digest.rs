@@ -0,0 +1,74 @@
+/// Incremental hash function as used by HMAC, PBKDF2, `EVP_BytesToKey` and Argon2
+pub trait Digest: Clone {
+    /// Size of the internal block in bytes
+    const BLOCK_SIZE: usize;
+    /// Size of the hash value in bytes
+    const OUTPUT_SIZE: usize;
+
+    fn new() -> Self;
+
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self) -> Vec<u8>;
+
+    /// Hashes `data` in one go
+    fn digest(data: &[u8]) -> Vec<u8> {
+        let mut hash = Self::new();
+        hash.update(data);
+        hash.finalize()
+    }
+}
+
+/// Buffers input into 64-byte blocks and appends the Merkle–Damgård length padding
+/// shared by MD5 and SHA-256. Only the byte order of the length differs.
+#[derive(Clone)]
+pub(crate) struct BlockBuffer {
+    buffer: [u8; 64],
+    buffered: usize,
+    len: u64,
+}
+
+impl BlockBuffer {
+    pub(crate) fn new() -> BlockBuffer {
+        BlockBuffer {
+            buffer: [0u8; 64],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    /// Feeds `data` and calls `compress` for every complete block
+    pub(crate) fn update<F: FnMut(&[u8; 64])>(&mut self, mut data: &[u8], mut compress: F) {
+        self.len = self.len.wrapping_add(data.len() as u64);
+        while !data.is_empty() {
+            let n = (64 - self.buffered).min(data.len());
+            self.buffer[self.buffered..self.buffered + n].copy_from_slice(&data[..n]);
+            self.buffered += n;
+            data = &data[n..];
+            if self.buffered == 64 {
+                compress(&self.buffer);
+                self.buffered = 0;
+            }
+        }
+    }
+
+    /// Appends `0x80`, zeros and the message length in bits
+    pub(crate) fn finalize<F: FnMut(&[u8; 64])>(mut self, big_endian: bool, mut compress: F) {
+        let bit_len = self.len.wrapping_mul(8);
+        self.buffer[self.buffered] = 0x80;
+        for byte in &mut self.buffer[self.buffered + 1..] {
+            *byte = 0;
+        }
+        if self.buffered >= 56 {
+            compress(&self.buffer);
+            self.buffer = [0u8; 64];
+        }
+        let len_bytes = if big_endian {
+            bit_len.to_be_bytes()
+        } else {
+            bit_len.to_le_bytes()
+        };
+        self.buffer[56..].copy_from_slice(&len_bytes);
+        compress(&self.buffer);
+    }
+}
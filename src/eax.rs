@@ -0,0 +1,348 @@
+use crate::aes_ctr_optimized::{
+    ct_eq, expand_key, for_each_chunk, open_output_file, process_chunk, process_payload_chunked,
+    read_full, Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::cmac::{Cmac, CmacState};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Shortest and longest nonce accepted on the command line, the cipher itself
+/// takes nonces of any length
+pub const NONCE_SIZES: (usize, usize) = (1, BLOCK_SIZE);
+
+/// AES-EAX (Bellare, Rogaway, Wagner), OMAC over nonce, header and ciphertext
+/// combined with CTR
+pub struct Eax {
+    mac: Cmac,
+    keys: SecretBytes,
+    nr: usize,
+    tag_size: usize,
+}
+
+impl Eax {
+    /// Creates a cipher from an AES key and a tag length of 1 to 16 bytes
+    pub fn new(key: &[u8], tag_size: usize) -> Result<Eax, AesError> {
+        if !(1..=16).contains(&tag_size) {
+            return Err(AesError::InvalidTagLength(tag_size));
+        }
+        let (keys, nr) = expand_key(key)?;
+        Ok(Eax {
+            mac: Cmac::new(key)?,
+            keys,
+            nr,
+            tag_size,
+        })
+    }
+
+    /// Starts an incremental encryption or decryption under `nonce`
+    pub fn start(&self, nonce: &[u8]) -> EaxState<'_> {
+        let mut nonce_mac = omac(&self.mac, 0);
+        nonce_mac.update(nonce);
+        EaxState {
+            eax: self,
+            counter: nonce_mac.finalize(),
+            header_mac: omac(&self.mac, 1),
+            ciphertext_mac: omac(&self.mac, 2),
+            position: 0,
+        }
+    }
+
+    /// Encrypts `data` in place and returns the tag
+    pub fn encrypt(&self, nonce: &[u8], aad: &[u8], data: &mut [u8]) -> Vec<u8> {
+        let mut state = self.start(nonce);
+        state.update_aad(aad);
+        state.encrypt(data);
+        state.finalize()
+    }
+
+    /// Decrypts `data` in place if `tag` is valid, otherwise `data` is left untouched
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), AesError> {
+        let mut state = self.start(nonce);
+        state.update_aad(aad);
+        state.authenticate(data);
+        let counter = state.counter;
+        state.verify(tag)?;
+        process_chunk(data, &self.keys, &counter, self.nr, 0);
+        Ok(())
+    }
+}
+
+/// OMAC^t: CMAC with the block `[t]_16` prepended
+fn omac(mac: &Cmac, t: u8) -> CmacState<'_> {
+    let mut state = mac.start();
+    let mut tweak = [0u8; BLOCK_SIZE];
+    tweak[BLOCK_SIZE - 1] = t;
+    state.update(&tweak);
+    state
+}
+
+/// Running EAX computation. Associated data and message can be fed in pieces of
+/// any size, the associated data may also be interleaved with the message.
+pub struct EaxState<'a> {
+    eax: &'a Eax,
+    /// N' = OMAC^0(N), the initial CTR counter block
+    counter: [u8; BLOCK_SIZE],
+    header_mac: CmacState<'a>,
+    ciphertext_mac: CmacState<'a>,
+    /// Number of message bytes processed so far
+    position: u64,
+}
+
+impl<'a> EaxState<'a> {
+    /// Absorbs associated data
+    pub fn update_aad(&mut self, aad: &[u8]) {
+        self.header_mac.update(aad);
+    }
+
+    /// Encrypts the next part of the message in place
+    pub fn encrypt(&mut self, data: &mut [u8]) {
+        self.apply_keystream(data);
+        self.ciphertext_mac.update(data);
+    }
+
+    /// Decrypts the next part of the message in place. The plaintext must not be
+    /// used before `verify` succeeded.
+    pub fn decrypt(&mut self, data: &mut [u8]) {
+        self.ciphertext_mac.update(data);
+        self.apply_keystream(data);
+    }
+
+    /// Authenticates the next part of the ciphertext without decrypting it, so that
+    /// a message can be verified before any plaintext is produced
+    pub fn authenticate(&mut self, data: &[u8]) {
+        self.ciphertext_mac.update(data);
+        self.position += data.len() as u64;
+    }
+
+    /// Returns the tag `N' xor H' xor C'`, truncated to the tag length
+    pub fn finalize(self) -> Vec<u8> {
+        let header = self.header_mac.finalize();
+        let ciphertext = self.ciphertext_mac.finalize();
+        self.counter
+            .iter()
+            .zip(header.iter().zip(ciphertext.iter()))
+            .take(self.eax.tag_size)
+            .map(|(n, (h, c))| n ^ h ^ c)
+            .collect()
+    }
+
+    /// Compares the computed tag with `tag` in constant time
+    pub fn verify(self, tag: &[u8]) -> Result<(), AesError> {
+        if ct_eq(&self.finalize(), tag) {
+            Ok(())
+        } else {
+            Err(AesError::AuthenticationFailed)
+        }
+    }
+
+    /// CTR with the 128-bit big-endian counter starting at N', a part may start in
+    /// the middle of a keystream block
+    fn apply_keystream(&mut self, mut data: &mut [u8]) {
+        let (keys, nr) = (&self.eax.keys, self.eax.nr);
+        let offset = (self.position % BLOCK_SIZE as u64) as usize;
+        let block = self.position / BLOCK_SIZE as u64;
+        self.position += data.len() as u64;
+        if offset > 0 {
+            let mut keystream = [0u8; BLOCK_SIZE];
+            process_chunk(&mut keystream, keys, &self.counter, nr, block);
+            let n = (BLOCK_SIZE - offset).min(data.len());
+            for (byte, k) in data[..n].iter_mut().zip(keystream[offset..].iter()) {
+                *byte ^= k;
+            }
+            data = &mut data[n..];
+            process_chunk(data, keys, &self.counter, nr, block + 1);
+        } else {
+            process_chunk(data, keys, &self.counter, nr, block);
+        }
+    }
+}
+
+/// Encrypts a file into `C || T`. OMAC over the ciphertext is inherently
+/// sequential, so the file is processed chunk by chunk on the current thread.
+pub fn encrypt_file(
+    eax: &Eax,
+    nonce: &[u8],
+    aad: &[u8],
+    input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, input_file);
+    let mut writer = BufWriter::with_capacity(CHUNK_SIZE, output_file);
+    let mut state = eax.start(nonce);
+    state.update_aad(aad);
+
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let bytes_read = read_full(&mut reader, &mut chunk)?;
+        if bytes_read == 0 {
+            break;
+        }
+        state.encrypt(&mut chunk[..bytes_read]);
+        writer.write_all(&chunk[..bytes_read])?;
+    }
+    writer.write_all(&state.finalize())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypts a file written by `encrypt_file`. The tag only covers the ciphertext,
+/// so it is verified in a first pass without decrypting anything, the decryption
+/// pass then runs on the thread pool.
+pub fn decrypt_file(
+    eax: &Eax,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let input_len = input_file.metadata()?.len() - input_offset;
+    if input_len < eax.tag_size as u64 {
+        return Err(AesError::InvalidLength(input_len));
+    }
+    let len = input_len - eax.tag_size as u64;
+
+    let mut tag = vec![0u8; eax.tag_size];
+    input_file.seek(SeekFrom::Start(input_offset + len))?;
+    input_file.read_exact(&mut tag)?;
+    input_file.seek(SeekFrom::Start(input_offset))?;
+
+    let mut state = eax.start(nonce);
+    state.update_aad(aad);
+    for_each_chunk(&input_file, len, |chunk, _| state.authenticate(chunk))?;
+    let counter = state.counter;
+    state.verify(&tag)?;
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: 0,
+    };
+    let (keys, nr) = (eax.keys.clone(), eax.nr);
+    process_payload_chunked(
+        input_file,
+        open_output_file(output_file_path)?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk(chunk, &keys, &counter, nr, starting_block);
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    /// Vectors 1 to 4 of the EAX paper (Bellare, Rogaway, Wagner) as `(message,
+    /// key, nonce, header, ciphertext || tag)`
+    const VECTORS: [(&str, &str, &str, &str, &str); 4] = [
+        (
+            "",
+            "233952DEE4D5ED5F9B9C6D6FF80FF478",
+            "62EC67F9C3A4A407FCB2A8C49031A8B3",
+            "6BFB914FD07EAE6B",
+            "E037830E8389F27B025A2D6527E79D01",
+        ),
+        (
+            "F7FB",
+            "91945D3F4DCBEE0BF45EF52255F095A4",
+            "BECAF043B0A23D843194BA972C66DEBD",
+            "FA3BFD4806EB53FA",
+            "19DD5C4C9331049D0BDAB0277408F67967E5",
+        ),
+        (
+            "1A47CB4933",
+            "01F74AD64077F2E704C0F60ADA3DD523",
+            "70C3DB4F0D26368400A10ED05D2BFF5E",
+            "234A3463C1264AC6",
+            "D851D5BAE03A59F238A23E39199DC9266626C40F80",
+        ),
+        (
+            "481C9E39B1",
+            "D07CF6CBB7F313BDDE66B727AFD3C5E8",
+            "8408DFFF3C1A2B1292DC199E46B7D617",
+            "33CCE2EABFF5A79D",
+            "632A9D131AD4C168A4225D8E1FF755939974A7BEDE",
+        ),
+    ];
+
+    #[test]
+    fn paper_vectors_one_shot() {
+        for (message, key, nonce, header, result) in VECTORS.iter() {
+            let eax = Eax::new(&unhex(key), 16).unwrap();
+            let (nonce, header) = (unhex(nonce), unhex(header));
+            let mut data = unhex(message);
+            let tag = eax.encrypt(&nonce, &header, &mut data);
+            assert_eq!(hex::encode_upper(&data) + &hex::encode_upper(&tag), *result);
+
+            eax.decrypt(&nonce, &header, &mut data, &tag).unwrap();
+            assert_eq!(hex::encode_upper(&data), *message);
+        }
+    }
+
+    /// A shorter tag is a prefix of the full one, unlike in OCB. A changed header
+    /// fails before anything is decrypted, also when only authenticating.
+    #[test]
+    fn truncated_tag_and_changed_header() {
+        let (full, short) = (
+            Eax::new(&[1; 16], 16).unwrap(),
+            Eax::new(&[1; 16], 8).unwrap(),
+        );
+        let nonce = [2; 16];
+        let mut data = *b"attack at dawn";
+        let tag = full.encrypt(&nonce, b"header", &mut data);
+        let mut short_data = *b"attack at dawn";
+        assert_eq!(short.encrypt(&nonce, b"header", &mut short_data), tag[..8]);
+        assert_eq!(short_data, data);
+
+        let ciphertext = data;
+        let result = full.decrypt(&nonce, b"Header", &mut data, &tag);
+        assert!(matches!(result, Err(AesError::AuthenticationFailed)));
+        assert_eq!(data, ciphertext);
+
+        let mut state = full.start(&nonce);
+        state.update_aad(b"Header");
+        state.authenticate(&ciphertext);
+        assert!(matches!(
+            state.verify(&tag),
+            Err(AesError::AuthenticationFailed)
+        ));
+    }
+
+    /// Feeds header and message byte by byte, with the header interleaved
+    #[test]
+    fn paper_vectors_incremental() {
+        for (message, key, nonce, header, result) in VECTORS.iter() {
+            let eax = Eax::new(&unhex(key), 16).unwrap();
+            let (nonce, header) = (unhex(nonce), unhex(header));
+            let (first, second) = header.split_at(header.len() / 2);
+
+            let mut data = unhex(message);
+            let mut state = eax.start(&nonce);
+            first.iter().for_each(|byte| state.update_aad(&[*byte]));
+            data.chunks_mut(1).for_each(|byte| state.encrypt(byte));
+            second.iter().for_each(|byte| state.update_aad(&[*byte]));
+            let tag = state.finalize();
+            assert_eq!(hex::encode_upper(&data) + &hex::encode_upper(&tag), *result);
+
+            let mut state = eax.start(&nonce);
+            state.update_aad(&header);
+            data.chunks_mut(1).for_each(|byte| state.decrypt(byte));
+            state.verify(&tag).unwrap();
+            assert_eq!(hex::encode_upper(&data), *message);
+        }
+    }
+}
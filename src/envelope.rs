@@ -0,0 +1,93 @@
+use crate::aes_ctr_optimized::replace_atomically;
+use crate::container::{Header, Recipient};
+use crate::error::AesError;
+use crate::kcv;
+use crate::kw;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Maximum number of recipients, the container header stores their count in a byte
+pub const MAX_RECIPIENTS: usize = u8::MAX as usize;
+
+/// Wraps `data_key` under the key encryption key `kek`
+pub fn recipient(kek: &[u8], data_key: &[u8]) -> Result<Recipient, AesError> {
+    Ok(Recipient {
+        kcv: kcv::kcv(kek)?,
+        wrapped_key: kw::wrap(kek, data_key)?,
+    })
+}
+
+/// Unwraps the data key with the key encryption key `kek`. Entries are found by
+/// the key check value, which another key may share by chance, so every matching
+/// entry is tried.
+pub fn unwrap_data_key(header: &Header, kek: &[u8]) -> Result<SecretBytes, AesError> {
+    let kcv = kcv::kcv(kek)?;
+    header
+        .recipients
+        .iter()
+        .filter(|recipient| recipient.kcv == kcv)
+        .map(|recipient| kw::unwrap(kek, &recipient.wrapped_key))
+        .find(Result::is_ok)
+        .unwrap_or(Err(AesError::KeyCheckFailed))
+}
+
+/// Wraps the data key of an envelope encrypted file under `new_kek` as well, `kek`
+/// has to be one of the recipients already
+pub fn add_recipient(
+    kek: &[u8],
+    new_kek: &[u8],
+    input_file_path: &Path,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let mut input_file = File::open(input_file_path)?;
+    let mut header = Header::read(&mut input_file)?;
+    let data_key = unwrap_data_key(&header, kek)?;
+    if unwrap_data_key(&header, new_kek).is_ok() {
+        return Err(AesError::DuplicateRecipient);
+    }
+    if header.recipients.len() >= MAX_RECIPIENTS {
+        return Err(AesError::TooManyRecipients);
+    }
+    header.recipients.push(recipient(new_kek, &data_key)?);
+    rewrite_header(&header, input_file, output_file_path)
+}
+
+/// Removes the recipients whose key encryption key has the key check value `kcv`,
+/// at least one recipient has to remain
+pub fn remove_recipient(
+    kcv: &[u8],
+    input_file_path: &Path,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let mut input_file = File::open(input_file_path)?;
+    let mut header = Header::read(&mut input_file)?;
+    let count = header.recipients.len();
+    header
+        .recipients
+        .retain(|recipient| recipient.kcv[..] != *kcv);
+    if header.recipients.len() == count {
+        return Err(AesError::UnknownRecipient);
+    }
+    if header.recipients.is_empty() {
+        return Err(AesError::LastRecipient);
+    }
+    rewrite_header(&header, input_file, output_file_path)
+}
+
+/// Writes `header` followed by the payload of `input_file` as it is. The wrapped
+/// data keys are not authenticated as part of the header, so the tag stays valid.
+fn rewrite_header(
+    header: &Header,
+    input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    replace_atomically(output_file_path, |output_file| {
+        let mut writer = BufWriter::new(output_file);
+        writer.write_all(&header.to_bytes())?;
+        io::copy(&mut BufReader::new(input_file), &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    })
+}
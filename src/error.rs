@@ -0,0 +1,125 @@
+use crate::aes_ctr_optimized::Mode;
+use std::fmt;
+use std::io;
+
+/// Errors that can occur while encrypting or decrypting a file or wrapping a key
+#[derive(Debug)]
+pub enum AesError {
+    /// Reading from or writing to a file failed
+    Io(io::Error),
+    /// The input length is not valid for the selected mode
+    InvalidLength(u64),
+    /// The key has none of the supported lengths (given in bytes)
+    InvalidKeyLength(usize),
+    /// The nonce has the wrong length for the selected mode (given in bytes)
+    InvalidNonceLength(usize),
+    /// The tag length is not supported by the selected mode (given in bytes)
+    InvalidTagLength(usize),
+    /// The PKCS#7 padding of the last block is malformed (wrong key or corrupted data)
+    BadPadding,
+    /// The authentication tag does not match (wrong key, nonce or tampered data)
+    AuthenticationFailed,
+    /// The input needs more blocks than the CTR counter field has left, continuing
+    /// would wrap the counter around and reuse keystream
+    CounterOverflow,
+    /// Unwrapping a key failed the integrity check (wrong key encryption key or corrupted data)
+    IntegrityCheckFailed,
+    /// The input does not start with a valid container or `Salted__` header (headerless
+    /// input, or the header is corrupted)
+    InvalidHeader,
+    /// The container header has a format version this tool cannot read
+    UnsupportedVersion(u8),
+    /// The key check value in the container header does not match the key (wrong key
+    /// or passphrase)
+    KeyCheckFailed,
+    /// An all-zero IV in a container, whose key check value would be the start of
+    /// the keystream
+    ZeroIv,
+    /// The key encryption key is already a recipient of the file
+    DuplicateRecipient,
+    /// The container header has no room for another recipient
+    TooManyRecipients,
+    /// No recipient of the file has the given key check value
+    UnknownRecipient,
+    /// Removing the recipient would leave no key to decrypt the file with
+    LastRecipient,
+    /// The operation is not available for the mode of the input file
+    UnsupportedMode(Mode),
+    /// The key store file is truncated, corrupted or not a key store
+    InvalidKeyStore,
+    /// Key names have to be 1 to 255 bytes long and must not look like a hex key
+    InvalidKeyName(String),
+    /// The key store already has a key with this name
+    DuplicateKeyName(String),
+    /// The key store has no key with this name
+    UnknownKeyName(String),
+}
+
+impl fmt::Display for AesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AesError::Io(e) => write!(f, "I/O error: {}", e),
+            AesError::InvalidLength(len) => {
+                write!(
+                    f,
+                    "input length of {} bytes is not valid for this mode",
+                    len
+                )
+            }
+            AesError::InvalidKeyLength(len) => {
+                write!(f, "key length of {} bytes is not supported", len)
+            }
+            AesError::InvalidNonceLength(len) => {
+                write!(f, "nonce length of {} bytes is not supported", len)
+            }
+            AesError::InvalidTagLength(len) => {
+                write!(f, "tag length of {} bytes is not supported", len)
+            }
+            AesError::BadPadding => write!(f, "bad decrypt (invalid padding)"),
+            AesError::AuthenticationFailed => write!(f, "authentication failed"),
+            AesError::CounterOverflow => {
+                write!(f, "counter would wrap around (input too long for the IV)")
+            }
+            AesError::IntegrityCheckFailed => write!(f, "key unwrap integrity check failed"),
+            AesError::InvalidHeader => write!(
+                f,
+                "missing or invalid header (headerless input or not encrypted with a password?)"
+            ),
+            AesError::UnsupportedVersion(version) => {
+                write!(f, "container format version {} is not supported", version)
+            }
+            AesError::KeyCheckFailed => {
+                write!(
+                    f,
+                    "key check value does not match (wrong key or passphrase?)"
+                )
+            }
+            AesError::ZeroIv => {
+                write!(f, "all-zero IV is not allowed in the container format")
+            }
+            AesError::DuplicateRecipient => write!(f, "key is already a recipient"),
+            AesError::TooManyRecipients => write!(f, "too many recipients"),
+            AesError::UnknownRecipient => write!(f, "no recipient has this key check value"),
+            AesError::LastRecipient => write!(f, "the last recipient cannot be removed"),
+            AesError::UnsupportedMode(mode) => {
+                write!(
+                    f,
+                    "mode '{}' is not supported by this operation",
+                    mode.name()
+                )
+            }
+            AesError::InvalidKeyStore => write!(f, "invalid or corrupted key store"),
+            AesError::InvalidKeyName(name) => write!(f, "invalid key name '{}'", name),
+            AesError::DuplicateKeyName(name) => {
+                write!(f, "key store already has a key named '{}'", name)
+            }
+            AesError::UnknownKeyName(name) => write!(f, "key store has no key named '{}'", name),
+        }
+    }
+}
+
+impl From<io::Error> for AesError {
+    fn from(e: io::Error) -> Self {
+        AesError::Io(e)
+    }
+}
@@ -0,0 +1,461 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, apply_keystream, ct_eq, expand_key, for_each_chunk, open_output_file,
+    process_payload_chunked, Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::{self, SecretBytes};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the nonce in bytes
+pub const NONCE_SIZE: usize = 12;
+/// Size of the authentication tag in bytes
+pub const TAG_SIZE: usize = 16;
+/// Maximum length of plaintext and associated data in bytes (2^36)
+const MAX_LEN: u64 = 1 << 36;
+
+/// POLYVAL universal hash from RFC 8452, the little-endian sibling of GHASH
+pub struct Polyval {
+    h: u128,
+    s: u128,
+}
+
+impl Polyval {
+    pub fn new(key: &[u8; 16]) -> Polyval {
+        Polyval {
+            h: u128::from_le_bytes(*key),
+            s: 0,
+        }
+    }
+
+    /// Absorbs `data`, a partial last block is padded with zeros.
+    /// Only the last call for a field may end in the middle of a block.
+    pub fn update_padded(&mut self, data: &[u8]) {
+        for block in data.chunks(BLOCK_SIZE) {
+            let mut padded = [0u8; BLOCK_SIZE];
+            padded[..block.len()].copy_from_slice(block);
+            self.s = dot(self.s ^ u128::from_le_bytes(padded), self.h);
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 16] {
+        self.s.to_le_bytes()
+    }
+}
+
+/// Multiplication in POLYVAL's field, i.e. `a * b * x^-128` modulo
+/// `x^128 + x^127 + x^126 + x^121 + 1`
+fn dot(a: u128, b: u128) -> u128 {
+    const REDUCTION: u128 = 1 | (1 << 121) | (1 << 126) | (1 << 127);
+    let mut r: u128 = 0;
+    for i in 0..128 {
+        if (b >> i) & 1 == 1 {
+            r ^= a;
+        }
+        // Multiply by x^-1
+        if r & 1 == 1 {
+            r = ((r ^ REDUCTION) >> 1) | (1 << 127);
+        } else {
+            r >>= 1;
+        }
+    }
+    r
+}
+
+/// AES-GCM-SIV (RFC 8452), nonce misuse resistant authenticated encryption
+pub struct GcmSiv {
+    keys: SecretBytes,
+    nr: usize,
+}
+
+/// Per-nonce keys derived from the key generating key
+struct DerivedKeys {
+    authentication_key: [u8; 16],
+    encryption_keys: SecretBytes,
+    nr: usize,
+}
+
+impl GcmSiv {
+    /// Creates a cipher from a 16 or 32 byte key generating key
+    pub fn new(key: &[u8]) -> Result<GcmSiv, AesError> {
+        if key.len() != 16 && key.len() != 32 {
+            return Err(AesError::InvalidKeyLength(key.len()));
+        }
+        let (keys, nr) = expand_key(key)?;
+        Ok(GcmSiv { keys, nr })
+    }
+
+    /// Encrypts `data` in place and returns the tag
+    pub fn encrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; TAG_SIZE], AesError> {
+        check_lengths(nonce, aad.len() as u64, data.len() as u64)?;
+        let derived = self.derive_keys(nonce);
+        let mut polyval = Polyval::new(&derived.authentication_key);
+        polyval.update_padded(aad);
+        polyval.update_padded(data);
+        let tag = derived.tag(polyval, nonce, aad.len() as u64, data.len() as u64);
+        derived.apply_keystream(data, &tag, 0);
+        Ok(tag)
+    }
+
+    /// Decrypts `data` in place if `tag` is valid, otherwise `data` is wiped
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), AesError> {
+        check_lengths(nonce, aad.len() as u64, data.len() as u64)?;
+        if tag.len() != TAG_SIZE {
+            return Err(AesError::AuthenticationFailed);
+        }
+        let derived = self.derive_keys(nonce);
+        derived.apply_keystream(data, tag, 0);
+        let mut polyval = Polyval::new(&derived.authentication_key);
+        polyval.update_padded(aad);
+        polyval.update_padded(data);
+        let expected = derived.tag(polyval, nonce, aad.len() as u64, data.len() as u64);
+        if !ct_eq(&expected, tag) {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+            return Err(AesError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    /// Derives the message authentication and encryption keys for a nonce
+    fn derive_keys(&self, nonce: &[u8]) -> DerivedKeys {
+        let blocks = if self.nr == 10 { 4 } else { 6 };
+        let mut derived = [0u8; 48];
+        for i in 0..blocks {
+            let mut block = [0u8; BLOCK_SIZE];
+            block[..4].copy_from_slice(&(i as u32).to_le_bytes());
+            block[4..].copy_from_slice(nonce);
+            aes_v2(&mut block, &self.keys, self.nr);
+            derived[i * 8..i * 8 + 8].copy_from_slice(&block[..8]);
+        }
+        let mut authentication_key = [0u8; 16];
+        authentication_key.copy_from_slice(&derived[..16]);
+        let (encryption_keys, nr) = expand_key(&derived[16..blocks * 8]).unwrap();
+        secret::wipe(&mut derived);
+        DerivedKeys {
+            authentication_key,
+            encryption_keys,
+            nr,
+        }
+    }
+}
+
+impl Drop for DerivedKeys {
+    fn drop(&mut self) {
+        secret::wipe(&mut self.authentication_key);
+    }
+}
+
+impl DerivedKeys {
+    /// Finishes POLYVAL with the length block and encrypts the result into the tag
+    fn tag(&self, mut polyval: Polyval, nonce: &[u8], aad_len: u64, len: u64) -> [u8; TAG_SIZE] {
+        let mut length_block = [0u8; BLOCK_SIZE];
+        length_block[..8].copy_from_slice(&(aad_len * 8).to_le_bytes());
+        length_block[8..].copy_from_slice(&(len * 8).to_le_bytes());
+        polyval.update_padded(&length_block);
+        let mut tag = polyval.finalize();
+        for (byte, n) in tag.iter_mut().zip(nonce.iter()) {
+            *byte ^= n;
+        }
+        tag[15] &= 0x7f;
+        aes_v2(&mut tag, &self.encryption_keys, self.nr);
+        tag
+    }
+
+    /// CTR with the tag as initial counter block, the first 32 bits are a
+    /// little-endian counter
+    fn apply_keystream(&self, data: &mut [u8], tag: &[u8], starting_block: u64) {
+        let mut initial = [0u8; BLOCK_SIZE];
+        initial.copy_from_slice(tag);
+        initial[15] |= 0x80;
+        let counter = u32::from_le_bytes([initial[0], initial[1], initial[2], initial[3]]);
+        apply_keystream(
+            data,
+            &self.encryption_keys,
+            self.nr,
+            starting_block,
+            |block| {
+                let mut counter_block = initial;
+                counter_block[..4]
+                    .copy_from_slice(&counter.wrapping_add(block as u32).to_le_bytes());
+                counter_block
+            },
+        );
+    }
+}
+
+fn check_lengths(nonce: &[u8], aad_len: u64, len: u64) -> Result<(), AesError> {
+    if nonce.len() != NONCE_SIZE {
+        return Err(AesError::InvalidNonceLength(nonce.len()));
+    }
+    if aad_len > MAX_LEN || len > MAX_LEN {
+        return Err(AesError::InvalidLength(len.max(aad_len)));
+    }
+    Ok(())
+}
+
+/// Encrypts a file and appends the tag. POLYVAL over the plaintext is computed
+/// in a first sequential pass, the CTR pass then runs on the thread pool.
+pub fn encrypt_file(
+    siv: &GcmSiv,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    mut output_file: File,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+    check_lengths(nonce, aad.len() as u64, len)?;
+    let derived = siv.derive_keys(nonce);
+
+    let mut polyval = Polyval::new(&derived.authentication_key);
+    polyval.update_padded(aad);
+    for_each_chunk(&input_file, len, |chunk, _| polyval.update_padded(chunk))?;
+    let tag = derived.tag(polyval, nonce, aad.len() as u64, len);
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: output_file.stream_position()?,
+    };
+    process_payload_chunked(
+        input_file,
+        output_file.try_clone()?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            derived.apply_keystream(chunk, &tag, starting_block);
+            Ok(())
+        },
+    )?;
+    output_file.seek(SeekFrom::Start(payload.output_offset + len))?;
+    output_file.write_all(&tag)?;
+    Ok(())
+}
+
+/// Decrypts a file written by `encrypt_file`. The tag is verified in a first pass
+/// without writing anything, so no unauthenticated plaintext ever reaches the
+/// output file. The decryption pass then runs on the thread pool.
+pub fn decrypt_file(
+    siv: &GcmSiv,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let input_len = input_file.metadata()?.len() - input_offset;
+    if input_len < TAG_SIZE as u64 {
+        return Err(AesError::InvalidLength(input_len));
+    }
+    let len = input_len - TAG_SIZE as u64;
+    check_lengths(nonce, aad.len() as u64, len)?;
+
+    let mut tag = [0u8; TAG_SIZE];
+    input_file.seek(SeekFrom::Start(input_offset + len))?;
+    input_file.read_exact(&mut tag)?;
+    input_file.seek(SeekFrom::Start(input_offset))?;
+
+    let derived = siv.derive_keys(nonce);
+    let mut polyval = Polyval::new(&derived.authentication_key);
+    polyval.update_padded(aad);
+    for_each_chunk(&input_file, len, |chunk, starting_block| {
+        derived.apply_keystream(chunk, &tag, starting_block);
+        polyval.update_padded(chunk);
+    })?;
+    let expected = derived.tag(polyval, nonce, aad.len() as u64, len);
+    if !ct_eq(&expected, &tag) {
+        return Err(AesError::AuthenticationFailed);
+    }
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: 0,
+    };
+    process_payload_chunked(
+        input_file,
+        open_output_file(output_file_path)?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            derived.apply_keystream(chunk, &tag, starting_block);
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encrypts and decrypts each `(key, nonce, aad, plaintext, ciphertext || tag)`
+    fn check(cases: &[(&str, &str, &str, &str, &str)]) {
+        for (key, nonce, aad, plaintext, result) in cases.iter() {
+            let siv = GcmSiv::new(&hex::decode(key).unwrap()).unwrap();
+            let (nonce, aad) = (hex::decode(nonce).unwrap(), hex::decode(aad).unwrap());
+            let mut data = hex::decode(plaintext).unwrap();
+            let tag = siv.encrypt(&nonce, &aad, &mut data).unwrap();
+            assert_eq!(hex::encode(&data) + &hex::encode(tag), *result);
+
+            siv.decrypt(&nonce, &aad, &mut data, &tag).unwrap();
+            assert_eq!(hex::encode(&data), *plaintext);
+        }
+    }
+
+    /// Changed associated data fails and the released plaintext is wiped, a nonce
+    /// that is not 96 bits long is rejected
+    #[test]
+    fn rejects_changed_aad_and_nonce_length() {
+        let siv = GcmSiv::new(&[1; 32]).unwrap();
+        let nonce = [2; NONCE_SIZE];
+        let mut data = *b"attack at dawn";
+        let tag = siv.encrypt(&nonce, b"header", &mut data).unwrap();
+        let result = siv.decrypt(&nonce, b"Header", &mut data, &tag);
+        assert!(matches!(result, Err(AesError::AuthenticationFailed)));
+        assert_eq!(data, [0; 14]);
+
+        let result = siv.encrypt(&nonce[..11], b"", &mut data);
+        assert!(matches!(result, Err(AesError::InvalidNonceLength(11))));
+    }
+
+    /// The POLYVAL example of RFC 8452 section 3
+    #[test]
+    fn rfc8452_polyval() {
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&hex::decode("25629347589242761d31f826ba4b757b").unwrap());
+        let mut polyval = Polyval::new(&key);
+        polyval.update_padded(
+            &hex::decode("4f4f95668c83dfb6401762bb2d01a262d1a24ddd2721d006bbe45f20d3c9f362")
+                .unwrap(),
+        );
+        assert_eq!(
+            hex::encode(polyval.finalize()),
+            "f7a3b47b846119fae5b7866cf5e5b77e"
+        );
+    }
+
+    /// RFC 8452 Appendix C.1, AEAD_AES_128_GCM_SIV
+    #[test]
+    fn rfc8452_aes_128() {
+        let key = "01000000000000000000000000000000";
+        let nonce = "030000000000000000000000";
+        check(&[
+            (key, nonce, "", "", "dc20e2d83f25705bb49e439eca56de25"),
+            (
+                key,
+                nonce,
+                "",
+                "0100000000000000",
+                "b5d839330ac7b786578782fff6013b815b287c22493a364c",
+            ),
+            (
+                key,
+                nonce,
+                "",
+                "010000000000000000000000",
+                "7323ea61d05932260047d942a4978db357391a0bc4fdec8b0d106639",
+            ),
+            (
+                key,
+                nonce,
+                "",
+                "0100000000000000000000000000000002000000000000000000000000000000",
+                concat!(
+                    "84e07e62ba83a6585417245d7ec413a9fe427d6315c09b57ce45f2e3936a9445",
+                    "1a8e45dcd4578c667cd86847bf6155ff"
+                ),
+            ),
+            (
+                key,
+                nonce,
+                "01",
+                "0200000000000000",
+                "1e6daba35669f4273b0a1a2560969cdf790d99759abd1508",
+            ),
+            (
+                key,
+                nonce,
+                "010000000000000000000000",
+                "0200000000000000000000000000000003000000000000000000000000000000",
+                concat!(
+                    "605cb439c366cef0525ee9032cdb81b0366ac7435bfd6dfe4892a59fbca85d6a",
+                    "2421355f1398a00afdb9396b73604ed2"
+                ),
+            ),
+            (
+                "ee8e1ed9ff2540ae8f2ba9f50bc2f27c",
+                "752abad3e0afb5f434dc4310",
+                "6578616d706c65",
+                "48656c6c6f20776f726c64",
+                "5d349ead175ef6b1def6fd4fbcdeb7e4793f4a1d7e4faa70100af1",
+            ),
+        ]);
+    }
+
+    /// RFC 8452 Appendix C.2, AEAD_AES_256_GCM_SIV
+    #[test]
+    fn rfc8452_aes_256() {
+        let key = "0100000000000000000000000000000000000000000000000000000000000000";
+        let nonce = "030000000000000000000000";
+        check(&[
+            (key, nonce, "", "", "07f5f4169bbf55a8400cd47ea6fd400f"),
+            (
+                key,
+                nonce,
+                "",
+                "0100000000000000",
+                "c2ef328e5c71c83b843122130f7364b761e0b97427e3df28",
+            ),
+            (
+                key,
+                nonce,
+                "",
+                "010000000000000000000000",
+                "9aab2aeb3faa0a34aea8e2b18ca50da9ae6559e48fd10f6e5c9ca17e",
+            ),
+            (
+                key,
+                nonce,
+                "",
+                "0100000000000000000000000000000002000000000000000000000000000000",
+                concat!(
+                    "4a6a9db4c8c6549201b9edb53006cba821ec9cf850948a7c86c68ac7539d027f",
+                    "e819e63abcd020b006a976397632eb5d"
+                ),
+            ),
+            (
+                key,
+                nonce,
+                "01",
+                "0200000000000000",
+                "1de22967237a813291213f267e3b452f02d01ae33e4ec854",
+            ),
+            (
+                key,
+                nonce,
+                "010000000000000000000000",
+                "0200000000000000000000000000000003000000000000000000000000000000",
+                concat!(
+                    "41a7dbdd7896cc25ed7ba00d7ece4bd7073fb9f1cf77b7e074e628ae8e4e1179",
+                    "1e03b4e0ceb74affd60029e2dfa0d98e"
+                ),
+            ),
+        ]);
+    }
+}
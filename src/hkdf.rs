@@ -0,0 +1,99 @@
+use crate::digest::Digest;
+use crate::hmac::Hmac;
+use crate::secret::SecretBytes;
+
+/// HKDF-Extract (RFC 5869): concentrates the input key material into a pseudorandom
+/// key. An empty salt stands for a string of `D::OUTPUT_SIZE` zeros.
+pub fn extract<D: Digest>(salt: &[u8], ikm: &[u8]) -> SecretBytes {
+    SecretBytes::new(Hmac::<D>::mac(salt, ikm))
+}
+
+/// HKDF-Expand (RFC 5869): fills `output` with key material bound to `info`, which
+/// can be at most 255 hash values long
+pub fn expand<D: Digest>(prk: &[u8], info: &[u8], output: &mut [u8]) {
+    assert!(
+        output.len() <= 255 * D::OUTPUT_SIZE,
+        "HKDF output is limited to 255 hash values"
+    );
+    // The keyed HMAC state is computed once and cloned for every block
+    let keyed = Hmac::<D>::new(prk);
+    let mut previous = SecretBytes::zeroed(0);
+    for (i, block) in output.chunks_mut(D::OUTPUT_SIZE).enumerate() {
+        let mut hmac = keyed.clone();
+        hmac.update(&previous);
+        hmac.update(info);
+        hmac.update(&[i as u8 + 1]);
+        previous = SecretBytes::new(hmac.finalize());
+        block.copy_from_slice(&previous[..block.len()]);
+    }
+}
+
+/// Extract followed by expand, fills `output`
+pub fn hkdf<D: Digest>(salt: &[u8], ikm: &[u8], info: &[u8], output: &mut [u8]) {
+    expand::<D>(&extract::<D>(salt, ikm), info, output);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256;
+    use crate::test_util::unhex;
+
+    /// RFC 5869 Appendix A, test cases 1 to 3: the basic case, long inputs and
+    /// outputs, and an empty salt and info
+    #[test]
+    fn rfc5869_sha256() {
+        let cases = [
+            (
+                "0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+                "000102030405060708090a0b0c",
+                "f0f1f2f3f4f5f6f7f8f9",
+                "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5",
+                concat!(
+                    "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf",
+                    "34007208d5b887185865"
+                ),
+            ),
+            (
+                concat!(
+                    "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+                    "202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f",
+                    "404142434445464748494a4b4c4d4e4f"
+                ),
+                concat!(
+                    "606162636465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f",
+                    "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f",
+                    "a0a1a2a3a4a5a6a7a8a9aaabacadaeaf"
+                ),
+                concat!(
+                    "b0b1b2b3b4b5b6b7b8b9babbbcbdbebfc0c1c2c3c4c5c6c7c8c9cacbcccdcecf",
+                    "d0d1d2d3d4d5d6d7d8d9dadbdcdddedfe0e1e2e3e4e5e6e7e8e9eaebecedeeef",
+                    "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"
+                ),
+                "06a6b88c5853361a06104c9ceb35b45cef760014904671014a193f40c15fc244",
+                concat!(
+                    "b11e398dc80327a1c8e7f78c596a49344f012eda2d4efad8a050cc4c19afa97c",
+                    "59045a99cac7827271cb41c65e590e09da3275600c2f09b8367793a9aca3db71",
+                    "cc30c58179ec3e87c14c01d5c1f3434f1d87"
+                ),
+            ),
+            (
+                "0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b",
+                "",
+                "",
+                "19ef24a32c717b167f33a91d6f648bdf96596776afdb6377ac434c1c293ccb04",
+                concat!(
+                    "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d",
+                    "9d201395faa4b61a96c8"
+                ),
+            ),
+        ];
+        for (ikm, salt, info, prk, okm) in cases.iter() {
+            let (ikm, salt, info) = (unhex(ikm), unhex(salt), unhex(info));
+            assert_eq!(hex::encode(&*extract::<Sha256>(&salt, &ikm)), *prk);
+            let mut output = vec![0u8; okm.len() / 2];
+            hkdf::<Sha256>(&salt, &ikm, &info, &mut output);
+            assert_eq!(hex::encode(output), *okm);
+        }
+    }
+}
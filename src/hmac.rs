@@ -0,0 +1,69 @@
+use crate::digest::Digest;
+use crate::secret::SecretBytes;
+
+/// HMAC (RFC 2104) over any of the crate's hash functions
+#[derive(Clone)]
+pub struct Hmac<D: Digest> {
+    inner: D,
+    /// Outer hash already keyed, so no copy of the key is kept
+    outer: D,
+}
+
+impl<D: Digest> Hmac<D> {
+    /// Keys longer than the block size are hashed first
+    pub fn new(key: &[u8]) -> Hmac<D> {
+        let mut block_key = SecretBytes::zeroed(D::BLOCK_SIZE);
+        if key.len() > D::BLOCK_SIZE {
+            let hashed = SecretBytes::new(D::digest(key));
+            block_key[..hashed.len()].copy_from_slice(&hashed);
+        } else {
+            block_key[..key.len()].copy_from_slice(key);
+        }
+
+        let inner_key = SecretBytes::new(block_key.iter().map(|byte| byte ^ 0x36).collect());
+        let outer_key = SecretBytes::new(block_key.iter().map(|byte| byte ^ 0x5c).collect());
+        let mut inner = D::new();
+        inner.update(&inner_key);
+        let mut outer = D::new();
+        outer.update(&outer_key);
+        Hmac { inner, outer }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finalize());
+        outer.finalize()
+    }
+
+    /// Computes the MAC of `data` in one go
+    pub fn mac(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut hmac = Hmac::<D>::new(key);
+        hmac.update(data);
+        hmac.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256;
+
+    #[test]
+    fn hmac_sha256() {
+        // RFC 4231 test case 2, and a key longer than the block size
+        let mac = Hmac::<Sha256>::mac(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let mac = Hmac::<Sha256>::mac(&[b'k'; 100], b"msg");
+        assert_eq!(
+            hex::encode(mac),
+            "bd56a1782c2830e8abc6ed866a57a1230661e650b84c62f7ee3accc5fa5af491"
+        );
+    }
+}
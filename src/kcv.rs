@@ -0,0 +1,106 @@
+use crate::aes_ctr_optimized::{aes_v2, expand_key, Mode, BLOCK_SIZE};
+use crate::error::AesError;
+
+/// Length of a key check value in bytes, the customary 24 bits
+pub const KCV_SIZE: usize = 3;
+
+/// Key check value of an AES key, i.e. the encryption of the zero block truncated to
+/// `KCV_SIZE` bytes. The zero block is also the first counter block of CTR with an
+/// all-zero IV, whose keystream the value reveals the start of, so containers refuse
+/// such an IV.
+pub fn kcv(key: &[u8]) -> Result<[u8; KCV_SIZE], AesError> {
+    let (keys, nr) = expand_key(key)?;
+    let mut block = [0u8; BLOCK_SIZE];
+    aes_v2(&mut block, &keys, nr);
+    let mut kcv = [0u8; KCV_SIZE];
+    kcv.copy_from_slice(&block[..KCV_SIZE]);
+    Ok(kcv)
+}
+
+/// Key check values of the AES keys `mode` uses, XTS and SIV get one per half
+pub fn for_mode(key: &[u8], mode: Mode) -> Result<Vec<u8>, AesError> {
+    if mode.double_key() {
+        let (first, second) = key.split_at(key.len() / 2);
+        Ok([kcv(first)?, kcv(second)?].concat())
+    } else {
+        Ok(kcv(key)?.to_vec())
+    }
+}
+
+/// Hex string of key check values, the ones of double keys separated by a colon
+pub fn to_hex(kcv: &[u8]) -> String {
+    kcv.chunks(KCV_SIZE)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ctr_optimized::{
+        handle_aes_ctr_command, CipherOptions, CounterEndianness, CounterLayout,
+    };
+    use std::fs;
+
+    /// The encryption of the zero block under the all-zero AES-128 and AES-256 keys
+    /// and under `00 01 ... 1f`, truncated
+    #[test]
+    fn zero_block_encryption() {
+        assert_eq!(kcv(&[0; 16]).unwrap(), [0x66, 0xe9, 0x4b]);
+        assert_eq!(kcv(&[0; 32]).unwrap(), [0xdc, 0x95, 0xc0]);
+        assert!(matches!(kcv(&[0; 20]), Err(AesError::InvalidKeyLength(20))));
+
+        let mut key = vec![0u8; 32];
+        key.extend(0..32);
+        assert_eq!(to_hex(&for_mode(&key, Mode::Xts).unwrap()), "dc95c0:f29000");
+        assert_eq!(to_hex(&for_mode(&key[32..], Mode::Ctr).unwrap()), "f29000");
+    }
+
+    /// With an all-zero IV the key check value is the start of the CTR keystream, so
+    /// such a container is refused before the output file is created
+    #[test]
+    fn zero_iv_container_is_refused() {
+        let dir = std::env::temp_dir();
+        let input_path = dir.join(format!("kcv-zero-iv-{}.in", std::process::id()));
+        let output_path = dir.join(format!("kcv-zero-iv-{}.out", std::process::id()));
+        fs::write(&input_path, [0u8; 16]).unwrap();
+        let key = [0x2b; 16];
+        let mut options = CipherOptions {
+            mode: Mode::Ctr,
+            sector_size: 0,
+            aad: None,
+            tag_size: 0,
+            counter_layout: CounterLayout::Full128,
+            counter_endianness: CounterEndianness::Big,
+            password: None,
+            passphrase: None,
+            raw: true,
+            envelope: false,
+            master: None,
+        };
+        let encrypt = |options: &CipherOptions| {
+            handle_aes_ctr_command(
+                "encrypt".to_string(),
+                options.clone(),
+                128,
+                &key,
+                vec![0; 16],
+                input_path.clone(),
+                output_path.clone(),
+            )
+        };
+
+        encrypt(&options).unwrap();
+        assert_eq!(
+            fs::read(&output_path).unwrap()[..KCV_SIZE],
+            kcv(&key).unwrap()
+        );
+        fs::remove_file(&output_path).unwrap();
+
+        options.raw = false;
+        assert!(matches!(encrypt(&options), Err(AesError::ZeroIv)));
+        assert!(!output_path.exists());
+        fs::remove_file(&input_path).unwrap();
+    }
+}
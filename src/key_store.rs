@@ -0,0 +1,353 @@
+use crate::aes_ctr_optimized::replace_atomically;
+use crate::error::AesError;
+use crate::kcv::{self, KCV_SIZE};
+use crate::kw;
+use crate::passphrase::{Kdf, SALT_SIZE};
+use crate::random;
+use crate::secret::{self, SecretBytes};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Magic at the start of key store files
+pub const MAGIC: &[u8; 7] = b"AESKEYS";
+/// Version of the key store format written by this tool
+pub const VERSION: u8 = 1;
+/// Size of the key encryption key derived from the passphrase
+const KEK_SIZE: usize = 32;
+
+/// Named keys in a file, each wrapped with AES-KWP under a key encryption key that
+/// is derived from a passphrase. The names are stored in the clear so the file can
+/// be listed, and once more inside the wrapped key, which binds each key to its name.
+///
+/// The file holds the magic, the version, the KDF with its parameters and salt, the
+/// key check value of the key encryption key and the entries, each as the length of
+/// the name (u8), the name, the length of the wrapped key (u16) and the wrapped key.
+/// All integers are big-endian.
+pub struct KeyStore {
+    kdf: Kdf,
+    salt: [u8; SALT_SIZE],
+    kek: SecretBytes,
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl KeyStore {
+    /// An empty key store, the key encryption key is derived with a fresh salt
+    pub fn create(passphrase: &[u8], kdf: Kdf) -> Result<KeyStore, AesError> {
+        let mut salt = [0u8; SALT_SIZE];
+        random::fill(&mut salt)?;
+        Ok(KeyStore {
+            kdf,
+            salt,
+            kek: derive_kek(kdf, passphrase, &salt),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Reads the key store and derives the key encryption key, a wrong passphrase
+    /// fails the key check value
+    pub fn open(path: &Path, passphrase: &[u8]) -> Result<KeyStore, AesError> {
+        let bytes = fs::read(path)?;
+        let mut reader = Reader(&bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(AesError::InvalidKeyStore);
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(AesError::InvalidKeyStore);
+        }
+        let kdf_id = reader.take(1)?[0];
+        let mut params = [0u32; 3];
+        for param in params.iter_mut() {
+            *param = reader.u32()?;
+        }
+        let kdf = Kdf::decode(kdf_id, params).ok_or(AesError::InvalidKeyStore)?;
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(reader.take(SALT_SIZE)?);
+        let kek_kcv = reader.take(KCV_SIZE)?;
+
+        let mut entries = Vec::new();
+        for _ in 0..reader.u16()? {
+            let name_len = reader.take(1)?[0] as usize;
+            let name = String::from_utf8(reader.take(name_len)?.to_vec())
+                .map_err(|_| AesError::InvalidKeyStore)?;
+            let wrapped_len = reader.u16()? as usize;
+            entries.push((name, reader.take(wrapped_len)?.to_vec()));
+        }
+        if !reader.0.is_empty() {
+            return Err(AesError::InvalidKeyStore);
+        }
+
+        let kek = derive_kek(kdf, passphrase, &salt);
+        if kcv::kcv(&kek)?[..] != *kek_kcv {
+            return Err(AesError::KeyCheckFailed);
+        }
+        Ok(KeyStore {
+            kdf,
+            salt,
+            kek,
+            entries,
+        })
+    }
+
+    /// Writes the key store, replacing the file only once it is complete
+    pub fn save(&self, path: &Path) -> Result<(), AesError> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        let (kdf_id, params) = self.kdf.encode();
+        bytes.push(kdf_id);
+        for param in params.iter() {
+            bytes.extend_from_slice(&param.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&kcv::kcv(&self.kek)?);
+        bytes.extend_from_slice(&(self.entries.len() as u16).to_be_bytes());
+        for (name, wrapped_key) in self.entries.iter() {
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(wrapped_key.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(wrapped_key);
+        }
+        replace_atomically(path, |mut file| {
+            secret::restrict_permissions(&file)?;
+            file.write_all(&bytes)?;
+            Ok(())
+        })
+    }
+
+    /// Names of the stored keys in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Adds a key under a new name, see `is_valid_name`
+    pub fn add(&mut self, name: &str, key: &[u8]) -> Result<(), AesError> {
+        if !is_valid_name(name) {
+            return Err(AesError::InvalidKeyName(name.to_string()));
+        }
+        if self.entries.len() >= u16::MAX as usize {
+            return Err(AesError::InvalidKeyStore);
+        }
+        if self.names().any(|existing| existing == name) {
+            return Err(AesError::DuplicateKeyName(name.to_string()));
+        }
+        let mut named_key = SecretBytes::zeroed(1 + name.len() + key.len());
+        named_key[0] = name.len() as u8;
+        named_key[1..1 + name.len()].copy_from_slice(name.as_bytes());
+        named_key[1 + name.len()..].copy_from_slice(key);
+        let wrapped_key = kw::wrap_with_padding(&self.kek, &named_key)?;
+        self.entries.push((name.to_string(), wrapped_key));
+        Ok(())
+    }
+
+    /// Unwraps the key stored under `name`
+    pub fn get(&self, name: &str) -> Result<SecretBytes, AesError> {
+        let (_, wrapped_key) = self
+            .entries
+            .iter()
+            .find(|(existing, _)| existing == name)
+            .ok_or_else(|| AesError::UnknownKeyName(name.to_string()))?;
+        let named_key = kw::unwrap_with_padding(&self.kek, wrapped_key)?;
+        // The name inside the wrapped key has to match the one in the clear
+        let name_len = named_key[0] as usize;
+        if named_key.len() <= 1 + name_len || named_key[1..1 + name_len] != *name.as_bytes() {
+            return Err(AesError::IntegrityCheckFailed);
+        }
+        Ok(SecretBytes::new(named_key[1 + name_len..].to_vec()))
+    }
+
+    /// Removes the key stored under `name`
+    pub fn remove(&mut self, name: &str) -> Result<(), AesError> {
+        let count = self.entries.len();
+        self.entries.retain(|(existing, _)| existing != name);
+        if self.entries.len() == count {
+            return Err(AesError::UnknownKeyName(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `name` can name a key: 1 to 255 bytes, and not only hex digits, so that
+/// `-k` can tell names and hex keys apart
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= u8::MAX as usize
+        && !name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// Writes `key` as hex string to a file only its owner can read, e.g. for `--key-file`
+pub fn export(key: &[u8], path: &Path) -> Result<(), AesError> {
+    let mut file = secret::create_private_file(path)?;
+    let hex = SecretBytes::new(hex::encode(key).into_bytes());
+    file.write_all(&hex)?;
+    file.write_all(b"\n")?;
+    Ok(())
+}
+
+fn derive_kek(kdf: Kdf, passphrase: &[u8], salt: &[u8]) -> SecretBytes {
+    let mut kek = SecretBytes::zeroed(KEK_SIZE);
+    kdf.derive(passphrase, salt, &mut kek);
+    kek
+}
+
+/// Reads the fields of a key store file one after the other
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], AesError> {
+        if self.0.len() < len {
+            return Err(AesError::InvalidKeyStore);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn u16(&mut self) -> Result<u16, AesError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, AesError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single PBKDF2 iteration keeps the tests fast, the format is the same
+    const KDF: Kdf = Kdf::Pbkdf2 { iterations: 1 };
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("key-store-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn save_and_open() {
+        let path = temp_path("save");
+        let mut store = KeyStore::create(b"passphrase", KDF).unwrap();
+        store.add("backup", &[1; 16]).unwrap();
+        store.add("archive-2024", &[2; 32]).unwrap();
+        store.save(&path).unwrap();
+
+        let store = KeyStore::open(&path, b"passphrase").unwrap();
+        assert_eq!(
+            store.names().collect::<Vec<_>>(),
+            ["backup", "archive-2024"]
+        );
+        assert_eq!(*store.get("backup").unwrap(), [1; 16]);
+        assert_eq!(*store.get("archive-2024").unwrap(), [2; 32]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let result = KeyStore::open(&path, b"wrong passphrase");
+        assert!(matches!(result, Err(AesError::KeyCheckFailed)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Magic, version, KDF, salt and key check value, followed by the entries with
+    /// the name in the clear and the wrapped `name length || name || key`
+    #[test]
+    fn file_format() {
+        let path = temp_path("format");
+        let mut store = KeyStore::create(b"passphrase", KDF).unwrap();
+        store.add("backup", &[1; 16]).unwrap();
+        store.save(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut reader = Reader(&bytes);
+        assert_eq!(reader.take(MAGIC.len()).unwrap(), MAGIC);
+        assert_eq!(reader.take(1).unwrap(), [VERSION]);
+        let (kdf_id, params) = KDF.encode();
+        assert_eq!(reader.take(1).unwrap(), [kdf_id]);
+        for param in params.iter() {
+            assert_eq!(reader.u32().unwrap(), *param);
+        }
+        assert_eq!(reader.take(SALT_SIZE).unwrap(), store.salt);
+        assert_eq!(
+            reader.take(KCV_SIZE).unwrap(),
+            kcv::kcv(&store.kek).unwrap()
+        );
+        assert_eq!(reader.u16().unwrap(), 1);
+        assert_eq!(reader.take(1).unwrap(), [6]);
+        assert_eq!(reader.take(6).unwrap(), b"backup");
+        // 23 bytes padded to 24, plus the integrity check semiblock
+        assert_eq!(reader.u16().unwrap(), 32);
+        let named_key = kw::unwrap_with_padding(&store.kek, reader.take(32).unwrap()).unwrap();
+        assert_eq!(*named_key, [&[6][..], b"backup", &[1; 16]].concat()[..]);
+        assert!(reader.0.is_empty());
+
+        for len in [0, MAGIC.len(), bytes.len() - 1].iter() {
+            fs::write(&path, &bytes[..*len]).unwrap();
+            let result = KeyStore::open(&path, b"passphrase");
+            assert!(matches!(result, Err(AesError::InvalidKeyStore)));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("backup"));
+        assert!(is_valid_name(&"k".repeat(255)));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(&"k".repeat(256)));
+        // Only hex digits would be taken for a key by -k
+        assert!(!is_valid_name("deadbeef"));
+        assert!(!is_valid_name("00112233445566778899AABBCCDDEEFF"));
+
+        let mut store = KeyStore::create(b"passphrase", KDF).unwrap();
+        let result = store.add("cafe", &[1; 16]);
+        assert!(matches!(result, Err(AesError::InvalidKeyName(_))));
+        store.add("backup", &[1; 16]).unwrap();
+        let result = store.add("backup", &[2; 16]);
+        assert!(matches!(result, Err(AesError::DuplicateKeyName(_))));
+        assert!(matches!(
+            store.get("other"),
+            Err(AesError::UnknownKeyName(_))
+        ));
+        assert!(matches!(
+            store.remove("other"),
+            Err(AesError::UnknownKeyName(_))
+        ));
+        store.remove("backup").unwrap();
+        assert_eq!(store.names().count(), 0);
+    }
+
+    /// Swapping the wrapped keys of two entries in the file is detected by the name
+    /// inside the wrapped key
+    #[test]
+    fn binds_keys_to_names() {
+        let mut store = KeyStore::create(b"passphrase", KDF).unwrap();
+        store.add("first", &[1; 16]).unwrap();
+        store.add("second", &[2; 16]).unwrap();
+        let first = store.entries[0].1.clone();
+        store.entries[0].1 = store.entries[1].1.clone();
+        store.entries[1].1 = first;
+        let result = store.get("first");
+        assert!(matches!(result, Err(AesError::IntegrityCheckFailed)));
+    }
+
+    #[test]
+    fn export_writes_private_hex_file() {
+        let path = temp_path("export");
+        export(&[0xab; 16], &path).unwrap();
+        let expected = format!("{}\n", "ab".repeat(16));
+        assert_eq!(fs::read_to_string(&path).unwrap(), expected);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
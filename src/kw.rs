@@ -0,0 +1,262 @@
+use crate::aes_ctr_optimized::{aes_v2, ct_eq, expand_key, inv_aes_v2, BLOCK_SIZE};
+use crate::error::AesError;
+use crate::secret::{self, SecretBytes};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Size of a semiblock, the unit key wrap works on
+pub const SEMIBLOCK_SIZE: usize = 8;
+/// Default initial value of RFC 3394
+const DEFAULT_IV: [u8; SEMIBLOCK_SIZE] = [0xa6; SEMIBLOCK_SIZE];
+/// First half of the alternative initial value of RFC 5649, the second half is
+/// the message length
+const ALTERNATIVE_IV: [u8; 4] = [0xa6, 0x59, 0x59, 0xa6];
+
+/// Wraps `key` under the key encryption key `kek` (AES-KW, RFC 3394). The key has
+/// to be a multiple of 8 bytes and at least 16 bytes long.
+pub fn wrap(kek: &[u8], key: &[u8]) -> Result<Vec<u8>, AesError> {
+    if key.len() < 2 * SEMIBLOCK_SIZE || !key.len().is_multiple_of(SEMIBLOCK_SIZE) {
+        return Err(AesError::InvalidLength(key.len() as u64));
+    }
+    let (keys, nr) = expand_key(kek)?;
+    Ok(wrap_semiblocks(&keys, nr, DEFAULT_IV, key))
+}
+
+/// Unwraps a key wrapped with `wrap`
+pub fn unwrap(kek: &[u8], wrapped: &[u8]) -> Result<SecretBytes, AesError> {
+    if wrapped.len() < 3 * SEMIBLOCK_SIZE || !wrapped.len().is_multiple_of(SEMIBLOCK_SIZE) {
+        return Err(AesError::InvalidLength(wrapped.len() as u64));
+    }
+    let (keys, nr) = expand_key(kek)?;
+    let (a, key) = unwrap_semiblocks(&keys, nr, wrapped);
+    let key = SecretBytes::new(key);
+    if !ct_eq(&a, &DEFAULT_IV) {
+        return Err(AesError::IntegrityCheckFailed);
+    }
+    Ok(key)
+}
+
+/// Wraps a key of any length from 1 byte to 4 GiB under `kek` (AES-KWP, RFC 5649)
+pub fn wrap_with_padding(kek: &[u8], key: &[u8]) -> Result<Vec<u8>, AesError> {
+    if key.is_empty() || key.len() as u64 > u32::MAX as u64 {
+        return Err(AesError::InvalidLength(key.len() as u64));
+    }
+    let (keys, nr) = expand_key(kek)?;
+    let mut aiv = [0u8; SEMIBLOCK_SIZE];
+    aiv[..4].copy_from_slice(&ALTERNATIVE_IV);
+    aiv[4..].copy_from_slice(&(key.len() as u32).to_be_bytes());
+    let padded_len = key.len().div_ceil(SEMIBLOCK_SIZE) * SEMIBLOCK_SIZE;
+    let mut padded = SecretBytes::zeroed(padded_len);
+    padded[..key.len()].copy_from_slice(key);
+
+    if padded_len == SEMIBLOCK_SIZE {
+        // A single semiblock is encrypted together with the AIV as one AES block
+        let mut block = [0u8; BLOCK_SIZE];
+        block[..SEMIBLOCK_SIZE].copy_from_slice(&aiv);
+        block[SEMIBLOCK_SIZE..].copy_from_slice(&padded);
+        aes_v2(&mut block, &keys, nr);
+        return Ok(block.to_vec());
+    }
+    Ok(wrap_semiblocks(&keys, nr, aiv, &padded))
+}
+
+/// Unwraps a key wrapped with `wrap_with_padding`
+pub fn unwrap_with_padding(kek: &[u8], wrapped: &[u8]) -> Result<SecretBytes, AesError> {
+    if wrapped.len() < 2 * SEMIBLOCK_SIZE || !wrapped.len().is_multiple_of(SEMIBLOCK_SIZE) {
+        return Err(AesError::InvalidLength(wrapped.len() as u64));
+    }
+    let (keys, nr) = expand_key(kek)?;
+    let (a, padded) = if wrapped.len() == BLOCK_SIZE {
+        let mut block = [0u8; BLOCK_SIZE];
+        block.copy_from_slice(wrapped);
+        inv_aes_v2(&mut block, &keys, nr);
+        let mut a = [0u8; SEMIBLOCK_SIZE];
+        a.copy_from_slice(&block[..SEMIBLOCK_SIZE]);
+        let padded = block[SEMIBLOCK_SIZE..].to_vec();
+        secret::wipe(&mut block);
+        (a, padded)
+    } else {
+        unwrap_semiblocks(&keys, nr, wrapped)
+    };
+    let mut padded = SecretBytes::new(padded);
+
+    // The AIV has to match, the length has to fall into the last semiblock and the
+    // padding has to be zero
+    let len = u32::from_be_bytes([a[4], a[5], a[6], a[7]]) as usize;
+    let valid_len = len + SEMIBLOCK_SIZE > padded.len() && len <= padded.len();
+    let padding = if valid_len { &padded[len..] } else { &[][..] };
+    let zero_padding = padding.iter().fold(0u8, |acc, byte| acc | byte) == 0;
+    if !ct_eq(&a[..4], &ALTERNATIVE_IV) || !valid_len || !zero_padding {
+        return Err(AesError::IntegrityCheckFailed);
+    }
+    padded.truncate(len);
+    Ok(padded)
+}
+
+/// The wrapping process W of RFC 3394 in its index based form, returns `A || R`
+fn wrap_semiblocks(keys: &[u8], nr: usize, iv: [u8; SEMIBLOCK_SIZE], plain: &[u8]) -> Vec<u8> {
+    let n = plain.len() / SEMIBLOCK_SIZE;
+    let mut a = iv;
+    let mut r = plain.to_vec();
+    let mut block = [0u8; BLOCK_SIZE];
+    for j in 0..6 {
+        for (i, semiblock) in r.chunks_exact_mut(SEMIBLOCK_SIZE).enumerate() {
+            block[..SEMIBLOCK_SIZE].copy_from_slice(&a);
+            block[SEMIBLOCK_SIZE..].copy_from_slice(semiblock);
+            aes_v2(&mut block, keys, nr);
+            let t = (n * j + i + 1) as u64;
+            a.copy_from_slice(&block[..SEMIBLOCK_SIZE]);
+            xor_counter(&mut a, t);
+            semiblock.copy_from_slice(&block[SEMIBLOCK_SIZE..]);
+        }
+    }
+    let mut wrapped = a.to_vec();
+    wrapped.extend_from_slice(&r);
+    wrapped
+}
+
+/// The unwrapping process W^-1 of RFC 3394, returns `A` and the plain semiblocks
+fn unwrap_semiblocks(keys: &[u8], nr: usize, wrapped: &[u8]) -> ([u8; SEMIBLOCK_SIZE], Vec<u8>) {
+    let n = wrapped.len() / SEMIBLOCK_SIZE - 1;
+    let mut a = [0u8; SEMIBLOCK_SIZE];
+    a.copy_from_slice(&wrapped[..SEMIBLOCK_SIZE]);
+    let mut r = wrapped[SEMIBLOCK_SIZE..].to_vec();
+    let mut block = [0u8; BLOCK_SIZE];
+    for j in (0..6).rev() {
+        for (i, semiblock) in r.chunks_exact_mut(SEMIBLOCK_SIZE).enumerate().rev() {
+            let t = (n * j + i + 1) as u64;
+            xor_counter(&mut a, t);
+            block[..SEMIBLOCK_SIZE].copy_from_slice(&a);
+            block[SEMIBLOCK_SIZE..].copy_from_slice(semiblock);
+            inv_aes_v2(&mut block, keys, nr);
+            a.copy_from_slice(&block[..SEMIBLOCK_SIZE]);
+            semiblock.copy_from_slice(&block[SEMIBLOCK_SIZE..]);
+        }
+    }
+    secret::wipe(&mut block);
+    (a, r)
+}
+
+#[inline]
+fn xor_counter(a: &mut [u8; SEMIBLOCK_SIZE], t: u64) {
+    for (byte, c) in a.iter_mut().zip(t.to_be_bytes().iter()) {
+        *byte ^= c;
+    }
+}
+
+/// Wraps or unwraps the key stored in the input file and writes the result to the
+/// output file. Keys are small, so both files are handled in memory.
+pub fn process_file(
+    kek: &[u8],
+    wrap_key: bool,
+    padding: bool,
+    input_file_path: &Path,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    // Either the input or the output is a plain key
+    let input = SecretBytes::new(fs::read(input_file_path)?);
+    match (wrap_key, padding) {
+        (true, false) => fs::write(output_file_path, wrap(kek, &input)?)?,
+        (false, false) => write_key(output_file_path, &unwrap(kek, &input)?)?,
+        (true, true) => fs::write(output_file_path, wrap_with_padding(kek, &input)?)?,
+        (false, true) => write_key(output_file_path, &unwrap_with_padding(kek, &input)?)?,
+    }
+    Ok(())
+}
+
+/// Writes an unwrapped key to a file only its owner can read, so that `--key-file`
+/// accepts it
+fn write_key(path: &Path, key: &[u8]) -> Result<(), AesError> {
+    secret::create_private_file(path)?.write_all(key)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    /// RFC 3394 section 4, as `(KEK length, key data, ciphertext)` with the KEK
+    /// 000102... and the key data 00112233...
+    #[test]
+    fn rfc3394_vectors() {
+        let vectors = [
+            (16, 16, "1FA68B0A8112B447AEF34BD8FB5A7B829D3E862371D2CFE5"),
+            (24, 16, "96778B25AE6CA435F92B5B97C050AED2468AB8A17AD84E5D"),
+            (32, 16, "64E8C3F9CE0F5BA263E9777905818A2A93C8191E7D6E8AE7"),
+            (
+                24,
+                24,
+                "031D33264E15D33268F24EC260743EDCE1C6C7DDEE725A936BA814915C6762D2",
+            ),
+            (
+                32,
+                24,
+                "A8F9BC1612C68B3FF6E6F4FBE30E71E4769C8B80A32CB8958CD5D17D6B254DA1",
+            ),
+            (
+                32,
+                32,
+                concat!(
+                    "28C9F404C4B810F4CBCCB35CFB87F8263F5786E2D80ED326",
+                    "CBC7F0E71A99F43BFB988B9B7A02DD21"
+                ),
+            ),
+        ];
+        let key_data = unhex(concat!(
+            "00112233445566778899AABBCCDDEEFF",
+            "000102030405060708090A0B0C0D0E0F"
+        ));
+        for (kek_len, key_len, ciphertext) in vectors.iter() {
+            let kek: Vec<u8> = (0..*kek_len as u8).collect();
+            let key = &key_data[..*key_len];
+            let wrapped = wrap(&kek, key).unwrap();
+            assert_eq!(hex::encode_upper(&wrapped), *ciphertext);
+            assert_eq!(&*unwrap(&kek, &wrapped).unwrap(), key);
+        }
+    }
+
+    /// RFC 5649 section 6, a 20 and a 7 byte key under a 192 bit KEK
+    #[test]
+    fn rfc5649_vectors() {
+        let kek = unhex("5840df6e29b02af1ab493b705bf16ea1ae8338f4dcc176a8");
+        let vectors = [
+            (
+                "c37b7e6492584340bed12207808941155068f738",
+                "138bdeaa9b8fa7fc61f97742e72248ee5ae6ae5360d1ae6a5f54f373fa543b6a",
+            ),
+            ("466f7250617369", "afbeb0f07dfbf5419200f2ccb50bb24f"),
+        ];
+        for (key, ciphertext) in vectors.iter() {
+            let key = unhex(key);
+            let wrapped = wrap_with_padding(&kek, &key).unwrap();
+            assert_eq!(hex::encode(&wrapped), *ciphertext);
+            assert_eq!(&*unwrap_with_padding(&kek, &wrapped).unwrap(), &key[..]);
+        }
+    }
+
+    /// Every flipped bit of a wrapped key fails the integrity check, not the length
+    /// or key checks
+    #[test]
+    fn flipped_bit_fails_integrity_check() {
+        let kek = [0x42; 16];
+        let key = [0x17; 24];
+        let mut wrapped = wrap(&kek, &key).unwrap();
+        for bit in 0..wrapped.len() * 8 {
+            wrapped[bit / 8] ^= 1 << (bit % 8);
+            let result = unwrap(&kek, &wrapped);
+            assert!(matches!(result, Err(AesError::IntegrityCheckFailed)));
+            wrapped[bit / 8] ^= 1 << (bit % 8);
+        }
+        for &key_len in [7usize, 20].iter() {
+            let mut wrapped = wrap_with_padding(&kek, &key[..key_len]).unwrap();
+            for bit in 0..wrapped.len() * 8 {
+                wrapped[bit / 8] ^= 1 << (bit % 8);
+                let result = unwrap_with_padding(&kek, &wrapped);
+                assert!(matches!(result, Err(AesError::IntegrityCheckFailed)));
+                wrapped[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! File encryption with AES in various modes of operation, compatible with `openssl enc`
+pub mod aes_ctr_optimized;
+pub mod argon2;
+pub mod blake2b;
+pub mod cbc;
+pub mod ccm;
+pub mod cfb;
+pub mod cmac;
+pub mod container;
+pub mod digest;
+pub mod eax;
+pub mod envelope;
+pub mod error;
+pub mod gcm_siv;
+pub mod hkdf;
+pub mod hmac;
+pub mod kcv;
+pub mod key_store;
+pub mod kw;
+pub mod log;
+pub mod master_key;
+pub mod md5;
+pub mod ocb;
+pub mod ofb;
+pub mod openssl;
+pub mod passphrase;
+pub mod pbkdf2;
+pub mod random;
+pub mod rekey;
+pub mod scrypt;
+pub mod secret;
+pub mod sha256;
+pub mod siv;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod thread_pool;
+pub mod xts;
@@ -0,0 +1,82 @@
+use crate::cmac::Cmac;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How much the tool prints, every level includes the ones before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Errors only
+    Error,
+    /// Errors and warnings, set by `--quiet`
+    Warning,
+    /// Progress messages, the default
+    Info,
+    /// Settings of the operation, set by `--verbose`. Keys only ever show up as
+    /// their fingerprint.
+    Verbose,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Sets the level for the whole process, usually once from the command line
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether messages of `level` are printed
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Identifies a key in logs without revealing it: the first 8 bytes of the CMAC of
+/// the zero block. Double-length keys of XTS and SIV that are longer than any AES
+/// key get one fingerprint per half.
+pub fn fingerprint(key: &[u8]) -> String {
+    match Cmac::new(key) {
+        Ok(cmac) => hex::encode(&cmac.mac(&[0u8; 16])[..8]),
+        Err(_) if key.len() == 48 || key.len() == 64 => {
+            let (first, second) = key.split_at(key.len() / 2);
+            format!("{}:{}", fingerprint(first), fingerprint(second))
+        }
+        Err(_) => String::from("(invalid key)"),
+    }
+}
+
+/// Prints an error to stderr, errors are never suppressed
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        eprintln!($($arg)*)
+    };
+}
+
+/// Prints a warning to stderr, `--quiet` keeps warnings and only drops progress
+/// messages
+#[macro_export]
+macro_rules! log_warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warning) {
+            eprintln!($($arg)*)
+        }
+    };
+}
+
+/// Prints a progress message to stdout unless `--quiet` is given
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Prints details to stdout with `--verbose`. Never pass key material here, only
+/// its `fingerprint`.
+#[macro_export]
+macro_rules! log_verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Verbose) {
+            println!($($arg)*)
+        }
+    };
+}
@@ -1,48 +1,237 @@
 use hex::FromHex;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
 use std::process;
 use std::time::Instant;
 use structopt::StructOpt;
 
-mod aes_ctr_optimized;
-mod thread_pool;
+use aes_ctr::aes_ctr_optimized::{self, CipherOptions, CounterEndianness, CounterLayout, Mode};
+use aes_ctr::container::Header;
+use aes_ctr::envelope;
+use aes_ctr::kcv;
+use aes_ctr::key_store::{self, KeyStore};
+use aes_ctr::log::{self, Level};
+use aes_ctr::master_key;
+use aes_ctr::openssl::{self, KeyDerivation, MessageDigest, PasswordOptions};
+use aes_ctr::passphrase::{Kdf, PassphraseOptions};
+use aes_ctr::random;
+use aes_ctr::rekey;
+use aes_ctr::secret::SecretBytes;
+use aes_ctr::{log_error, log_info, log_verbose, log_warning};
+
+/// IVs of the README, the Makefile and NIST SP 800-38A, which tend to be copied
+/// into real use
+const EXAMPLE_IVS: [&str; 3] = [
+    "00112233445566778899aabbccddeeff",
+    "000102030405060708090a0b0c0d0e0f",
+    "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+];
 
 /// Command line arguments struct
 #[derive(StructOpt)]
 #[structopt(
     name = "aes-ctr",
-    about = "File encryption tool using AES in CTR mode."
+    about = "File encryption tool using AES in CTR, CBC, XTS, CFB, OFB, GCM-SIV, SIV, CCM, OCB or EAX mode."
 )]
 struct Cli {
     #[structopt(short = "c", long="command", required = true,
-                help = concat!("Encryption or decryption command\n",
-                               "(has to be 'encrypt' or 'decrypt')"))]
+                help = concat!("Encryption, decryption, key wrap, key check value, recipient, rekey or key store command\n",
+                               "(has to be 'encrypt', 'decrypt', 'wrap-key', 'unwrap-key', 'kcv',\n",
+                               "'add-recipient', 'remove-recipient', 'rekey', 'keys-add',\n",
+                               "'keys-list', 'keys-remove' or 'keys-export',\n",
+                               "'kcv' prints the key check value of the key and compares it\n",
+                               "with the one in the container header of the input file if given,\n",
+                               "the recipient commands edit the wrapped data keys of an envelope\n",
+                               "encrypted file without reencrypting the payload,\n",
+                               "'rekey' reencrypts a CTR or XTS file with the new key in one pass\n",
+                               "and replaces the output file only once it is complete,\n",
+                               "the key store commands add the given or a random key under a name,\n",
+                               "list the names, remove a key or write it as hex string to the output file)"))]
     command: String,
-    #[structopt(short = "k", long = "key", required = true,
+    #[structopt(short = "m", long = "mode",
+                help = concat!("Block cipher mode of operation\n",
+                               "(has to be 'ctr', 'cbc', 'xts', 'cfb', 'cfb8',\n",
+                               "'cfb1', 'ofb', 'gcm-siv', 'siv', 'ccm', 'ocb' or 'eax'\n",
+                               "for files and 'kw' or 'kwp' for key wrap,\n",
+                               "defaults to 'ctr' or 'kwp' respectively,\n",
+                               "decryption reads it from the container header)"))]
+    mode: Option<String>,
+    #[structopt(short = "k", long = "key",
                 help = concat!("Key for AES-CTR as hex string\n",
                                "(has to be of length 128-bit or 256-bit,\n",
-                               "i.e. exactly 32 or 64 hex characters)"))]
-    key: String,
-    #[structopt(short = "v", long = "initialization-vector", required = true,
+                               "i.e. exactly 32 or 64 hex characters,\n",
+                               "twice as long for XTS and SIV,\n",
+                               "key encryption key for key wrap,\n",
+                               "or the name of a key in the key store,\n",
+                               "either the key, a password or a passphrase is required)"))]
+    key: Option<String>,
+    #[structopt(parse(from_os_str), long = "key-file",
+                help = concat!("File holding the key as hex string or raw bytes\n",
+                               "(must not be readable by everyone)"))]
+    key_file: Option<std::path::PathBuf>,
+    #[structopt(
+        long = "allow-insecure-key-file",
+        help = "Accept a key file that is readable by everyone"
+    )]
+    allow_insecure_key_file: bool,
+    #[structopt(
+        long = "key-env",
+        help = "Environment variable holding the key as hex string"
+    )]
+    key_env: Option<String>,
+    #[structopt(long = "key-fd",
+                help = concat!("File descriptor to read the key from as hex string or raw bytes\n",
+                               "(e.g. 0 for standard input)"))]
+    key_fd: Option<i32>,
+    #[structopt(long = "pass",
+                help = concat!("Password to derive key and IV from like 'openssl enc -pass'\n",
+                               "(writes or expects the 'Salted__' header,\n",
+                               "only for 'ctr', 'cbc', 'cfb', 'cfb8', 'cfb1' and 'ofb')"))]
+    pass: Option<String>,
+    #[structopt(long = "pbkdf2",
+                help = concat!("Derive key and IV from the password with PBKDF2\n",
+                               "(like 'openssl enc -pbkdf2', otherwise the legacy\n",
+                               "EVP_BytesToKey derivation is used)"))]
+    pbkdf2: bool,
+    #[structopt(long = "iter",
+                help = concat!("Iteration count of PBKDF2, implies --pbkdf2\n",
+                               "(defaults to 10000 like openssl)"))]
+    iter: Option<u32>,
+    #[structopt(long = "md",
+                help = concat!("Digest of the password based key derivation\n",
+                               "(has to be 'sha256' or 'md5', defaults to 'sha256')"))]
+    md: Option<String>,
+    #[structopt(long = "salt",
+                help = concat!("Salt for password based encryption as hex string\n",
+                               "(has to be of length 64-bit, i.e. exactly 16 hex characters,\n",
+                               "a random salt is used by default)"))]
+    salt: Option<String>,
+    #[structopt(long = "passphrase",
+                help = concat!("Passphrase to derive key and IV from\n",
+                               "(the KDF, its parameters and a random salt\n",
+                               "are stored in the container header)"))]
+    passphrase: Option<String>,
+    #[structopt(parse(from_os_str), long = "passphrase-file",
+                help = concat!("File holding the passphrase\n",
+                               "(a trailing line break is ignored)"))]
+    passphrase_file: Option<std::path::PathBuf>,
+    #[structopt(long = "kdf",
+                help = concat!("Key derivation function for the passphrase\n",
+                               "(has to be 'argon2id', 'scrypt' or 'pbkdf2',\n",
+                               "defaults to 'argon2id', decryption reads it from the header)"))]
+    kdf: Option<String>,
+    #[structopt(long = "key-size",
+                help = concat!("Size of the key derived from the password or passphrase\n",
+                               "or of the data key for envelope encryption in bits\n",
+                               "(has to be 128, 192 or 256, defaults to 256,\n",
+                               "twice as long for XTS and SIV,\n",
+                               "read from the header when decrypting with a passphrase or envelope)"))]
+    key_size: Option<u16>,
+    #[structopt(long = "raw",
+                help = concat!("Write or read headerless output like 'openssl enc'\n",
+                               "(implied by --pass, otherwise encryption writes a container header\n",
+                               "with mode, key size, IV and the mode specific settings,\n",
+                               "which decryption uses instead of the command line)"))]
+    raw: bool,
+    #[structopt(long = "envelope",
+                help = concat!("Encrypt with a random data key wrapped under the given key\n",
+                               "(the key encryption key, which can be rotated by rewriting\n",
+                               "the container header without reencrypting the payload,\n",
+                               "decryption reads the wrapped data key from the header)"))]
+    envelope: bool,
+    #[structopt(long = "derive-from-master",
+                help = concat!("Encrypt with key and IV derived from the given master key\n",
+                               "(with HKDF-SHA256 from a random salt and the relative path of the\n",
+                               "input file, which are stored in the container header,\n",
+                               "so every file gets its own key, decryption reads them from the header)"))]
+    derive_from_master: bool,
+    #[structopt(long = "new-key",
+                help = concat!("Key of the recipient to add or key to reencrypt with as hex string\n",
+                               "(has to be of length 128-bit or 256-bit,\n",
+                               "twice as long for rekeying XTS without envelope encryption,\n",
+                               "the key given as usual is the current one)"))]
+    new_key: Option<String>,
+    #[structopt(
+        long = "new-key-file",
+        parse(from_os_str),
+        help = "File holding the key of the recipient to add or key to reencrypt with as hex string"
+    )]
+    new_key_file: Option<std::path::PathBuf>,
+    #[structopt(long = "recipient-kcv",
+                help = concat!("Key check value of the recipient to remove as hex string\n",
+                               "(as printed by the 'kcv' command, i.e. 6 hex characters)"))]
+    recipient_kcv: Option<String>,
+    #[structopt(long = "name",
+                help = concat!("Name of the key to add, remove or export with the key store commands\n",
+                               "(1 to 255 bytes, not only hex characters)"))]
+    name: Option<String>,
+    #[structopt(parse(from_os_str), long = "key-store",
+                help = concat!("Key store file with the named keys\n",
+                               "(defaults to $AES_CTR_KEY_STORE or ~/.aes-ctr-keys,\n",
+                               "'keys-add' creates it if missing)"))]
+    key_store: Option<std::path::PathBuf>,
+    #[structopt(parse(from_os_str), long = "key-store-passphrase-file",
+                help = concat!("File holding the passphrase of the key store\n",
+                               "(defaults to $AES_CTR_KEY_STORE_PASSPHRASE,\n",
+                               "a trailing line break is ignored)"))]
+    key_store_passphrase_file: Option<std::path::PathBuf>,
+    #[structopt(short = "v", long = "initialization-vector",
                 help = concat!("Initialization Vector (IV) for AES-CTR as hex string\n",
                                "(has to be of length 128-bit,\n",
-                               "i.e. exactly 32 hex characters)"))]
-    iv: String,
+                               "i.e. exactly 32 hex characters, 96-bit nonce\n",
+                               "for GCM-SIV, 56-bit to 104-bit nonce for CCM,\n",
+                               "8-bit to 120-bit nonce for OCB,\n",
+                               "8-bit to 128-bit nonce for EAX,\n",
+                               "not used for XTS and SIV,\n",
+                               "a random IV is generated for encryption if omitted,\n",
+                               "decryption reads it from the container header)"))]
+    iv: Option<String>,
+    #[structopt(short = "a", long = "aad",
+                help = concat!("Associated data as hex string\n",
+                               "(authenticated but not encrypted by GCM-SIV, SIV, CCM, OCB and EAX)"))]
+    aad: Option<String>,
+    #[structopt(long = "tag-length", default_value = "16",
+                help = concat!("Length of the CCM, OCB or EAX authentication tag in bytes\n",
+                               "(has to be from 1 to 16 for OCB and EAX,\n",
+                               "even and from 4 to 16 for CCM)"))]
+    tag_length: usize,
+    #[structopt(long = "counter-layout", default_value = "full128",
+                help = concat!("Layout of the CTR counter block\n",
+                               "(has to be 'full128', '64-64', '96-32' or '96-32-wrapping',\n",
+                               "i.e. a 128-bit counter or a 64-bit or 96-bit nonce\n",
+                               "followed by a 64-bit or 32-bit counter)"))]
+    counter_layout: String,
+    #[structopt(long = "counter-endianness", default_value = "big",
+                help = concat!("Byte order the CTR counter field is incremented in\n",
+                               "(has to be 'big' or 'little')"))]
+    counter_endianness: String,
+    #[structopt(long = "sector-size", default_value = "512",
+                help = concat!("Sector (data unit) size in bytes for XTS\n",
+                               "(has to be 512 or 4096)"))]
+    sector_size: usize,
     #[structopt(
         parse(from_os_str),
         short = "i",
         long = "input-file",
-        required = true,
         help = "Path to input file"
     )]
-    input_file_path: std::path::PathBuf,
+    input_file_path: Option<std::path::PathBuf>,
     #[structopt(
         parse(from_os_str),
         short = "o",
         long = "output-file",
-        required = true,
         help = "Path to output file"
     )]
-    output_file_path: std::path::PathBuf,
+    output_file_path: Option<std::path::PathBuf>,
+    #[structopt(long = "verbose", conflicts_with = "quiet",
+                help = concat!("Print the settings of the operation\n",
+                               "(keys are only shown as fingerprint, i.e. the start of their\n",
+                               "CMAC of the zero block)"))]
+    verbose: bool,
+    #[structopt(short = "q", long = "quiet", help = "Print only errors and warnings")]
+    quiet: bool,
 }
 
 /// Main function
@@ -50,104 +239,1266 @@ fn main() {
     // Initialize some variables
     let mut errors = 0;
     let mut key_size: u16 = 0;
-    let mut key_bytes: Vec<u8> = Vec::new();
+    let mut mode = Mode::Ctr;
+    let mut key_bytes = SecretBytes::zeroed(0);
     let mut iv_bytes: Vec<u8> = Vec::new();
+    let mut aad_bytes: Option<Vec<u8>> = None;
+    let mut counter_layout = CounterLayout::Full128;
+    let mut counter_endianness = CounterEndianness::Big;
+    let mut password: Option<PasswordOptions> = None;
+    let mut passphrase: Option<PassphraseOptions> = None;
 
     // Get command line arguments
-    let args = Cli::from_args();
+    let mut args = Cli::from_args();
+    log::set_level(if args.verbose {
+        Level::Verbose
+    } else if args.quiet {
+        Level::Warning
+    } else {
+        Level::Info
+    });
 
     // User message: checking and parsing command line arguments
-    println!("\n### Checking and parsing command line arguments ...");
+    log_info!("\n### Checking and parsing command line arguments ...");
 
     // Check command
-    if args.command != "encrypt" && args.command != "decrypt" {
-        eprintln!(
+    let key_wrap_command = args.command == "wrap-key" || args.command == "unwrap-key";
+    let kcv_command = args.command == "kcv";
+    let recipient_command = args.command == "add-recipient" || args.command == "remove-recipient";
+    let rekey_command = args.command == "rekey";
+    let keys_command = args.command.starts_with("keys-");
+    if args.command != "encrypt"
+        && args.command != "decrypt"
+        && !key_wrap_command
+        && !kcv_command
+        && !recipient_command
+        && !rekey_command
+        && args.command != "keys-add"
+        && args.command != "keys-list"
+        && args.command != "keys-remove"
+        && args.command != "keys-export"
+    {
+        log_error!(
             concat!(
                 "!!! ERROR: Command not correct!\n",
-                "!!!        (is '{}', but should be 'encrypt' or 'decrypt')"
+                "!!!        (is '{}', but should be 'encrypt', 'decrypt', 'wrap-key', 'unwrap-key', 'kcv', 'add-recipient', 'remove-recipient', 'rekey', 'keys-add', 'keys-list', 'keys-remove' or 'keys-export')"
             ),
             args.command
         );
         errors += 1;
     }
 
-    // Check and parse key
-    if args.key.len() == 32 {
-        key_size = 128;
-        match Vec::from_hex(args.key) {
-            Ok(bytes) => key_bytes = bytes,
+    // Check input and output file, the key check value needs neither and the key
+    // store commands only export to a file
+    if kcv_command {
+        if args.output_file_path.is_some() {
+            log_error!("!!! ERROR: Output file is not used by command 'kcv'!");
+            errors += 1;
+        }
+    } else if keys_command {
+        if args.input_file_path.is_some() {
+            log_error!(
+                "!!! ERROR: Input file is not used by command '{}'!",
+                args.command
+            );
+            errors += 1;
+        }
+        if args.command == "keys-export" && args.output_file_path.is_none() {
+            log_error!("!!! ERROR: Output file is required!");
+            errors += 1;
+        } else if args.command != "keys-export" && args.output_file_path.is_some() {
+            log_error!(
+                "!!! ERROR: Output file is not used by command '{}'!",
+                args.command
+            );
+            errors += 1;
+        }
+    } else {
+        if args.input_file_path.is_none() {
+            log_error!("!!! ERROR: Input file is required!");
+            errors += 1;
+        }
+        if args.output_file_path.is_none() {
+            log_error!("!!! ERROR: Output file is required!");
+            errors += 1;
+        }
+    }
+
+    // Read the container header, decryption takes mode, key size, IV and the mode
+    // specific settings from it
+    let raw = args.raw || args.pass.is_some();
+    let mut container: Option<Header> = None;
+    let container_expected = match args.input_file_path {
+        Some(_) if kcv_command || recipient_command || rekey_command => !raw,
+        Some(_) => args.command == "decrypt" && !raw,
+        None => false,
+    };
+    if let (true, Some(path)) = (container_expected, &args.input_file_path) {
+        match fs::File::open(path)
+            .map_err(From::from)
+            .and_then(|mut file| Header::read(&mut file))
+        {
+            Ok(header) => container = Some(header),
             Err(e) => {
-                eprintln!("!!! ERROR: Key hex string parsing failed: {}", e);
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Container header reading failed: {}\n",
+                        "!!!        (use --raw for input without header)"
+                    ),
+                    e
+                );
                 errors += 1;
             }
-        };
-    } else if args.key.len() == 64 {
-        key_size = 256;
-        match Vec::from_hex(args.key) {
-            Ok(bytes) => key_bytes = bytes,
-            Err(e) => {
-                eprintln!("!!! ERROR: Key hex string parsing failed: {}", e);
+        }
+    }
+
+    // Check and parse mode (key wrap commands default to KWP)
+    let mode_name = match (&args.mode, &container) {
+        (Some(name), _) => name.clone(),
+        (None, Some(header)) => String::from(header.mode.name()),
+        (None, None) if key_wrap_command => String::from("kwp"),
+        (None, None) => String::from("ctr"),
+    };
+    match Mode::from_name(&mode_name) {
+        Some(m) => {
+            mode = m;
+            if let Some(ref header) = container {
+                if m != header.mode {
+                    log_error!(
+                        concat!(
+                            "!!! ERROR: Mode does not match the input file!\n",
+                            "!!!        (is '{}', but the file was encrypted with '{}')"
+                        ),
+                        mode_name,
+                        header.mode.name()
+                    );
+                    errors += 1;
+                }
+            }
+            if m.key_wrap() != key_wrap_command && !kcv_command && !keys_command {
+                log_error!(
+                    "!!! ERROR: Mode '{}' cannot be used with command '{}'!",
+                    mode_name,
+                    args.command
+                );
                 errors += 1;
             }
+        }
+        None => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Mode not correct!\n",
+                    "!!!        (is '{}', but should be 'ctr', 'cbc', 'xts', 'cfb', 'cfb8', 'cfb1', 'ofb', 'gcm-siv', 'siv', 'ccm', 'ocb', 'eax', 'kw' or 'kwp')"
+                ),
+                mode_name
+            );
+            errors += 1;
+        }
+    }
+
+    // Check key sources, exactly one of key, password and passphrase is needed
+    let passphrase_given = args.passphrase.is_some() || args.passphrase_file.is_some();
+    let derived_key = args.pass.is_some() || passphrase_given;
+    if (kcv_command || recipient_command || rekey_command || keys_command) && derived_key {
+        log_error!(
+            "!!! ERROR: Command '{}' needs a key instead of a password or passphrase!",
+            args.command
+        );
+        errors += 1;
+    }
+    let key_sources = [
+        args.key.is_some(),
+        args.key_file.is_some(),
+        args.key_env.is_some(),
+        args.key_fd.is_some(),
+        args.pass.is_some(),
+        args.passphrase.is_some(),
+        args.passphrase_file.is_some(),
+    ]
+    .iter()
+    .filter(|given| **given)
+    .count();
+    // Adding to the key store without a key generates a random one
+    let generate_key = args.command == "keys-add" && key_sources == 0;
+    if args.command == "remove-recipient" || (keys_command && args.command != "keys-add") {
+        // Dropping a wrapped data key or a stored key needs no key at all
+        if key_sources > 0 {
+            log_error!("!!! ERROR: Key is not used by command '{}'!", args.command);
+            errors += 1;
+        }
+    } else if key_sources == 0 && !generate_key {
+        log_error!("!!! ERROR: Key, password or passphrase is required!");
+        errors += 1;
+    } else if key_sources > 1 {
+        log_error!("!!! ERROR: Only one of key, password and passphrase can be given!");
+        errors += 1;
+    }
+
+    // Envelope encryption wraps a random data key under the given key, decryption
+    // finds the wrapped data key in the container header
+    let envelope = match container {
+        Some(ref header) => !header.recipients.is_empty(),
+        None => args.envelope,
+    };
+    if args.envelope {
+        if args.command != "encrypt" {
+            log_error!(
+                "!!! ERROR: Envelope encryption is read from the input file for decryption!"
+            );
+            errors += 1;
+        } else if raw || derived_key {
+            log_error!("!!! ERROR: Envelope encryption needs a key and the container header!");
+            errors += 1;
+        }
+    }
+
+    // A master key derives key and IV of each file from its relative path, which
+    // decryption finds in the container header
+    let master = match container {
+        Some(ref header) => header.master.is_some(),
+        None => args.derive_from_master,
+    };
+    let mut master_path = None;
+    if args.derive_from_master {
+        if args.command != "encrypt" {
+            log_error!(
+                "!!! ERROR: Derivation from a master key is read from the input file for decryption!"
+            );
+            errors += 1;
+        } else if raw || derived_key || args.envelope {
+            log_error!(
+                "!!! ERROR: Derivation from a master key needs a key and the container header without envelope encryption!"
+            );
+            errors += 1;
+        } else if let Some(ref path) = args.input_file_path {
+            // Absolute paths below the current directory are taken relative to it
+            let path = env::current_dir()
+                .ok()
+                .and_then(|dir| path.strip_prefix(dir).ok())
+                .unwrap_or(path);
+            master_path = master_key::relative_path(path);
+            if master_path.is_none() {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Input file path not supported by --derive-from-master!\n",
+                        "!!!        (is '{}', but should be relative without '..' and valid UTF-8)"
+                    ),
+                    path.display()
+                );
+                errors += 1;
+            }
+        }
+    }
+    if recipient_command && !envelope && (container.is_some() || raw) {
+        log_error!("!!! ERROR: Input file is not envelope encrypted!");
+        errors += 1;
+    }
+
+    // Rekeying processes the chunks independently, which only CTR and XTS allow
+    if rekey_command {
+        if raw {
+            log_error!("!!! ERROR: Command 'rekey' needs input with container header!");
+            errors += 1;
+        } else if let Some(ref header) = container {
+            if header.mode != Mode::Ctr && header.mode != Mode::Xts {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Mode not supported by command 'rekey'!\n",
+                        "!!!        (is '{}', but should be 'ctr' or 'xts')"
+                    ),
+                    header.mode.name()
+                );
+                errors += 1;
+            }
+        }
+    }
+
+    // Check the name of the key to add, remove or export
+    match args.name {
+        Some(_) if !keys_command => {
+            log_error!("!!! ERROR: Key name is only used by the key store commands!");
+            errors += 1;
+        }
+        Some(_) if args.command == "keys-list" => {
+            log_error!("!!! ERROR: Key name is not used by command 'keys-list'!");
+            errors += 1;
+        }
+        Some(ref name) if !key_store::is_valid_name(name) => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Key name not correct!\n",
+                    "!!!        (is '{}', but should be 1 to 255 bytes and not only hex characters)"
+                ),
+                name
+            );
+            errors += 1;
+        }
+        None if keys_command && args.command != "keys-list" => {
+            log_error!(
+                "!!! ERROR: Key name is required for command '{}'!",
+                args.command
+            );
+            errors += 1;
+        }
+        _ => {}
+    }
+
+    // Open the key store for the key store commands and for a key given by name, i.e.
+    // a key that is not only hex characters. 'keys-add' creates a missing key store.
+    let key_name_given = args
+        .key
+        .as_ref()
+        .is_some_and(|key| !key.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    let key_store_path = args
+        .key_store
+        .clone()
+        .or_else(|| env::var_os("AES_CTR_KEY_STORE").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".aes-ctr-keys")));
+    let mut key_store: Option<KeyStore> = None;
+    if keys_command || key_name_given {
+        let phrase = match args.key_store_passphrase_file {
+            // A trailing line break is ignored like for the passphrase file
+            Some(ref path) => fs::read(path)
+                .map(|bytes| {
+                    let bytes = SecretBytes::new(bytes);
+                    let phrase = bytes.strip_suffix(b"\n").map_or(&bytes[..], |phrase| {
+                        phrase.strip_suffix(b"\r").unwrap_or(phrase)
+                    });
+                    SecretBytes::new(phrase.to_vec())
+                })
+                .map_err(|e| e.to_string()),
+            None => env::var("AES_CTR_KEY_STORE_PASSPHRASE")
+                .map(|value| SecretBytes::new(value.into_bytes()))
+                .map_err(|e| e.to_string()),
         };
+        match (phrase, &key_store_path) {
+            (Err(e), _) => {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Key store passphrase reading failed: {}\n",
+                        "!!!        (use --key-store-passphrase-file or $AES_CTR_KEY_STORE_PASSPHRASE)"
+                    ),
+                    e
+                );
+                errors += 1;
+            }
+            (Ok(ref phrase), _) if phrase.is_empty() => {
+                log_error!("!!! ERROR: Key store passphrase is empty!");
+                errors += 1;
+            }
+            (Ok(_), None) => {
+                log_error!(
+                    "!!! ERROR: Key store path is required! (use --key-store or $AES_CTR_KEY_STORE)"
+                );
+                errors += 1;
+            }
+            (Ok(ref phrase), Some(path)) if args.command == "keys-add" && !path.exists() => {
+                log_info!("\n### Creating key store {} ...", path.display());
+                match KeyStore::create(
+                    phrase,
+                    Kdf::from_name("argon2id").expect("argon2id is known"),
+                ) {
+                    Ok(store) => key_store = Some(store),
+                    Err(e) => {
+                        log_error!("!!! ERROR: Key store creation failed: {}", e);
+                        errors += 1;
+                    }
+                }
+            }
+            (Ok(ref phrase), Some(path)) => match KeyStore::open(path, phrase) {
+                Ok(store) => key_store = Some(store),
+                Err(e) => {
+                    log_error!(
+                        "!!! ERROR: Key store '{}' reading failed: {}",
+                        path.display(),
+                        e
+                    );
+                    errors += 1;
+                }
+            },
+        }
+    } else if args.key_store.is_some() || args.key_store_passphrase_file.is_some() {
+        log_error!("!!! ERROR: Key store is only used by the key store commands and key names!");
+        errors += 1;
+    }
+
+    // Read the key from the environment, a file, a file descriptor or the key store
+    // instead of the command line, where it would show up in ps and the shell
+    // history. All copies of the key are wiped once they are no longer needed. A key
+    // encryption key is a single AES key in any mode.
+    let key_factor = if mode.double_key() && !envelope && !master {
+        2
     } else {
-        eprintln!(
-            concat!(
-                "!!! ERROR: Key hex string neither matches 128-bit nor 256-bit size!\n",
-                "!!!        (is {} characters long, but should be 32 or 64)"
-            ),
-            args.key.len()
+        1
+    };
+    let mut key_hex = match (args.key.take(), &key_store) {
+        (Some(name), Some(store)) if key_name_given => match store.get(&name) {
+            Ok(key) => Some(SecretBytes::new(hex::encode(&*key).into_bytes())),
+            Err(e) => {
+                log_error!("!!! ERROR: Key '{}' reading failed: {}", name, e);
+                errors += 1;
+                None
+            }
+        },
+        (key, _) => key.map(|key| SecretBytes::new(key.into_bytes())),
+    };
+    if let Some(ref name) = args.key_env {
+        match env::var(name) {
+            Ok(value) => {
+                let value = SecretBytes::new(value.into_bytes());
+                key_hex = Some(SecretBytes::new(value.trim_ascii().to_vec()));
+            }
+            Err(e) => {
+                log_error!(
+                    "!!! ERROR: Key environment variable '{}' reading failed: {}",
+                    name,
+                    e
+                );
+                errors += 1;
+            }
+        }
+    }
+    let mut key_file_bytes: Option<SecretBytes> = None;
+    if let Some(ref path) = args.key_file {
+        match fs::metadata(path) {
+            Ok(ref metadata) if world_readable(metadata) && !args.allow_insecure_key_file => {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Key file '{}' is readable by everyone!\n",
+                        "!!!        (restrict it with 'chmod 600' or pass --allow-insecure-key-file)"
+                    ),
+                    path.display()
+                );
+                errors += 1;
+            }
+            Ok(_) => match fs::read(path) {
+                Ok(bytes) => key_file_bytes = Some(SecretBytes::new(bytes)),
+                Err(e) => {
+                    log_error!("!!! ERROR: Key file reading failed: {}", e);
+                    errors += 1;
+                }
+            },
+            Err(e) => {
+                log_error!("!!! ERROR: Key file reading failed: {}", e);
+                errors += 1;
+            }
+        }
+    }
+    if let Some(fd) = args.key_fd {
+        match read_fd(fd) {
+            Ok(bytes) => key_file_bytes = Some(SecretBytes::new(bytes)),
+            Err(e) => {
+                log_error!(
+                    "!!! ERROR: Key file descriptor {} reading failed: {}",
+                    fd,
+                    e
+                );
+                errors += 1;
+            }
+        }
+    }
+    // Key files hold a hex string (surrounding whitespace is ignored) or raw bytes
+    if let Some(bytes) = key_file_bytes {
+        let text = bytes.trim_ascii();
+        if !text.is_empty() && text.iter().all(|byte| byte.is_ascii_hexdigit()) {
+            key_hex = Some(SecretBytes::new(text.to_vec()));
+        } else if bytes.len() == 16 * key_factor || bytes.len() == 32 * key_factor {
+            key_hex = Some(SecretBytes::new(hex::encode(&*bytes).into_bytes()));
+        } else {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Raw key neither matches {}-bit nor {}-bit size!\n",
+                    "!!!        (is {} bytes long, but should be {} or {})"
+                ),
+                128 * key_factor,
+                256 * key_factor,
+                bytes.len(),
+                16 * key_factor,
+                32 * key_factor
+            );
+            errors += 1;
+        }
+    }
+
+    // Check and parse key (XTS and SIV take two AES keys, i.e. twice the length)
+    if let Some(ref key) = key_hex {
+        if key.len() == 32 * key_factor || key.len() == 64 * key_factor {
+            key_size = (key.len() * 4) as u16;
+            match Vec::from_hex(&key[..]) {
+                Ok(bytes) => key_bytes = SecretBytes::new(bytes),
+                Err(e) => {
+                    log_error!("!!! ERROR: Key hex string parsing failed: {}", e);
+                    errors += 1;
+                }
+            };
+        } else {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Key hex string neither matches {}-bit nor {}-bit size!\n",
+                    "!!!        (is {} characters long, but should be {} or {})"
+                ),
+                128 * key_factor,
+                256 * key_factor,
+                key.len(),
+                32 * key_factor,
+                64 * key_factor
+            );
+            errors += 1;
+        }
+    }
+
+    // Check and parse the key of the recipient to add or the key to reencrypt with,
+    // which is as long as the key given as usual
+    let new_key_command = args.command == "add-recipient" || args.command == "rekey";
+    let mut new_key = SecretBytes::zeroed(0);
+    let mut new_key_hex = args
+        .new_key
+        .take()
+        .map(|key| SecretBytes::new(key.into_bytes()));
+    if let Some(ref path) = args.new_key_file {
+        match fs::metadata(path) {
+            Ok(ref metadata) if world_readable(metadata) && !args.allow_insecure_key_file => {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: New key file '{}' is readable by everyone!\n",
+                        "!!!        (restrict it with 'chmod 600' or pass --allow-insecure-key-file)"
+                    ),
+                    path.display()
+                );
+                errors += 1;
+            }
+            Ok(_) if new_key_hex.is_some() => {
+                log_error!("!!! ERROR: Only one of new key and new key file can be given!");
+                errors += 1;
+            }
+            Ok(_) => match fs::read(path) {
+                Ok(bytes) => {
+                    let bytes = SecretBytes::new(bytes);
+                    new_key_hex = Some(SecretBytes::new(bytes.trim_ascii().to_vec()));
+                }
+                Err(e) => {
+                    log_error!("!!! ERROR: New key file reading failed: {}", e);
+                    errors += 1;
+                }
+            },
+            Err(e) => {
+                log_error!("!!! ERROR: New key file reading failed: {}", e);
+                errors += 1;
+            }
+        }
+    }
+    match new_key_hex {
+        Some(_) if !new_key_command => {
+            log_error!("!!! ERROR: New key is only used by commands 'add-recipient' and 'rekey'!");
+            errors += 1;
+        }
+        Some(ref key) if key.len() == 32 * key_factor || key.len() == 64 * key_factor => {
+            match Vec::from_hex(&key[..]) {
+                Ok(bytes) => new_key = SecretBytes::new(bytes),
+                Err(e) => {
+                    log_error!("!!! ERROR: New key hex string parsing failed: {}", e);
+                    errors += 1;
+                }
+            }
+        }
+        Some(ref key) => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: New key hex string neither matches {}-bit nor {}-bit size!\n",
+                    "!!!        (is {} characters long, but should be {} or {})"
+                ),
+                128 * key_factor,
+                256 * key_factor,
+                key.len(),
+                32 * key_factor,
+                64 * key_factor
+            );
+            errors += 1;
+        }
+        None if new_key_command => {
+            log_error!(
+                "!!! ERROR: New key is required for command '{}'!",
+                args.command
+            );
+            errors += 1;
+        }
+        None => {}
+    }
+
+    // Check and parse the key check value of the recipient to remove
+    let mut recipient_kcv = Vec::new();
+    match args.recipient_kcv {
+        Some(_) if args.command != "remove-recipient" => {
+            log_error!(
+                "!!! ERROR: Recipient key check value is only used by command 'remove-recipient'!"
+            );
+            errors += 1;
+        }
+        Some(ref hex) if hex.len() == 2 * kcv::KCV_SIZE => match Vec::from_hex(hex) {
+            Ok(bytes) => recipient_kcv = bytes,
+            Err(e) => {
+                log_error!(
+                    "!!! ERROR: Recipient key check value hex string parsing failed: {}",
+                    e
+                );
+                errors += 1;
+            }
+        },
+        Some(ref hex) => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Recipient key check value hex string does not match {}-bit size!\n",
+                    "!!!        (is {} characters long, but should be {})"
+                ),
+                8 * kcv::KCV_SIZE,
+                hex.len(),
+                2 * kcv::KCV_SIZE
+            );
+            errors += 1;
+        }
+        None if args.command == "remove-recipient" => {
+            log_error!(
+                "!!! ERROR: Recipient key check value is required for command 'remove-recipient'!"
+            );
+            errors += 1;
+        }
+        None => {}
+    }
+
+    // The key has to fit the container
+    if let Some(ref header) = container {
+        if key_hex.is_some() && !envelope && !master && key_size != header.key_size {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Key size does not match the input file!\n",
+                    "!!!        (is {}-bit, but should be {}-bit)"
+                ),
+                key_size,
+                header.key_size
+            );
+            errors += 1;
+        }
+        if header.kdf.is_some() != passphrase_given && key_sources == 1 {
+            if passphrase_given {
+                log_error!("!!! ERROR: Input file was not encrypted with a passphrase!");
+            } else {
+                log_error!("!!! ERROR: Input file was encrypted with a passphrase!");
+            }
+            errors += 1;
+        }
+    }
+
+    // Check size of keys derived from a password, passphrase or master key, of data
+    // keys and of generated keys, XTS and GCM-SIV have no AES-192. The container
+    // header records it for decryption.
+    if (passphrase_given || envelope || master)
+        && args.key_size.is_some()
+        && args.command != "encrypt"
+    {
+        log_error!("!!! ERROR: Key size is read from the input file for decryption!");
+        errors += 1;
+    }
+    if derived_key || envelope || master || generate_key {
+        key_size = args.key_size.unwrap_or(256);
+        if (key_size != 128 && key_size != 192 && key_size != 256)
+            || (key_size == 192 && (mode == Mode::Xts || mode == Mode::GcmSiv))
+        {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Key size not supported by mode '{}'!\n",
+                    "!!!        (is {}, but should be 128, 192 or 256)"
+                ),
+                mode_name,
+                key_size
+            );
+            errors += 1;
+        }
+        if mode.double_key() {
+            key_size *= 2;
+        }
+    } else if args.key_size.is_some() {
+        log_error!(
+            "!!! ERROR: Key size is only used with a password, passphrase, master key, envelope encryption or a generated key!"
         );
         errors += 1;
     }
+    if generate_key && errors == 0 {
+        key_bytes = SecretBytes::zeroed(key_size as usize / 8);
+        if let Err(e) = random::fill(&mut key_bytes) {
+            log_error!("!!! ERROR: Random key generation failed: {}", e);
+            errors += 1;
+        }
+    }
 
-    // Check and parse initializtion vector
-    if args.iv.len() == 32 {
-        match Vec::from_hex(args.iv) {
-            Ok(bytes) => iv_bytes = bytes,
+    // Check and parse password based key derivation settings
+    if let Some(pass) = args.pass.take().map(String::into_bytes) {
+        if !mode.password_based() {
+            log_error!(
+                "!!! ERROR: Password is not supported by mode '{}'!",
+                mode_name
+            );
+            errors += 1;
+        }
+        let md_name = args.md.as_deref().unwrap_or("sha256");
+        let digest = MessageDigest::from_name(md_name).unwrap_or_else(|| {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Digest not correct!\n",
+                    "!!!        (is '{}', but should be 'sha256' or 'md5')"
+                ),
+                md_name
+            );
+            errors += 1;
+            MessageDigest::Sha256
+        });
+        let derivation = match args.iter {
+            Some(0) => {
+                log_error!("!!! ERROR: Iteration count has to be at least 1!");
+                errors += 1;
+                KeyDerivation::BytesToKey
+            }
+            Some(iterations) => KeyDerivation::Pbkdf2 { iterations },
+            None if args.pbkdf2 => KeyDerivation::Pbkdf2 {
+                iterations: openssl::DEFAULT_ITERATIONS,
+            },
+            None => KeyDerivation::BytesToKey,
+        };
+        let mut salt = None;
+        match args.salt {
+            Some(_) if args.command != "encrypt" => {
+                log_error!("!!! ERROR: Salt is read from the input file for decryption!");
+                errors += 1;
+            }
+            Some(ref hex) if hex.len() == 2 * openssl::SALT_SIZE => {
+                match <[u8; openssl::SALT_SIZE]>::from_hex(hex) {
+                    Ok(bytes) => salt = Some(bytes),
+                    Err(e) => {
+                        log_error!("!!! ERROR: Salt hex string parsing failed: {}", e);
+                        errors += 1;
+                    }
+                };
+            }
+            Some(ref hex) => {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Salt hex string does not match {}-bit size!\n",
+                        "!!!        (is {} characters long, but should be {})"
+                    ),
+                    8 * openssl::SALT_SIZE,
+                    hex.len(),
+                    2 * openssl::SALT_SIZE
+                );
+                errors += 1;
+            }
+            None => {}
+        }
+        password = Some(PasswordOptions {
+            password: SecretBytes::new(pass),
+            derivation,
+            digest,
+            salt,
+        });
+    } else if args.pbkdf2 || args.iter.is_some() || args.md.is_some() || args.salt.is_some() {
+        log_error!("!!! ERROR: Key derivation options are only used with a password!");
+        errors += 1;
+    }
+
+    // Check and read passphrase, its KDF settings are stored in the container header
+    if passphrase_given {
+        if mode.key_wrap() {
+            log_error!(
+                "!!! ERROR: Passphrase is not supported by mode '{}'!",
+                mode_name
+            );
+            errors += 1;
+        }
+        if args.raw {
+            log_error!("!!! ERROR: Passphrase cannot be used with --raw output!");
+            errors += 1;
+        }
+        let phrase = match (args.passphrase.take(), &args.passphrase_file) {
+            (Some(phrase), _) => SecretBytes::new(phrase.into_bytes()),
+            (None, Some(path)) => match fs::read(path) {
+                Ok(mut bytes) => {
+                    if bytes.ends_with(b"\n") {
+                        bytes.pop();
+                        if bytes.ends_with(b"\r") {
+                            bytes.pop();
+                        }
+                    }
+                    SecretBytes::new(bytes)
+                }
+                Err(e) => {
+                    log_error!("!!! ERROR: Passphrase file reading failed: {}", e);
+                    errors += 1;
+                    SecretBytes::zeroed(0)
+                }
+            },
+            (None, None) => unreachable!("passphrase is given"),
+        };
+        if phrase.is_empty() {
+            log_error!("!!! ERROR: Passphrase is empty!");
+            errors += 1;
+        }
+        let kdf_name = args.kdf.as_deref().unwrap_or("argon2id");
+        match Kdf::from_name(kdf_name) {
+            Some(kdf) => {
+                passphrase = Some(PassphraseOptions {
+                    passphrase: phrase,
+                    kdf,
+                })
+            }
+            None => {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Key derivation function not correct!\n",
+                        "!!!        (is '{}', but should be 'argon2id', 'scrypt' or 'pbkdf2')"
+                    ),
+                    kdf_name
+                );
+                errors += 1;
+            }
+        }
+    } else if args.kdf.is_some() {
+        log_error!("!!! ERROR: Key derivation function is only used with a passphrase!");
+        errors += 1;
+    }
+
+    // Check and parse counter layout
+    match CounterLayout::from_name(&args.counter_layout) {
+        Some(layout) if layout != CounterLayout::Full128 && mode != Mode::Ctr => {
+            log_error!(
+                "!!! ERROR: Counter layout is not supported by mode '{}'!",
+                mode_name
+            );
+            errors += 1;
+        }
+        Some(layout) => counter_layout = layout,
+        None => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Counter layout not correct!\n",
+                    "!!!        (is '{}', but should be 'full128', '64-64', '96-32' or '96-32-wrapping')"
+                ),
+                args.counter_layout
+            );
+            errors += 1;
+        }
+    }
+
+    // Check and parse counter endianness
+    match CounterEndianness::from_name(&args.counter_endianness) {
+        Some(endianness) if endianness != CounterEndianness::Big && mode != Mode::Ctr => {
+            log_error!(
+                "!!! ERROR: Counter endianness is not supported by mode '{}'!",
+                mode_name
+            );
+            errors += 1;
+        }
+        Some(endianness) => counter_endianness = endianness,
+        None => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: Counter endianness not correct!\n",
+                    "!!!        (is '{}', but should be 'big' or 'little')"
+                ),
+                args.counter_endianness
+            );
+            errors += 1;
+        }
+    }
+
+    // Check and parse initializtion vector (XTS and SIV need none, CCM, OCB and EAX take a range)
+    let (iv_min, iv_max) = mode.iv_sizes();
+    match args.iv {
+        Some(_) if derived_key || master => {
+            log_error!("!!! ERROR: IV is derived from the password, passphrase or master key!");
+            errors += 1;
+        }
+        Some(_) if kcv_command || keys_command => {
+            log_error!("!!! ERROR: IV is not used by command '{}'!", args.command);
+            errors += 1;
+        }
+        Some(_) if container_expected => {
+            log_error!("!!! ERROR: IV is read from the input file for decryption!");
+            errors += 1;
+        }
+        Some(_) if iv_max == 0 => {
+            log_error!("!!! ERROR: IV is not used by mode '{}'!", mode_name);
+            errors += 1;
+        }
+        Some(ref iv) if iv.len() % 2 == 0 && iv.len() >= 2 * iv_min && iv.len() <= 2 * iv_max => {
+            match Vec::from_hex(iv) {
+                Ok(bytes) => {
+                    if args.command == "encrypt" && !raw && bytes.iter().all(|&byte| byte == 0) {
+                        log_error!(concat!(
+                            "!!! ERROR: IV is all-zero!\n",
+                            "!!!        (the key check value in the header would reveal the start\n",
+                            "!!!        of the keystream, omit -v to get a random IV)"
+                        ));
+                        errors += 1;
+                    } else if args.command == "encrypt" && weak_iv(&bytes) {
+                        log_warning!(concat!(
+                            "!!! WARNING: IV is all-zero or a well-known example value!\n",
+                            "!!!          (reusing an IV with the same key reveals the plaintext,\n",
+                            "!!!          omit -v to get a random IV)"
+                        ));
+                    }
+                    iv_bytes = bytes;
+                }
+                Err(e) => {
+                    log_error!(" ! ERROR: IV hex string parsing failed: {}", e);
+                    errors += 1;
+                }
+            };
+        }
+        Some(ref iv) if iv_min == iv_max => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: IV hex string does not match {}-bit size!\n",
+                    "!!!        (is {} characters long, but should be {})"
+                ),
+                8 * iv_min,
+                iv.len(),
+                2 * iv_min
+            );
+            errors += 1;
+        }
+        Some(ref iv) => {
+            log_error!(
+                concat!(
+                    "!!! ERROR: IV hex string does not match {}-bit to {}-bit size!\n",
+                    "!!!        (is {} characters long, but should be an even number from {} to {})"
+                ),
+                8 * iv_min,
+                8 * iv_max,
+                iv.len(),
+                2 * iv_min,
+                2 * iv_max
+            );
+            errors += 1;
+        }
+        None if iv_max == 0
+            || derived_key
+            || master
+            || container_expected
+            || kcv_command
+            || rekey_command
+            || keys_command => {}
+        None if args.command == "encrypt" => {
+            // CCM takes the shortest nonce like for derived IVs, which leaves the most
+            // room for the message length. Only the nonce part of a split CTR counter
+            // is random, the counter starts at its start value.
+            let mut iv = vec![0u8; if mode == Mode::Ccm { iv_min } else { iv_max }];
+            match counter_layout.fresh_iv(&mut iv, counter_endianness) {
+                Ok(()) if raw => {
+                    // Printed even with --quiet, the IV is lost otherwise
+                    println!(
+                        concat!(
+                            "\n### Generated random IV {}\n",
+                            "### (needed for decryption, --raw output has no header)"
+                        ),
+                        hex::encode(&iv)
+                    );
+                    iv_bytes = iv;
+                }
+                Ok(()) => {
+                    log_info!(
+                        "\n### Generated random IV {} (stored in the container header)",
+                        hex::encode(&iv)
+                    );
+                    iv_bytes = iv;
+                }
+                Err(e) => {
+                    log_error!("!!! ERROR: Random IV generation failed: {}", e);
+                    errors += 1;
+                }
+            }
+        }
+        None => {
+            log_error!("!!! ERROR: IV is required for mode '{}'!", mode_name);
+            errors += 1;
+        }
+    }
+
+    // Check and parse associated data
+    if let Some(ref aad) = args.aad {
+        if !mode.authenticated() {
+            log_error!(
+                "!!! ERROR: Associated data is not supported by mode '{}'!",
+                mode_name
+            );
+            errors += 1;
+        }
+        match Vec::from_hex(aad) {
+            Ok(bytes) => aad_bytes = Some(bytes),
             Err(e) => {
-                eprintln!(" ! ERROR: IV hex string parsing failed: {}", e);
+                log_error!(
+                    "!!! ERROR: Associated data hex string parsing failed: {}",
+                    e
+                );
                 errors += 1;
             }
         };
-    } else {
-        eprintln!(
+    }
+
+    // Check tag length
+    if mode == Mode::Ccm && (!(4..=16).contains(&args.tag_length) || args.tag_length & 1 != 0) {
+        log_error!(
+            concat!(
+                "!!! ERROR: Tag length not supported!\n",
+                "!!!        (is {}, but should be even and from 4 to 16)"
+            ),
+            args.tag_length
+        );
+        errors += 1;
+    }
+    if (mode == Mode::Ocb || mode == Mode::Eax) && !(1..=16).contains(&args.tag_length) {
+        log_error!(
+            concat!(
+                "!!! ERROR: Tag length not supported!\n",
+                "!!!        (is {}, but should be from 1 to 16)"
+            ),
+            args.tag_length
+        );
+        errors += 1;
+    }
+
+    // Check sector size
+    if mode == Mode::Xts && args.sector_size != 512 && args.sector_size != 4096 {
+        log_error!(
             concat!(
-                "!!! ERROR: IV hex string does not match 128-bit size!\n",
-                "!!!        (is {} characters long, but should be 32)"
+                "!!! ERROR: Sector size not supported!\n",
+                "!!!        (is {}, but should be 512 or 4096)"
             ),
-            args.iv.len()
+            args.sector_size
         );
         errors += 1;
     }
 
+    // Print the key check value and compare it with the input file
+    if errors == 0 && kcv_command {
+        // A key encryption key is a single AES key in any mode, a master key is checked
+        // by the key it derives for the input file
+        let value = match container {
+            Some(Header {
+                master: Some(ref file),
+                key_size,
+                ref iv,
+                counter_layout,
+                counter_endianness,
+                ..
+            }) => {
+                let (key, _) = master_key::derive_key_iv(
+                    &key_bytes,
+                    file,
+                    key_size as usize / 8,
+                    iv.len(),
+                    counter_layout,
+                    counter_endianness,
+                );
+                kcv::for_mode(&key, mode)
+            }
+            _ if envelope => kcv::kcv(&key_bytes).map(|value| value.to_vec()),
+            _ => kcv::for_mode(&key_bytes, mode),
+        };
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                log_error!("!!! ERROR: Key check value computation failed: {}", e);
+                process::exit(1);
+            }
+        };
+        log_info!("\n### Key check value ...");
+        // Printed even with --quiet, it is the result of the command
+        println!("{}", kcv::to_hex(&value));
+        if let Some(ref header) = container {
+            let expected: Vec<&[u8]> = if envelope {
+                header
+                    .recipients
+                    .iter()
+                    .map(|recipient| &recipient.kcv[..])
+                    .collect()
+            } else {
+                vec![&header.kcv]
+            };
+            if !expected.contains(&&value[..]) {
+                log_error!(
+                    concat!(
+                        "!!! ERROR: Key does not match the input file!\n",
+                        "!!!        (is {}, but should be {})"
+                    ),
+                    kcv::to_hex(&value),
+                    expected
+                        .iter()
+                        .map(|kcv| kcv::to_hex(kcv))
+                        .collect::<Vec<_>>()
+                        .join(" or ")
+                );
+                process::exit(1);
+            }
+            log_info!("\n### Key matches the input file!");
+        }
+        return;
+    }
+
+    // Edit the key store or export a key from it
+    if errors == 0 && keys_command {
+        let mut store = key_store.expect("key store is checked");
+        let path = key_store_path.expect("key store path is checked");
+        let name = args.name.as_deref().unwrap_or_default();
+        let result = match args.command.as_str() {
+            "keys-add" => store.add(name, &key_bytes).and_then(|()| store.save(&path)),
+            "keys-remove" => store.remove(name).and_then(|()| store.save(&path)),
+            "keys-export" => store.get(name).and_then(|key| {
+                let output_file_path = args.output_file_path.as_ref();
+                key_store::export(&key, output_file_path.expect("output file is checked"))
+            }),
+            _ => {
+                log_info!("\n### Keys in {} ...", path.display());
+                // Printed even with --quiet, it is the result of the command
+                store.names().try_for_each(|name| {
+                    let key = store.get(name)?;
+                    println!(
+                        "{} ({}-bit, {})",
+                        name,
+                        key.len() * 8,
+                        log::fingerprint(&key)
+                    );
+                    Ok(())
+                })
+            }
+        };
+        if let Err(e) = result {
+            log_error!("!!! ERROR: Command '{}' failed: {}", args.command, e);
+            process::exit(1);
+        }
+        match args.command.as_str() {
+            "keys-add" => log_info!("\n### Added key '{}' ({}-bit)", name, key_bytes.len() * 8),
+            "keys-remove" => log_info!("\n### Removed key '{}'", name),
+            "keys-export" => log_info!("\n### Exported key '{}'", name),
+            _ => {}
+        }
+        return;
+    }
+
     // Handle encryption/decryption command with given parameters
     if errors == 0 {
-        println!("\n### Performing {}ion ...", args.command);
+        let action = match args.command.as_str() {
+            "encrypt" => "encryption",
+            "decrypt" => "decryption",
+            "wrap-key" => "key wrapping",
+            "unwrap-key" => "key unwrapping",
+            "add-recipient" => "adding the recipient",
+            "remove-recipient" => "removing the recipient",
+            _ => "rekeying",
+        };
+        log_info!("\n### Performing {} ...", action);
+        let input_file_path = args.input_file_path.clone().expect("input file is checked");
+        let output_file_path = args
+            .output_file_path
+            .clone()
+            .expect("output file is checked");
         const LEN: usize = 1usize;
         let mut times: Vec<f32> = vec![0f32; LEN];
-        for i in 0..LEN {
+        for time in times.iter_mut() {
             let now = Instant::now();
-            aes_ctr_optimized::handle_aes_ctr_command(
-                args.command.clone(),
-                key_size,
-                key_bytes.clone(),
-                iv_bytes.clone(),
-                args.input_file_path.clone(),
-                args.output_file_path.clone(),
-            );
-            times[i] = now.elapsed().as_secs_f32();
-            println!(
+            let result = match args.command.as_str() {
+                // Only the container header is rewritten, the payload is copied
+                "add-recipient" => envelope::add_recipient(
+                    &key_bytes,
+                    &new_key,
+                    &input_file_path,
+                    &output_file_path,
+                ),
+                "remove-recipient" => {
+                    envelope::remove_recipient(&recipient_kcv, &input_file_path, &output_file_path)
+                }
+                // Decrypts and encrypts again chunk by chunk in memory
+                "rekey" => {
+                    rekey::rekey_file(&key_bytes, &new_key, &input_file_path, &output_file_path)
+                }
+                _ => aes_ctr_optimized::handle_aes_ctr_command(
+                    args.command.clone(),
+                    CipherOptions {
+                        mode,
+                        sector_size: args.sector_size,
+                        aad: aad_bytes.clone(),
+                        tag_size: args.tag_length,
+                        counter_layout,
+                        counter_endianness,
+                        password: password.take(),
+                        passphrase: passphrase.take(),
+                        raw,
+                        envelope,
+                        master: master_path.clone(),
+                    },
+                    key_size,
+                    &key_bytes,
+                    iv_bytes.clone(),
+                    input_file_path.clone(),
+                    output_file_path.clone(),
+                ),
+            };
+            if let Err(e) = result {
+                log_error!("!!! ERROR: {} failed: {}", action, e);
+                process::exit(1);
+            }
+            *time = now.elapsed().as_secs_f32();
+            log_info!(
                 "\n### Finished! It took {:.10} seconds!",
                 now.elapsed().as_secs_f32()
             );
         }
         let sum = times.iter().fold(0f32, |acc, x| acc + x);
-        println!("The time is: {times:?}");
-        println!("The sum is: {}", sum / times.len() as f32);
+        log_verbose!("The time is: {times:?}");
+        log_verbose!("The sum is: {}", sum / times.len() as f32);
     } else {
-        println!("\n### Aborting ...");
-        println!("!!! {} error(s) occurred!", errors);
+        log_info!("\n### Aborting ...");
+        log_error!("!!! {} error(s) occurred!", errors);
         process::exit(1);
     }
 }
+
+/// Whether `iv` is all-zero or (the start of) one of the example IVs
+fn weak_iv(iv: &[u8]) -> bool {
+    let hex = hex::encode(iv);
+    iv.iter().all(|&byte| byte == 0) || EXAMPLE_IVS.iter().any(|example| example.starts_with(&hex))
+}
+
+/// Whether everyone may read the file
+#[cfg(unix)]
+fn world_readable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o004 != 0
+}
+
+#[cfg(not(unix))]
+fn world_readable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+/// Reads everything from the inherited file descriptor `fd`
+#[cfg(unix)]
+fn read_fd(fd: i32) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::io::FromRawFd;
+    if fd < 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "negative file descriptor",
+        ));
+    }
+    // The descriptor stays open, it can be one the process still needs like stdout
+    let mut file = std::mem::ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> std::io::Result<Vec<u8>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "file descriptors are only supported on Unix",
+    ))
+}
@@ -0,0 +1,117 @@
+use crate::aes_ctr_optimized::{CounterEndianness, CounterLayout};
+use crate::hkdf::hkdf;
+use crate::secret::SecretBytes;
+use crate::sha256::Sha256;
+use std::path::{Component, Path};
+
+/// Size of the random salt
+pub const SALT_SIZE: usize = 16;
+
+/// What the key and IV of a file are derived from besides the master key, as stored
+/// in the container header
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileDerivation {
+    pub salt: [u8; SALT_SIZE],
+    /// Path of the plaintext file relative to the directory encryption ran in, with
+    /// `/` as separator on every platform
+    pub path: String,
+}
+
+/// Label in front of the HKDF info, separates this derivation from other uses of
+/// the same master key
+const LABEL: &[u8] = b"aes-ctr file key";
+
+/// Derives the key and IV of a file from the master key with HKDF-SHA256. The salt
+/// goes into the extract step, so every file gets its own key even when its path is
+/// reused. The info is the label, the key and IV lengths and the relative path, so a
+/// key belongs to one path only.
+///
+/// Only the nonce part of a split CTR counter is derived, the counter field starts
+/// at its start value so the full counter range is available.
+pub fn derive_key_iv(
+    master_key: &[u8],
+    file: &FileDerivation,
+    key_len: usize,
+    iv_len: usize,
+    layout: CounterLayout,
+    endianness: CounterEndianness,
+) -> (SecretBytes, Vec<u8>) {
+    let nonce_len = match layout {
+        CounterLayout::Full128 => iv_len,
+        _ => iv_len - layout.counter_size(),
+    };
+    let info = [LABEL, &[key_len as u8, iv_len as u8], file.path.as_bytes()].concat();
+    let mut output = SecretBytes::zeroed(key_len + nonce_len);
+    hkdf::<Sha256>(&file.salt, master_key, &info, &mut output);
+    let mut iv = vec![0u8; iv_len];
+    iv[..nonce_len].copy_from_slice(&output[key_len..]);
+    layout.start_counter(&mut iv, endianness);
+    (SecretBytes::new(output[..key_len].to_vec()), iv)
+}
+
+/// The relative path of `path` as it goes into the derivation, `.` components are
+/// dropped. Absolute paths, `..`, paths that are not valid UTF-8 and paths longer
+/// than the header allows have none.
+pub fn relative_path(path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    let path = parts.join("/");
+    if path.is_empty() || path.len() > u16::MAX as usize {
+        return None;
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// HKDF-SHA256 with the salt as extract salt and `label || 16 || 16 || path` as
+    /// info, cross-checked with the HKDF of pyca/cryptography. Another salt or path
+    /// gives another key.
+    #[test]
+    fn derives_with_salt_and_path() {
+        let mut file = FileDerivation {
+            salt: [7; SALT_SIZE],
+            path: "dir/file.txt".to_string(),
+        };
+        let big = CounterEndianness::Big;
+        let derive = |file: &FileDerivation| {
+            derive_key_iv(&[1; 32], file, 16, 16, CounterLayout::Full128, big)
+        };
+        let (key, iv) = derive(&file);
+        assert_eq!(hex::encode(&*key), "2746b74be37677aacabaa76fbe31cd34");
+        assert_eq!(hex::encode(&iv), "c9ee0554e6ab5c8140721d5515e4f92c");
+
+        file.salt[0] ^= 1;
+        assert_ne!(*derive(&file).0, *key);
+        file.salt[0] ^= 1;
+        file.path = "dir/other.txt".to_string();
+        assert_ne!(*derive(&file).0, *key);
+    }
+
+    /// Split layouts derive the nonce only, the key stays the same
+    #[test]
+    fn derived_iv_starts_counter_field() {
+        let file = FileDerivation {
+            salt: [7; SALT_SIZE],
+            path: "dir/file.txt".to_string(),
+        };
+        let big = CounterEndianness::Big;
+        let derive = |layout| derive_key_iv(&[1; 32], &file, 16, 16, layout, big);
+        let (key, full) = derive(CounterLayout::Full128);
+        let (split64_key, split64) = derive(CounterLayout::Split64);
+        let (_, split96) = derive(CounterLayout::Split96 { check_wrap: true });
+        assert_eq!(&*key, &*split64_key);
+        assert_eq!(split64[..8], full[..8]);
+        assert_eq!(split64[8..], [0; 8]);
+        assert_eq!(split96[..12], full[..12]);
+        assert_eq!(split96[12..], [0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,498 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, ct_eq, expand_key, inv_aes_v2, open_output_file, process_payload_chunked,
+    scan_payload_chunked, Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Shortest and longest allowed nonce in bytes
+pub const NONCE_SIZES: (usize, usize) = (1, 15);
+
+/// AES-OCB3 (RFC 7253), single pass authenticated encryption in which every block
+/// can be processed independently of the others
+pub struct Ocb {
+    keys: SecretBytes,
+    nr: usize,
+    tag_size: usize,
+    l_star: u128,
+    l_dollar: u128,
+    /// `L_i` for every possible number of trailing zeros of a block index
+    l: [u128; 64],
+}
+
+impl Ocb {
+    /// Creates a cipher from an AES key and a tag length of 1 to 16 bytes
+    pub fn new(key: &[u8], tag_size: usize) -> Result<Ocb, AesError> {
+        if !(1..=16).contains(&tag_size) {
+            return Err(AesError::InvalidTagLength(tag_size));
+        }
+        let (keys, nr) = expand_key(key)?;
+        let mut ocb = Ocb {
+            keys,
+            nr,
+            tag_size,
+            l_star: 0,
+            l_dollar: 0,
+            l: [0; 64],
+        };
+        ocb.l_star = ocb.encipher(0);
+        ocb.l_dollar = double(ocb.l_star);
+        let mut l = ocb.l_dollar;
+        for entry in ocb.l.iter_mut() {
+            l = double(l);
+            *entry = l;
+        }
+        Ok(ocb)
+    }
+
+    /// Encrypts `data` in place and returns the tag
+    pub fn encrypt(&self, nonce: &[u8], aad: &[u8], data: &mut [u8]) -> Result<Vec<u8>, AesError> {
+        let offset_0 = self.initial_offset(nonce)?;
+        let checksum = self.process_blocks(data, offset_0, 0, true);
+        Ok(self.tag(offset_0, checksum, data.len() as u64, aad))
+    }
+
+    /// Decrypts `data` in place if `tag` is valid, otherwise `data` is wiped
+    pub fn decrypt(
+        &self,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+    ) -> Result<(), AesError> {
+        let offset_0 = self.initial_offset(nonce)?;
+        let checksum = self.process_blocks(data, offset_0, 0, false);
+        if !ct_eq(&self.tag(offset_0, checksum, data.len() as u64, aad), tag) {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+            return Err(AesError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    /// `Offset_0`, derived from the nonce and the tag length
+    fn initial_offset(&self, nonce: &[u8]) -> Result<u128, AesError> {
+        if nonce.len() < NONCE_SIZES.0 || nonce.len() > NONCE_SIZES.1 {
+            return Err(AesError::InvalidNonceLength(nonce.len()));
+        }
+        // num2str(TAGLEN mod 128, 7) || zeros || 1 || N
+        let mut block = [0u8; BLOCK_SIZE];
+        block[BLOCK_SIZE - nonce.len()..].copy_from_slice(nonce);
+        block[BLOCK_SIZE - 1 - nonce.len()] |= 1;
+        block[0] |= ((self.tag_size * 8 % 128) << 1) as u8;
+
+        let bottom = (block[BLOCK_SIZE - 1] & 0x3f) as u32;
+        block[BLOCK_SIZE - 1] &= 0xc0;
+        let ktop = self.encipher(u128::from_be_bytes(block));
+        // Stretch = Ktop || (Ktop[1..64] xor Ktop[9..72]), Offset_0 = Stretch[1+bottom..128+bottom]
+        let stretch = ((ktop >> 64) ^ (ktop >> 56)) as u64;
+        if bottom == 0 {
+            Ok(ktop)
+        } else {
+            Ok((ktop << bottom) | (stretch >> (64 - bottom)) as u128)
+        }
+    }
+
+    /// `Offset_i` computed directly: the running xor of `L_ntz(j)` for j = 1..i is
+    /// the xor of the `L_k` selected by the Gray code of i
+    fn offset(&self, offset_0: u128, i: u64) -> u128 {
+        let gray = i ^ (i >> 1);
+        self.l
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| (gray >> k) & 1 == 1)
+            .fold(offset_0, |offset, (_, l)| offset ^ l)
+    }
+
+    /// Encrypts or decrypts `data` in place, starting with block `first_block + 1`,
+    /// and returns its share of the checksum. Only the last part of a message may
+    /// end with a partial block.
+    fn process_blocks(
+        &self,
+        data: &mut [u8],
+        offset_0: u128,
+        first_block: u64,
+        encrypt: bool,
+    ) -> u128 {
+        let mut offset = self.offset(offset_0, first_block);
+        let mut checksum = 0;
+        let mut i = first_block;
+        let mut blocks = data.chunks_exact_mut(BLOCK_SIZE);
+        for block in &mut blocks {
+            i += 1;
+            offset ^= self.l[i.trailing_zeros() as usize];
+            let input = u128::from_be_bytes((&*block).try_into().unwrap());
+            let output = if encrypt {
+                checksum ^= input;
+                offset ^ self.encipher(input ^ offset)
+            } else {
+                let plain = offset ^ self.decipher(input ^ offset);
+                checksum ^= plain;
+                plain
+            };
+            block.copy_from_slice(&output.to_be_bytes());
+        }
+
+        let tail = blocks.into_remainder();
+        if !tail.is_empty() {
+            let pad = self.encipher(offset ^ self.l_star).to_be_bytes();
+            let mut padded = [0u8; BLOCK_SIZE];
+            if encrypt {
+                padded[..tail.len()].copy_from_slice(tail);
+            }
+            for (byte, p) in tail.iter_mut().zip(pad.iter()) {
+                *byte ^= p;
+            }
+            if !encrypt {
+                padded[..tail.len()].copy_from_slice(tail);
+            }
+            padded[tail.len()] = 0x80;
+            checksum ^= u128::from_be_bytes(padded);
+        }
+        checksum
+    }
+
+    /// `ENCIPHER(K, Checksum xor Offset xor L_$) xor HASH(K, A)`, truncated to the tag length
+    fn tag(&self, offset_0: u128, checksum: u128, len: u64, aad: &[u8]) -> Vec<u8> {
+        let mut offset = self.offset(offset_0, len / BLOCK_SIZE as u64);
+        if !len.is_multiple_of(BLOCK_SIZE as u64) {
+            offset ^= self.l_star;
+        }
+        let tag = self.encipher(checksum ^ offset ^ self.l_dollar) ^ self.hash(aad);
+        tag.to_be_bytes()[..self.tag_size].to_vec()
+    }
+
+    /// HASH(K, A) over the associated data
+    fn hash(&self, aad: &[u8]) -> u128 {
+        let mut sum = 0;
+        let mut offset = 0;
+        let mut blocks = aad.chunks_exact(BLOCK_SIZE);
+        for (i, block) in (&mut blocks).enumerate() {
+            offset ^= self.l[(i as u64 + 1).trailing_zeros() as usize];
+            sum ^= self.encipher(u128::from_be_bytes(block.try_into().unwrap()) ^ offset);
+        }
+        let tail = blocks.remainder();
+        if !tail.is_empty() {
+            let mut padded = [0u8; BLOCK_SIZE];
+            padded[..tail.len()].copy_from_slice(tail);
+            padded[tail.len()] = 0x80;
+            sum ^= self.encipher(u128::from_be_bytes(padded) ^ offset ^ self.l_star);
+        }
+        sum
+    }
+
+    fn encipher(&self, block: u128) -> u128 {
+        let mut block = block.to_be_bytes();
+        aes_v2(&mut block, &self.keys, self.nr);
+        u128::from_be_bytes(block)
+    }
+
+    fn decipher(&self, block: u128) -> u128 {
+        let mut block = block.to_be_bytes();
+        inv_aes_v2(&mut block, &self.keys, self.nr);
+        u128::from_be_bytes(block)
+    }
+}
+
+/// Doubling in GF(2^128), the same as for CMAC
+fn double(block: u128) -> u128 {
+    let carry = (block >> 127) as u8;
+    (block << 1) ^ (0x87 * carry as u128)
+}
+
+/// Runs OCB over the payload on the thread pool and returns the checksum. Without
+/// an output file the processed chunks are only used for the checksum.
+fn process_payload(
+    ocb: &Arc<Ocb>,
+    input_file: File,
+    output_file: Option<File>,
+    payload: Payload,
+    offset_0: u128,
+    encrypt: bool,
+) -> Result<u128, AesError> {
+    let checksum = Arc::new(Mutex::new(0u128));
+    let job = {
+        let (ocb, checksum) = (ocb.clone(), checksum.clone());
+        move |chunk_id: usize, _: &[u8], chunk: &mut Vec<u8>, _| {
+            let first_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            let sum = ocb.process_blocks(chunk, offset_0, first_block, encrypt);
+            *checksum.lock().unwrap() ^= sum;
+            Ok(())
+        }
+    };
+    match output_file {
+        Some(output_file) => process_payload_chunked(input_file, output_file, payload, 0, job)?,
+        None => scan_payload_chunked(input_file, payload, 0, job)?,
+    }
+    let checksum = *checksum.lock().unwrap();
+    Ok(checksum)
+}
+
+/// Encrypts a file into `C || T` in a single pass on the thread pool. The checksum
+/// is a plain xor over the plaintext blocks, so every chunk adds its share to it.
+pub fn encrypt_file(
+    ocb: &Arc<Ocb>,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    mut output_file: File,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+    let offset_0 = ocb.initial_offset(nonce)?;
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: output_file.stream_position()?,
+    };
+    let checksum = process_payload(
+        ocb,
+        input_file,
+        Some(output_file.try_clone()?),
+        payload,
+        offset_0,
+        true,
+    )?;
+    output_file.seek(SeekFrom::Start(payload.output_offset + len))?;
+    output_file.write_all(&ocb.tag(offset_0, checksum, len, aad))?;
+    Ok(())
+}
+
+/// Decrypts a file written by `encrypt_file`. The tag is verified in a first
+/// parallel pass without writing anything, the second pass writes the plaintext.
+pub fn decrypt_file(
+    ocb: &Arc<Ocb>,
+    nonce: &[u8],
+    aad: &[u8],
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let input_len = input_file.metadata()?.len() - input_offset;
+    if input_len < ocb.tag_size as u64 {
+        return Err(AesError::InvalidLength(input_len));
+    }
+    let len = input_len - ocb.tag_size as u64;
+    let offset_0 = ocb.initial_offset(nonce)?;
+
+    let mut tag = vec![0u8; ocb.tag_size];
+    input_file.seek(SeekFrom::Start(input_offset + len))?;
+    input_file.read_exact(&mut tag)?;
+
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: 0,
+    };
+    let checksum = process_payload(ocb, input_file.try_clone()?, None, payload, offset_0, false)?;
+    if !ct_eq(&ocb.tag(offset_0, checksum, len, aad), &tag) {
+        return Err(AesError::AuthenticationFailed);
+    }
+
+    process_payload(
+        ocb,
+        input_file,
+        Some(open_output_file(output_file_path)?),
+        payload,
+        offset_0,
+        false,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encrypts and decrypts `(nonce, aad length, plaintext length, ciphertext || tag)`
+    /// where associated data and plaintext are the bytes 00, 01, 02, ...
+    fn check(key: &str, tag_size: usize, cases: &[(&str, u8, u8, &str)]) {
+        let ocb = Ocb::new(&hex::decode(key).unwrap(), tag_size).unwrap();
+        for (nonce, aad_len, len, result) in cases.iter() {
+            let nonce = hex::decode(nonce).unwrap();
+            let aad: Vec<u8> = (0..*aad_len).collect();
+            let plaintext: Vec<u8> = (0..*len).collect();
+            let mut data = plaintext.clone();
+            let tag = ocb.encrypt(&nonce, &aad, &mut data).unwrap();
+            assert_eq!(hex::encode_upper(&data) + &hex::encode_upper(&tag), *result);
+
+            ocb.decrypt(&nonce, &aad, &mut data, &tag).unwrap();
+            assert_eq!(data, plaintext);
+        }
+    }
+
+    /// The tag length goes into the initial offset, so a truncated 128-bit tag does
+    /// not verify as a 96-bit tag and the ciphertexts differ. Nonces longer than 120
+    /// bits are rejected.
+    #[test]
+    fn binds_tag_length_and_rejects_nonce_length() {
+        let (long, short) = (
+            Ocb::new(&[1; 16], 16).unwrap(),
+            Ocb::new(&[1; 16], 12).unwrap(),
+        );
+        let nonce = [2; 12];
+        let mut long_data = *b"attack at dawn";
+        let mut short_data = long_data;
+        let tag = long.encrypt(&nonce, b"header", &mut long_data).unwrap();
+        short.encrypt(&nonce, b"header", &mut short_data).unwrap();
+        assert_ne!(long_data, short_data);
+        let result = short.decrypt(&nonce, b"header", &mut long_data, &tag[..12]);
+        assert!(matches!(result, Err(AesError::AuthenticationFailed)));
+        assert_eq!(long_data, [0; 14]);
+
+        let result = long.encrypt(&[2; 16], b"", &mut short_data);
+        assert!(matches!(result, Err(AesError::InvalidNonceLength(16))));
+        let result = Ocb::new(&[1; 16], 0);
+        assert!(matches!(result, Err(AesError::InvalidTagLength(0))));
+    }
+
+    /// RFC 7253 Appendix A, the samples with a 128-bit tag
+    #[test]
+    fn rfc7253_samples() {
+        check(
+            "000102030405060708090A0B0C0D0E0F",
+            16,
+            &[
+                (
+                    "BBAA99887766554433221100",
+                    0,
+                    0,
+                    "785407BFFFC8AD9EDCC5520AC9111EE6",
+                ),
+                (
+                    "BBAA99887766554433221101",
+                    8,
+                    8,
+                    "6820B3657B6F615A5725BDA0D3B4EB3A257C9AF1F8F03009",
+                ),
+                (
+                    "BBAA99887766554433221102",
+                    8,
+                    0,
+                    "81017F8203F081277152FADE694A0A00",
+                ),
+                (
+                    "BBAA99887766554433221103",
+                    0,
+                    8,
+                    "45DD69F8F5AAE72414054CD1F35D82760B2CD00D2F99BFA9",
+                ),
+                (
+                    "BBAA99887766554433221104",
+                    16,
+                    16,
+                    "571D535B60B277188BE5147170A9A22C3AD7A4FF3835B8C5701C1CCEC8FC3358",
+                ),
+                (
+                    "BBAA99887766554433221105",
+                    16,
+                    0,
+                    "8CF761B6902EF764462AD86498CA6B97",
+                ),
+                (
+                    "BBAA99887766554433221106",
+                    0,
+                    16,
+                    "5CE88EC2E0692706A915C00AEB8B2396F40E1C743F52436BDF06D8FA1ECA343D",
+                ),
+                (
+                    "BBAA99887766554433221107",
+                    24,
+                    24,
+                    concat!(
+                        "1CA2207308C87C010756104D8840CE1952F09673A448A122C92C62241051F573",
+                        "56D7F3C90BB0E07F"
+                    ),
+                ),
+                (
+                    "BBAA99887766554433221108",
+                    24,
+                    0,
+                    "6DC225A071FC1B9F7C69F93B0F1E10DE",
+                ),
+                (
+                    "BBAA99887766554433221109",
+                    0,
+                    24,
+                    concat!(
+                        "221BD0DE7FA6FE993ECCD769460A0AF2D6CDED0C395B1C3CE725F32494B9F914",
+                        "D85C0B1EB38357FF"
+                    ),
+                ),
+                (
+                    "BBAA9988776655443322110A",
+                    32,
+                    32,
+                    concat!(
+                        "BD6F6C496201C69296C11EFD138A467ABD3C707924B964DEAFFC40319AF5A485",
+                        "40FBBA186C5553C68AD9F592A79A4240"
+                    ),
+                ),
+                (
+                    "BBAA9988776655443322110B",
+                    32,
+                    0,
+                    "FE80690BEE8A485D11F32965BC9D2A32",
+                ),
+                (
+                    "BBAA9988776655443322110C",
+                    0,
+                    32,
+                    concat!(
+                        "2942BFC773BDA23CABC6ACFD9BFD5835BD300F0973792EF46040C53F1432BCDF",
+                        "B5E1DDE3BC18A5F840B52E653444D5DF"
+                    ),
+                ),
+                (
+                    "BBAA9988776655443322110D",
+                    40,
+                    40,
+                    concat!(
+                        "D5CA91748410C1751FF8A2F618255B68A0A12E093FF454606E59F9C1D0DDC54B",
+                        "65E8628E568BAD7AED07BA06A4A69483A7035490C5769E60"
+                    ),
+                ),
+                (
+                    "BBAA9988776655443322110E",
+                    40,
+                    0,
+                    "C5CD9D1850C141E358649994EE701B68",
+                ),
+                (
+                    "BBAA9988776655443322110F",
+                    0,
+                    40,
+                    concat!(
+                        "4412923493C57D5DE0D700F753CCE0D1D2D95060122E9F15A5DDBFC5787E50B5",
+                        "CC55EE507BCB084E479AD363AC366B95A98CA5F3000B1479"
+                    ),
+                ),
+            ],
+        );
+    }
+
+    /// RFC 7253 Appendix A, the sample with a 96-bit tag
+    #[test]
+    fn rfc7253_sample_96_bit_tag() {
+        check(
+            "0F0E0D0C0B0A09080706050403020100",
+            12,
+            &[(
+                "BBAA9988776655443322110D",
+                40,
+                40,
+                concat!(
+                    "1792A4E31E0755FB03E31B22116E6C2DDF9EFD6E33D536F1A0124B0A55BAE884",
+                    "ED93481529C76B6AD0C515F4D1CDD4FDAC4F02AA"
+                ),
+            )],
+        );
+    }
+}
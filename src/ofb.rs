@@ -0,0 +1,87 @@
+use crate::aes_ctr_optimized::{aes_v2, process_file_sequential, BLOCK_SIZE};
+use crate::error::AesError;
+use std::fs::File;
+
+/// Encrypts or decrypts the input file in OFB mode (`openssl enc -aes-*-ofb`).
+/// Both directions are the same operation. Each keystream block is the encryption
+/// of the previous one, so this runs sequentially.
+pub fn process_file(
+    keys: &[u8],
+    nr: usize,
+    iv: &[u8],
+    input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    let mut register = [0u8; BLOCK_SIZE];
+    register.copy_from_slice(iv);
+    process_file_sequential(input_file, output_file, |chunk| {
+        ofb_blocks(chunk, keys, nr, &mut register)
+    })
+}
+
+/// XORs the OFB keystream into `data`, `register` holds the IV or the last
+/// keystream block. Only the last call may end in the middle of a block.
+pub(crate) fn ofb_blocks(data: &mut [u8], keys: &[u8], nr: usize, register: &mut [u8; BLOCK_SIZE]) {
+    for block in data.chunks_mut(BLOCK_SIZE) {
+        aes_v2(register, keys, nr);
+        for (byte, k) in block.iter_mut().zip(register.iter()) {
+            *byte ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aes_ctr_optimized::expand_key;
+
+    /// NIST SP 800-38A F.4.1 to F.4.6, decryption is the same operation
+    #[test]
+    fn sp800_38a_ofb() {
+        let plaintext = hex::decode(concat!(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+        ))
+        .unwrap();
+        let cases = [
+            (
+                "2b7e151628aed2a6abf7158809cf4f3c",
+                concat!(
+                    "3b3fd92eb72dad20333449f8e83cfb4a7789508d16918f03f53c52dac54ed825",
+                    "9740051e9c5fecf64344f7a82260edcc304c6528f659c77866a510d9c1d6ae5e"
+                ),
+            ),
+            (
+                "8e73b0f7da0e6452c810f32b809079e562f8ead2522c6b7b",
+                concat!(
+                    "cdc80d6fddf18cab34c25909c99a4174fcc28b8d4c63837c09e81700c1100401",
+                    "8d9a9aeac0f6596f559c6d4daf59a5f26d9f200857ca6c3e9cac524bd9acc92a"
+                ),
+            ),
+            (
+                "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+                concat!(
+                    "dc7e84bfda79164b7ecd8486985d38604febdc6740d20b3ac88f6ad82a4fb08d",
+                    "71ab47a086e86eedf39d1c5bba97c4080126141d67f37be8538f5a8be740e484"
+                ),
+            ),
+        ];
+        for (key, ciphertext) in cases.iter() {
+            let (keys, nr) = expand_key(&hex::decode(key).unwrap()).unwrap();
+            let mut iv = [0u8; BLOCK_SIZE];
+            iv.copy_from_slice(&hex::decode("000102030405060708090a0b0c0d0e0f").unwrap());
+
+            let mut data = plaintext.clone();
+            let mut register = iv;
+            ofb_blocks(&mut data, &keys, nr, &mut register);
+            assert_eq!(hex::encode(&data), *ciphertext);
+
+            // Decrypting in two calls continues the keystream from the register
+            let mut register = iv;
+            let (first, second) = data.split_at_mut(2 * BLOCK_SIZE);
+            ofb_blocks(first, &keys, nr, &mut register);
+            ofb_blocks(second, &keys, nr, &mut register);
+            assert_eq!(data, plaintext);
+        }
+    }
+}
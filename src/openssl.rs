@@ -0,0 +1,154 @@
+use crate::aes_ctr_optimized::read_full;
+use crate::digest::Digest;
+use crate::error::AesError;
+use crate::md5::Md5;
+use crate::pbkdf2::pbkdf2;
+use crate::secret::SecretBytes;
+use crate::sha256::Sha256;
+use std::io::Read;
+
+/// Magic at the start of files written by `openssl enc` with a password
+pub const MAGIC: &[u8; 8] = b"Salted__";
+/// Size of the salt following the magic
+pub const SALT_SIZE: usize = 8;
+/// Iteration count of `openssl enc -pbkdf2` without `-iter`
+pub const DEFAULT_ITERATIONS: u32 = 10_000;
+
+/// Hash function of the key derivation (`openssl enc -md`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageDigest {
+    Md5,
+    Sha256,
+}
+
+impl MessageDigest {
+    /// Parses a digest name as given to `openssl enc -md`
+    pub fn from_name(name: &str) -> Option<MessageDigest> {
+        match name {
+            "md5" => Some(MessageDigest::Md5),
+            "sha256" => Some(MessageDigest::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// How `openssl enc` derives key and IV from the password
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyDerivation {
+    /// PBKDF2 with HMAC over the digest (`-pbkdf2`, `-iter`)
+    Pbkdf2 { iterations: u32 },
+    /// Legacy `EVP_BytesToKey` with a single iteration (no `-pbkdf2`)
+    BytesToKey,
+}
+
+/// Password and key derivation settings of the `Salted__` format
+#[derive(Clone, Debug)]
+pub struct PasswordOptions {
+    pub password: SecretBytes,
+    pub derivation: KeyDerivation,
+    pub digest: MessageDigest,
+    /// Salt for encryption (`-S`), a random salt is used if none is given
+    pub salt: Option<[u8; SALT_SIZE]>,
+}
+
+/// Derives a key of `key_len` bytes and an IV of `iv_len` bytes like `openssl enc`
+pub fn derive_key_iv(
+    options: &PasswordOptions,
+    salt: &[u8],
+    key_len: usize,
+    iv_len: usize,
+) -> (SecretBytes, Vec<u8>) {
+    let mut key = SecretBytes::zeroed(key_len + iv_len);
+    let password = &options.password;
+    match (options.derivation, options.digest) {
+        (KeyDerivation::Pbkdf2 { iterations }, MessageDigest::Md5) => {
+            pbkdf2::<Md5>(password, salt, iterations, &mut key)
+        }
+        (KeyDerivation::Pbkdf2 { iterations }, MessageDigest::Sha256) => {
+            pbkdf2::<Sha256>(password, salt, iterations, &mut key)
+        }
+        (KeyDerivation::BytesToKey, MessageDigest::Md5) => {
+            bytes_to_key::<Md5>(password, salt, &mut key)
+        }
+        (KeyDerivation::BytesToKey, MessageDigest::Sha256) => {
+            bytes_to_key::<Sha256>(password, salt, &mut key)
+        }
+    }
+    let iv = key[key_len..].to_vec();
+    (SecretBytes::new(key[..key_len].to_vec()), iv)
+}
+
+/// `EVP_BytesToKey` with one iteration, `D_i = H(D_i-1 || password || salt)`
+/// concatenated until `output` is filled
+fn bytes_to_key<D: Digest>(password: &[u8], salt: &[u8], output: &mut [u8]) {
+    let mut previous = SecretBytes::zeroed(0);
+    for block in output.chunks_mut(D::OUTPUT_SIZE) {
+        let mut hash = D::new();
+        hash.update(&previous);
+        hash.update(password);
+        hash.update(salt);
+        previous = SecretBytes::new(hash.finalize());
+        block.copy_from_slice(&previous[..block.len()]);
+    }
+}
+
+/// The `Salted__` header for `salt`
+pub fn header(salt: &[u8; SALT_SIZE]) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(salt);
+    header
+}
+
+/// Reads the `Salted__` header and returns the salt
+pub fn read_header<R: Read>(reader: &mut R) -> Result<[u8; SALT_SIZE], AesError> {
+    let mut header = [0u8; MAGIC.len() + SALT_SIZE];
+    if read_full(reader, &mut header)? < header.len() || &header[..MAGIC.len()] != MAGIC {
+        return Err(AesError::InvalidHeader);
+    }
+    let mut salt = [0u8; SALT_SIZE];
+    salt.copy_from_slice(&header[MAGIC.len()..]);
+    Ok(salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key and IV printed by `openssl enc -aes-256-cbc -pass pass:password
+    /// -S 0102030405060708 -md <digest> -P`, with and without `-pbkdf2 -iter 1000`
+    #[test]
+    fn matches_openssl_enc() {
+        let cases = [
+            (
+                KeyDerivation::BytesToKey,
+                MessageDigest::Md5,
+                "e7b0971e52ca5cc8d0539fb3412f6316f7ba2e6ee293d9f3457b99436b51ce02",
+                "8d450e2ed75a84a923d4eac9fe49226b",
+            ),
+            (
+                KeyDerivation::BytesToKey,
+                MessageDigest::Sha256,
+                "2435177f1410536baad2acc155c0f94783d58384573cb0f72157443606285d3f",
+                "f96efc044e0f1613bf324245c95e7411",
+            ),
+            (
+                KeyDerivation::Pbkdf2 { iterations: 1000 },
+                MessageDigest::Md5,
+                "4130967ba25b853713a35c4d59058442390fabe08f6814034d78c2cfb2f3e2df",
+                "b6e80732ff12ce3599b1428de85c16f7",
+            ),
+        ];
+        let salt = [1, 2, 3, 4, 5, 6, 7, 8];
+        for (derivation, digest, key, iv) in cases.iter() {
+            let options = PasswordOptions {
+                password: SecretBytes::new(b"password".to_vec()),
+                derivation: *derivation,
+                digest: *digest,
+                salt: Some(salt),
+            };
+            let (derived_key, derived_iv) = derive_key_iv(&options, &salt, 32, 16);
+            assert_eq!(hex::encode(&derived_key[..]), *key);
+            assert_eq!(hex::encode(derived_iv), *iv);
+        }
+    }
+}
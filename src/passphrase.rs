@@ -0,0 +1,131 @@
+use crate::argon2::{argon2id, Argon2Params};
+use crate::pbkdf2::pbkdf2;
+use crate::scrypt::{scrypt, ScryptParams};
+use crate::secret::SecretBytes;
+use crate::sha256::Sha256;
+
+/// Size of the random salt
+pub const SALT_SIZE: usize = 16;
+
+/// Upper bound for the memory a header may ask for, so a crafted file cannot make
+/// decryption allocate arbitrary amounts (4 GiB)
+const MAX_MEMORY: u64 = 1 << 32;
+/// Upper bounds for the time a header may ask for, so a crafted file cannot keep
+/// decryption busy for hours. They are far above the recommended parameters.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+const MAX_SCRYPT_PARALLEL_WORK: u64 = 256;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+
+/// Key derivation function and its cost parameters as stored in the container header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kdf {
+    /// PBKDF2-HMAC-SHA256
+    Pbkdf2 {
+        iterations: u32,
+    },
+    Scrypt(ScryptParams),
+    Argon2id(Argon2Params),
+}
+
+impl Kdf {
+    /// Parses a KDF name, each comes with its recommended parameters
+    pub fn from_name(name: &str) -> Option<Kdf> {
+        match name {
+            "pbkdf2" => Some(Kdf::Pbkdf2 {
+                iterations: 600_000,
+            }),
+            "scrypt" => Some(Kdf::Scrypt(ScryptParams {
+                log_n: 17,
+                r: 8,
+                p: 1,
+            })),
+            "argon2id" => Some(Kdf::Argon2id(Argon2Params {
+                memory_kib: 64 * 1024,
+                iterations: 3,
+                lanes: 4,
+            })),
+            _ => None,
+        }
+    }
+
+    /// Fills `output` with key material derived from the passphrase
+    pub fn derive(&self, passphrase: &[u8], salt: &[u8], output: &mut [u8]) {
+        match self {
+            Kdf::Pbkdf2 { iterations } => pbkdf2::<Sha256>(passphrase, salt, *iterations, output),
+            Kdf::Scrypt(params) => scrypt(passphrase, salt, params, output),
+            Kdf::Argon2id(params) => argon2id(passphrase, salt, params, output),
+        }
+    }
+
+    /// Identifier and the three parameters stored in the container header
+    pub(crate) fn encode(&self) -> (u8, [u32; 3]) {
+        match *self {
+            Kdf::Pbkdf2 { iterations } => (1, [iterations, 0, 0]),
+            Kdf::Scrypt(params) => (2, [params.log_n as u32, params.r, params.p]),
+            Kdf::Argon2id(params) => (3, [params.memory_kib, params.iterations, params.lanes]),
+        }
+    }
+
+    /// Rebuilds the KDF from the header fields, parameters that are out of range
+    /// or would need too much memory or time are rejected
+    pub(crate) fn decode(id: u8, params: [u32; 3]) -> Option<Kdf> {
+        let [a, b, c] = params;
+        match id {
+            1 if (1..=MAX_PBKDF2_ITERATIONS).contains(&a) => Some(Kdf::Pbkdf2 { iterations: a }),
+            2 if (1..32).contains(&a)
+                && b > 0
+                && c > 0
+                && 128 * b as u64 * (1u64 << a) <= MAX_MEMORY
+                && b as u64 * c as u64 <= MAX_SCRYPT_PARALLEL_WORK =>
+            {
+                Some(Kdf::Scrypt(ScryptParams {
+                    log_n: a as u8,
+                    r: b,
+                    p: c,
+                }))
+            }
+            3 if (1..=MAX_ARGON2_ITERATIONS).contains(&b)
+                && (1..1 << 24).contains(&c)
+                && a as u64 >= 8 * c as u64
+                && a as u64 * 1024 <= MAX_MEMORY =>
+            {
+                Some(Kdf::Argon2id(Argon2Params {
+                    memory_kib: a,
+                    iterations: b,
+                    lanes: c,
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Passphrase and key derivation function for encryption, decryption takes the
+/// KDF from the container header
+#[derive(Clone, Debug)]
+pub struct PassphraseOptions {
+    pub passphrase: SecretBytes,
+    pub kdf: Kdf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_rejects_costs_above_caps() {
+        for name in ["pbkdf2", "scrypt", "argon2id"].iter() {
+            let kdf = Kdf::from_name(name).unwrap();
+            let (id, params) = kdf.encode();
+            assert_eq!(Kdf::decode(id, params), Some(kdf));
+        }
+        let max = MAX_PBKDF2_ITERATIONS;
+        assert!(Kdf::decode(1, [max, 0, 0]).is_some());
+        assert!(Kdf::decode(1, [max + 1, 0, 0]).is_none());
+        assert!(Kdf::decode(2, [10, 8, 32]).is_some());
+        assert!(Kdf::decode(2, [10, 8, 33]).is_none());
+        let max = MAX_ARGON2_ITERATIONS;
+        assert!(Kdf::decode(3, [1024, max, 4]).is_some());
+        assert!(Kdf::decode(3, [1024, max + 1, 4]).is_none());
+    }
+}
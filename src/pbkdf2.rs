@@ -0,0 +1,56 @@
+use crate::digest::Digest;
+use crate::hmac::Hmac;
+use crate::secret::{self, SecretBytes};
+
+/// PBKDF2 (RFC 8018) with HMAC over `D` as pseudorandom function, fills `output`
+pub fn pbkdf2<D: Digest>(password: &[u8], salt: &[u8], iterations: u32, output: &mut [u8]) {
+    // The keyed HMAC state is computed once and cloned for every iteration
+    let keyed = Hmac::<D>::new(password);
+    for (i, block) in output.chunks_mut(D::OUTPUT_SIZE).enumerate() {
+        let mut hmac = keyed.clone();
+        hmac.update(salt);
+        hmac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u = SecretBytes::new(hmac.finalize());
+        let mut t = u.clone();
+        for _ in 1..iterations {
+            let mut hmac = keyed.clone();
+            hmac.update(&u);
+            let mut next = hmac.finalize();
+            u.copy_from_slice(&next);
+            secret::wipe(&mut next);
+            for (x, y) in t.iter_mut().zip(u.iter()) {
+                *x ^= y;
+            }
+        }
+        block.copy_from_slice(&t[..block.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256;
+
+    /// RFC 7914 section 11, PBKDF2-HMAC-SHA256 with 64 byte outputs spanning two
+    /// blocks
+    #[test]
+    fn rfc7914_vectors() {
+        let mut output = [0u8; 64];
+        pbkdf2::<Sha256>(b"passwd", b"salt", 1, &mut output);
+        assert_eq!(
+            hex::encode(&output[..]),
+            concat!(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc",
+                "49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+            )
+        );
+        pbkdf2::<Sha256>(b"Password", b"NaCl", 80000, &mut output);
+        assert_eq!(
+            hex::encode(&output[..]),
+            concat!(
+                "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56",
+                "a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d"
+            )
+        );
+    }
+}
@@ -0,0 +1,7 @@
+use std::io;
+
+/// Fills `buf` with random bytes from the CSPRNG of the operating system (the
+/// `getrandom` syscall on Linux), which blocks until it is seeded
+pub fn fill(buf: &mut [u8]) -> io::Result<()> {
+    getrandom::getrandom(buf).map_err(io::Error::from)
+}
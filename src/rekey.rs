@@ -0,0 +1,188 @@
+use crate::aes_ctr_optimized::{
+    check_counter_range, expand_key, process_chunk_with_layout, process_payload_chunked,
+    replace_atomically, Mode, Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::container::Header;
+use crate::envelope;
+use crate::error::AesError;
+use crate::kcv;
+use crate::master_key::{self, FileDerivation};
+use crate::random;
+use crate::secret::SecretBytes;
+use crate::xts::Xts;
+use std::fs::File;
+use std::io::{Seek, Write};
+use std::path::Path;
+
+/// Reencrypts a container file under `new_key` in a single pass. Each chunk is
+/// decrypted and encrypted again in memory on the thread pool, so the plaintext
+/// never reaches the disk, and the output replaces `output_file_path` only once it
+/// is complete. CTR gets a fresh IV, see `CounterLayout::fresh_iv`.
+///
+/// Only modes whose chunks can be processed independently are supported, i.e. CTR
+/// and XTS. With envelope encryption a fresh data key is wrapped under `new_key`,
+/// which becomes the only recipient. A file key derived from a master key is derived
+/// again from `new_key` with a fresh salt.
+pub fn rekey_file(
+    old_key: &[u8],
+    new_key: &[u8],
+    input_file_path: &Path,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let mut input_file = File::open(input_file_path)?;
+    let old = Header::read(&mut input_file)?;
+    if old.mode != Mode::Ctr && old.mode != Mode::Xts {
+        return Err(AesError::UnsupportedMode(old.mode));
+    }
+
+    // The key the payload is encrypted with, i.e. the data key of an envelope or the
+    // file key derived from a master key
+    let key_len = old.key_size as usize / 8;
+    let old_data_key = match old.master {
+        Some(ref file) => {
+            let (layout, endianness) = (old.counter_layout, old.counter_endianness);
+            let iv_len = old.iv.len();
+            Some(master_key::derive_key_iv(old_key, file, key_len, iv_len, layout, endianness).0)
+        }
+        None if !old.recipients.is_empty() => Some(envelope::unwrap_data_key(&old, old_key)?),
+        None => None,
+    };
+    let old_key = old_data_key.as_deref().unwrap_or(old_key);
+    if old_key.len() * 8 != old.key_size as usize {
+        return Err(AesError::InvalidKeyLength(old_key.len()));
+    }
+    if kcv::for_mode(old_key, old.mode)? != old.kcv {
+        return Err(AesError::KeyCheckFailed);
+    }
+
+    let mut new = old.clone();
+    new.counter_layout
+        .fresh_iv(&mut new.iv, new.counter_endianness)?;
+    let new_data_key = match old.master {
+        Some(ref file) => {
+            let mut file = FileDerivation {
+                salt: [0u8; master_key::SALT_SIZE],
+                path: file.path.clone(),
+            };
+            random::fill(&mut file.salt)?;
+            let (layout, endianness) = (new.counter_layout, new.counter_endianness);
+            let iv_len = new.iv.len();
+            let (key, iv) =
+                master_key::derive_key_iv(new_key, &file, key_len, iv_len, layout, endianness);
+            new.iv = iv;
+            new.master = Some(file);
+            Some(key)
+        }
+        None if !old.recipients.is_empty() => {
+            let mut data_key = SecretBytes::zeroed(key_len);
+            random::fill(&mut data_key)?;
+            new.recipients = vec![envelope::recipient(new_key, &data_key)?];
+            Some(data_key)
+        }
+        None => {
+            new.key_size = (new_key.len() * 8) as u16;
+            None
+        }
+    };
+    let new_key = new_data_key.as_deref().unwrap_or(new_key);
+    new.kcv = kcv::for_mode(new_key, new.mode)?;
+
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+    let header = new.to_bytes();
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: header.len() as u64,
+    };
+    replace_atomically(output_file_path, |mut output_file| {
+        output_file.write_all(&header)?;
+        if new.mode == Mode::Xts {
+            rekey_xts(old_key, new_key, &new, input_file, output_file, payload)
+        } else {
+            rekey_ctr(
+                old_key,
+                &old,
+                new_key,
+                &new,
+                input_file,
+                output_file,
+                payload,
+            )
+        }
+    })
+}
+
+/// Applies the keystream of the old key and then the one of the new key, both with
+/// the counter layout of the input
+fn rekey_ctr(
+    old_key: &[u8],
+    old: &Header,
+    new_key: &[u8],
+    new: &Header,
+    input_file: File,
+    output_file: File,
+    payload: Payload,
+) -> Result<(), AesError> {
+    let (layout, endianness) = (new.counter_layout, new.counter_endianness);
+    let blocks = payload.len.div_ceil(BLOCK_SIZE as u64);
+    check_counter_range(&new.iv, layout, endianness, 0, blocks)?;
+    let (old_keys, old_nr) = expand_key(old_key)?;
+    let (new_keys, new_nr) = expand_key(new_key)?;
+    let (old_iv, new_iv) = (old.iv.clone(), new.iv.clone());
+    process_payload_chunked(
+        input_file,
+        output_file,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk_with_layout(
+                chunk,
+                &old_keys,
+                &old_iv,
+                old_nr,
+                starting_block,
+                layout,
+                endianness,
+            );
+            process_chunk_with_layout(
+                chunk,
+                &new_keys,
+                &new_iv,
+                new_nr,
+                starting_block,
+                layout,
+                endianness,
+            );
+            Ok(())
+        },
+    )
+}
+
+/// Decrypts every sector with the old key and encrypts it again with the new one
+fn rekey_xts(
+    old_key: &[u8],
+    new_key: &[u8],
+    new: &Header,
+    input_file: File,
+    output_file: File,
+    payload: Payload,
+) -> Result<(), AesError> {
+    let sector_size = new.sector_size as usize;
+    let (old_xts, new_xts) = (Xts::new(old_key)?, Xts::new(new_key)?);
+    process_payload_chunked(
+        input_file,
+        output_file,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let first_sector = (chunk_id * CHUNK_SIZE / sector_size) as u128;
+            for (i, sector) in chunk.chunks_mut(sector_size).enumerate() {
+                old_xts.decrypt_sector(sector, first_sector + i as u128)?;
+                new_xts.encrypt_sector(sector, first_sector + i as u128)?;
+            }
+            Ok(())
+        },
+    )
+}
@@ -0,0 +1,157 @@
+use crate::pbkdf2::pbkdf2;
+use crate::secret::{self, SecretBytes};
+use crate::sha256::Sha256;
+
+/// Cost parameters of scrypt, memory use is `128 * r * 2^log_n` bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScryptParams {
+    /// Base 2 logarithm of the CPU/memory cost `N`
+    pub log_n: u8,
+    /// Block size factor
+    pub r: u32,
+    /// Parallelization factor
+    pub p: u32,
+}
+
+/// scrypt (RFC 7914), fills `output`
+pub fn scrypt(password: &[u8], salt: &[u8], params: &ScryptParams, output: &mut [u8]) {
+    let block_len = 128 * params.r as usize;
+    let mut b = SecretBytes::zeroed(block_len * params.p as usize);
+    pbkdf2::<Sha256>(password, salt, 1, &mut b);
+    for block in b.chunks_exact_mut(block_len) {
+        ro_mix(block, params.r as usize, 1 << params.log_n);
+    }
+    pbkdf2::<Sha256>(password, &b, 1, output);
+}
+
+/// The sequential memory-hard function, mixes `block` of `128 * r` bytes in place
+fn ro_mix(block: &mut [u8], r: usize, n: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block
+        .chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    let mut y = vec![0u32; words];
+    let mut v = vec![0u32; words * n];
+
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for _ in 0..n {
+        // Integerify, the first word of the last 64-byte block modulo N
+        let j = x[words - 16] as usize & (n - 1);
+        for (a, b) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+            *a ^= b;
+        }
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+
+    for (bytes, word) in block.chunks_exact_mut(4).zip(x.iter()) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    // V holds every intermediate state, any of them leads to the output
+    secret::wipe(&mut x);
+    secret::wipe(&mut y);
+    secret::wipe(&mut v);
+}
+
+/// BlockMix with Salsa20/8, even output blocks go to the first half of `output`
+fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+    let mut x = [0u32; 16];
+    x.copy_from_slice(&input[(2 * r - 1) * 16..]);
+    for (i, chunk) in input.chunks_exact(16).enumerate() {
+        for (a, b) in x.iter_mut().zip(chunk) {
+            *a ^= b;
+        }
+        salsa20_8(&mut x);
+        let position = (i / 2 + (i % 2) * r) * 16;
+        output[position..position + 16].copy_from_slice(&x);
+    }
+}
+
+/// The Salsa20 core reduced to 8 rounds
+fn salsa20_8(b: &mut [u32; 16]) {
+    let mut x = *b;
+    for _ in 0..4 {
+        // Columns
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        // Rows
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+    for (a, b) in b.iter_mut().zip(x.iter()) {
+        *a = a.wrapping_add(*b);
+    }
+}
+
+#[inline]
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+    x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+    x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+    x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 7914 section 12 test vectors
+    #[test]
+    fn rfc7914_vectors() {
+        let cases: [(&[u8], &[u8], ScryptParams, &str); 3] = [
+            (
+                b"",
+                b"",
+                ScryptParams {
+                    log_n: 4,
+                    r: 1,
+                    p: 1,
+                },
+                concat!(
+                    "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442",
+                    "fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+                ),
+            ),
+            (
+                b"password",
+                b"NaCl",
+                ScryptParams {
+                    log_n: 10,
+                    r: 8,
+                    p: 16,
+                },
+                concat!(
+                    "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162",
+                    "2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+                ),
+            ),
+            (
+                b"pleaseletmein",
+                b"SodiumChloride",
+                ScryptParams {
+                    log_n: 14,
+                    r: 8,
+                    p: 1,
+                },
+                concat!(
+                    "7023bdcb3afd7348461c06cd81fd38ebfda8fbba904f8e3ea9b543f6545da1f2",
+                    "d5432955613f0fcf62d49705242a9af9e61e85dc0d651e40dfcf017b45575887"
+                ),
+            ),
+        ];
+        for (password, salt, params, expected) in cases.iter() {
+            let mut output = [0u8; 64];
+            scrypt(password, salt, params, &mut output);
+            assert_eq!(hex::encode(&output[..]), *expected);
+        }
+    }
+}
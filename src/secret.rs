@@ -0,0 +1,154 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Key material that is wiped when dropped. Where the operating system allows it,
+/// the pages are locked into memory, so the key never ends up in swap.
+///
+/// Copies made with `clone` are separate buffers that are wiped on their own.
+pub struct SecretBytes {
+    bytes: Vec<u8>,
+    locked: bool,
+}
+
+impl SecretBytes {
+    /// Takes ownership of `bytes` without copying them
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        let locked = lock(&bytes);
+        SecretBytes { bytes, locked }
+    }
+
+    /// A buffer of `len` zero bytes to derive key material into
+    pub fn zeroed(len: usize) -> SecretBytes {
+        SecretBytes::new(vec![0u8; len])
+    }
+
+    /// Shortens the buffer, the bytes cut off are wiped with the rest on drop
+    pub fn truncate(&mut self, len: usize) {
+        self.bytes.truncate(len);
+    }
+}
+
+impl Clone for SecretBytes {
+    fn clone(&self) -> SecretBytes {
+        SecretBytes::new(self.bytes.clone())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes([REDACTED; {}])", self.bytes.len())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        // The spare capacity may hold key material from before a reallocation too
+        let capacity = self.bytes.capacity();
+        let bytes = self.bytes.as_mut_ptr();
+        for i in 0..capacity {
+            unsafe { ptr::write_volatile(bytes.add(i), 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+        if self.locked {
+            unlock(&self.bytes);
+        }
+    }
+}
+
+/// Overwrites `values` with zeros in a way the compiler does not optimize away, for
+/// key material in stack buffers and the working memory of the KDFs
+pub fn wipe<T: Copy + Default>(values: &mut [T]) {
+    for value in values.iter_mut() {
+        unsafe { ptr::write_volatile(value, T::default()) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Creates or truncates `path` as a file only its owner can read and write, for key
+/// material such as exported or unwrapped keys. A new file is created with these
+/// permissions, an existing one gets them before anything is written.
+pub fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    restrict_permissions(&file)?;
+    Ok(file)
+}
+
+/// Makes the file readable and writable by its owner only
+#[cfg(unix)]
+pub(crate) fn restrict_permissions(file: &File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn restrict_permissions(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Locks the pages of the allocation into memory, failures (e.g. `RLIMIT_MEMLOCK`)
+/// only leave the key swappable
+#[cfg(unix)]
+fn lock(bytes: &Vec<u8>) -> bool {
+    bytes.capacity() > 0
+        && unsafe { libc::mlock(bytes.as_ptr() as *const libc::c_void, bytes.capacity()) } == 0
+}
+
+/// Locks do not nest, so this may unlock a page shared with another key early
+#[cfg(unix)]
+fn unlock(bytes: &Vec<u8>) {
+    unsafe { libc::munlock(bytes.as_ptr() as *const libc::c_void, bytes.capacity()) };
+}
+
+#[cfg(not(unix))]
+fn lock(_bytes: &Vec<u8>) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock(_bytes: &Vec<u8>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// New files are created private, existing ones are made private
+    #[cfg(unix)]
+    #[test]
+    fn create_private_file_restricts_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("private-file-{}", std::process::id()));
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        create_private_file(&path).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        create_private_file(&path).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        fs::remove_file(&path).unwrap();
+    }
+}
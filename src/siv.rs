@@ -0,0 +1,287 @@
+use crate::aes_ctr_optimized::{
+    ct_eq, expand_key, for_each_chunk, open_output_file, process_chunk, process_payload_chunked,
+    Payload, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::cmac::{dbl, Cmac, CmacState};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+/// Size of the synthetic IV, which doubles as authentication tag
+pub const SIV_SIZE: usize = 16;
+
+/// AES-SIV (RFC 5297), deterministic authenticated encryption
+pub struct Siv {
+    mac: Cmac,
+    ctr_keys: SecretBytes,
+    nr: usize,
+}
+
+impl Siv {
+    /// Creates a cipher from a 32, 48 or 64 byte key, the first half keys S2V
+    /// and the second half CTR
+    pub fn new(key: &[u8]) -> Result<Siv, AesError> {
+        if key.len() != 32 && key.len() != 48 && key.len() != 64 {
+            return Err(AesError::InvalidKeyLength(key.len()));
+        }
+        let (mac_key, ctr_key) = key.split_at(key.len() / 2);
+        let (ctr_keys, nr) = expand_key(ctr_key)?;
+        Ok(Siv {
+            mac: Cmac::new(mac_key)?,
+            ctr_keys,
+            nr,
+        })
+    }
+
+    /// Encrypts `data` in place and returns the synthetic IV. A nonce, if any,
+    /// is passed as the last associated data component.
+    pub fn encrypt(&self, ad: &[&[u8]], data: &mut [u8]) -> [u8; SIV_SIZE] {
+        let mut s2v = self.s2v(ad, data.len() as u64);
+        s2v.update(data);
+        let v = s2v.finalize();
+        self.apply_keystream(data, &v, 0);
+        v
+    }
+
+    /// Decrypts `data` in place if the synthetic IV `v` is valid, otherwise `data` is wiped
+    pub fn decrypt(&self, ad: &[&[u8]], data: &mut [u8], v: &[u8]) -> Result<(), AesError> {
+        if v.len() != SIV_SIZE {
+            return Err(AesError::AuthenticationFailed);
+        }
+        self.apply_keystream(data, v, 0);
+        let mut s2v = self.s2v(ad, data.len() as u64);
+        s2v.update(data);
+        if !ct_eq(&s2v.finalize(), v) {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+            return Err(AesError::AuthenticationFailed);
+        }
+        Ok(())
+    }
+
+    /// Starts S2V over the associated data, the plaintext of length `len` follows
+    fn s2v(&self, ad: &[&[u8]], len: u64) -> S2v<'_> {
+        let mut d = self.mac.mac(&[0u8; BLOCK_SIZE]);
+        for component in ad {
+            let mac = self.mac.mac(component);
+            d = dbl(&d);
+            for (x, m) in d.iter_mut().zip(mac.iter()) {
+                *x ^= m;
+            }
+        }
+        S2v {
+            mac: &self.mac,
+            state: self.mac.start(),
+            d,
+            len,
+            fed: 0,
+            short: [0u8; BLOCK_SIZE],
+        }
+    }
+
+    fn apply_keystream(&self, data: &mut [u8], v: &[u8], starting_block: u64) {
+        process_chunk(data, &self.ctr_keys, &counter(v), self.nr, starting_block);
+    }
+}
+
+/// Initial CTR counter, bits 63 and 31 of the synthetic IV are cleared so that
+/// implementations can use 32 or 64-bit counter arithmetic
+fn counter(v: &[u8]) -> [u8; BLOCK_SIZE] {
+    let mut counter = [0u8; BLOCK_SIZE];
+    counter.copy_from_slice(v);
+    counter[8] &= 0x7f;
+    counter[12] &= 0x7f;
+    counter
+}
+
+/// S2V over the last component, which is streamed in with `update`
+struct S2v<'a> {
+    mac: &'a Cmac,
+    state: CmacState<'a>,
+    d: [u8; BLOCK_SIZE],
+    len: u64,
+    fed: u64,
+    short: [u8; BLOCK_SIZE],
+}
+
+impl<'a> S2v<'a> {
+    fn update(&mut self, data: &[u8]) {
+        if self.len < BLOCK_SIZE as u64 {
+            let start = self.fed as usize;
+            self.short[start..start + data.len()].copy_from_slice(data);
+            self.fed += data.len() as u64;
+            return;
+        }
+        // The last block of the component gets D xored in ("xorend")
+        let xorend_start = self.len - BLOCK_SIZE as u64;
+        let plain = xorend_start.saturating_sub(self.fed).min(data.len() as u64) as usize;
+        self.state.update(&data[..plain]);
+        let mut tail = data[plain..].to_vec();
+        let offset = (self.fed + plain as u64 - xorend_start) as usize;
+        for (i, byte) in tail.iter_mut().enumerate() {
+            *byte ^= self.d[offset + i];
+        }
+        self.state.update(&tail);
+        self.fed += data.len() as u64;
+    }
+
+    fn finalize(self) -> [u8; SIV_SIZE] {
+        if self.len >= BLOCK_SIZE as u64 {
+            return self.state.finalize();
+        }
+        let mut t = dbl(&self.d);
+        let mut padded = self.short;
+        padded[self.len as usize] = 0x80;
+        for (x, p) in t.iter_mut().zip(padded.iter()) {
+            *x ^= p;
+        }
+        self.mac.mac(&t)
+    }
+}
+
+/// Encrypts a file into `V || C`. S2V over the plaintext is computed in a first
+/// sequential pass, the CTR pass then runs on the thread pool.
+pub fn encrypt_file(
+    siv: &Siv,
+    ad: &[&[u8]],
+    mut input_file: File,
+    mut output_file: File,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let len = input_file.metadata()?.len() - input_offset;
+
+    let mut s2v = siv.s2v(ad, len);
+    for_each_chunk(&input_file, len, |chunk, _| s2v.update(chunk))?;
+    let v = s2v.finalize();
+
+    output_file.write_all(&v)?;
+    let payload = Payload {
+        input_offset,
+        len,
+        output_offset: output_file.stream_position()?,
+    };
+    let (ctr_keys, nr) = (siv.ctr_keys.clone(), siv.nr);
+    process_payload_chunked(
+        input_file,
+        output_file,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk(chunk, &ctr_keys, &counter(&v), nr, starting_block);
+            Ok(())
+        },
+    )
+}
+
+/// Decrypts a file written by `encrypt_file`. The synthetic IV is verified in a
+/// first pass without writing anything, the decryption pass then runs on the
+/// thread pool.
+pub fn decrypt_file(
+    siv: &Siv,
+    ad: &[&[u8]],
+    mut input_file: File,
+    output_file_path: &Path,
+) -> Result<(), AesError> {
+    let input_offset = input_file.stream_position()?;
+    let input_len = input_file.metadata()?.len() - input_offset;
+    if input_len < SIV_SIZE as u64 {
+        return Err(AesError::InvalidLength(input_len));
+    }
+    let len = input_len - SIV_SIZE as u64;
+    let mut v = [0u8; SIV_SIZE];
+    input_file.read_exact(&mut v)?;
+
+    let mut s2v = siv.s2v(ad, len);
+    for_each_chunk(&input_file, len, |chunk, starting_block| {
+        siv.apply_keystream(chunk, &v, starting_block);
+        s2v.update(chunk);
+    })?;
+    if !ct_eq(&s2v.finalize(), &v) {
+        return Err(AesError::AuthenticationFailed);
+    }
+
+    let payload = Payload {
+        input_offset: input_offset + SIV_SIZE as u64,
+        len,
+        output_offset: 0,
+    };
+    let (ctr_keys, nr) = (siv.ctr_keys.clone(), siv.nr);
+    process_payload_chunked(
+        input_file,
+        open_output_file(output_file_path)?,
+        payload,
+        0,
+        move |chunk_id, _, chunk, _| {
+            let starting_block = (chunk_id * CHUNK_SIZE / BLOCK_SIZE) as u64;
+            process_chunk(chunk, &ctr_keys, &counter(&v), nr, starting_block);
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    /// Encrypts and decrypts `plaintext` under the associated data components and
+    /// compares `V || C`
+    fn check(key: &str, ad: &[&str], plaintext: &str, result: &str) {
+        let siv = Siv::new(&unhex(key)).unwrap();
+        let ad: Vec<Vec<u8>> = ad.iter().map(|component| unhex(component)).collect();
+        let ad: Vec<&[u8]> = ad.iter().map(|component| &component[..]).collect();
+        let mut data = unhex(plaintext);
+        let v = siv.encrypt(&ad, &mut data);
+        assert_eq!(hex::encode(v) + &hex::encode(&data), result);
+        siv.decrypt(&ad, &mut data, &v).unwrap();
+        assert_eq!(hex::encode(&data), plaintext);
+    }
+
+    /// RFC 5297 A.1, deterministic authenticated encryption
+    #[test]
+    fn rfc5297_deterministic() {
+        check(
+            "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+            &["101112131415161718191a1b1c1d1e1f2021222324252627"],
+            "112233445566778899aabbccddee",
+            "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c",
+        );
+    }
+
+    /// RFC 5297 A.2, two associated data components followed by the nonce
+    #[test]
+    fn rfc5297_nonce_based() {
+        check(
+            "7f7e7d7c7b7a79787776757473727170404142434445464748494a4b4c4d4e4f",
+            &[
+                "00112233445566778899aabbccddeeffdeaddadadeaddadaffeeddccbbaa99887766554433221100",
+                "102030405060708090a0",
+                "09f911029d74e35bd84156c5635688c0",
+            ],
+            concat!(
+                "7468697320697320736f6d6520706c61696e7465787420746f20656e63727970",
+                "74207573696e67205349562d414553"
+            ),
+            concat!(
+                "7bdb6e3b432667eb06f4d14bff2fbd0fcb900f2fddbe404326601965c889bf17",
+                "dba77ceb094fa663b7a3f748ba8af829ea64ad544a272e9c485b62a3fd5c0d"
+            ),
+        );
+    }
+
+    /// The order of the associated data components matters, and a failed
+    /// decryption wipes the released plaintext
+    #[test]
+    fn rejects_reordered_associated_data() {
+        let siv = Siv::new(&[1; 32]).unwrap();
+        let mut data = *b"attack at dawn";
+        let v = siv.encrypt(&[b"first", b"second"], &mut data);
+        let result = siv.decrypt(&[b"second", b"first"], &mut data, &v);
+        assert!(matches!(result, Err(AesError::AuthenticationFailed)));
+        assert_eq!(data, [0; 14]);
+    }
+}
@@ -0,0 +1,6 @@
+//! Helpers shared by the unit tests
+
+/// Decodes a hex string of a test vector
+pub(crate) fn unhex(hex: &str) -> Vec<u8> {
+    hex::decode(hex).unwrap()
+}
@@ -1,3 +1,4 @@
+use crate::log_verbose;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -28,7 +29,7 @@ impl Worker {
                     job();
                 }
                 Message::Terminate => {
-                    println!("ThreadPool| Worker {} was told to terminate.", id);
+                    log_verbose!("ThreadPool| Worker {} was told to terminate.", id);
                     break;
                 }
             }
@@ -77,7 +78,7 @@ impl Drop for ThreadPool {
         }
 
         for worker in &mut self.workers {
-            println!("ThreadPool| Shutting down worker {}", worker.id);
+            log_verbose!("ThreadPool| Shutting down worker {}", worker.id);
             if let Some(thread) = worker.thread.take() {
                 thread.join().unwrap();
             }
@@ -0,0 +1,219 @@
+use crate::aes_ctr_optimized::{
+    aes_v2, expand_key, inv_aes_v2, process_file_chunked, BLOCK_SIZE, CHUNK_SIZE,
+};
+use crate::error::AesError;
+use crate::secret::SecretBytes;
+use std::fs::File;
+use std::io::Seek;
+use std::sync::Arc;
+
+/// XTS-AES (IEEE 1619) for sector based encryption of disk images and block devices
+pub struct Xts {
+    data_keys: SecretBytes,
+    tweak_keys: SecretBytes,
+    nr: usize,
+}
+
+impl Xts {
+    /// Creates a cipher from `key1 || key2`, i.e. 32 bytes for XTS-AES-128
+    /// or 64 bytes for XTS-AES-256
+    pub fn new(key: &[u8]) -> Result<Xts, AesError> {
+        if key.len() != 32 && key.len() != 64 {
+            return Err(AesError::InvalidKeyLength(key.len()));
+        }
+        let (data_key, tweak_key) = key.split_at(key.len() / 2);
+        let (data_keys, nr) = expand_key(data_key)?;
+        let (tweak_keys, _) = expand_key(tweak_key)?;
+        Ok(Xts {
+            data_keys,
+            tweak_keys,
+            nr,
+        })
+    }
+
+    /// Encrypts one data unit in place, a partial last block is handled by ciphertext stealing
+    pub fn encrypt_sector(&self, data: &mut [u8], sector: u128) -> Result<(), AesError> {
+        if data.len() < BLOCK_SIZE {
+            return Err(AesError::InvalidLength(data.len() as u64));
+        }
+        let full_blocks = data.len() / BLOCK_SIZE;
+        let tail = data.len() % BLOCK_SIZE;
+        let mut tweak = self.initial_tweak(sector);
+
+        for block in data.chunks_exact_mut(BLOCK_SIZE) {
+            self.encrypt_block(block, tweak);
+            tweak = mul_alpha(tweak);
+        }
+        if tail > 0 {
+            // Steal the tail of the last full ciphertext block to fill up the partial block
+            let last = (full_blocks - 1) * BLOCK_SIZE;
+            let mut block = [0u8; BLOCK_SIZE];
+            block.copy_from_slice(&data[last..last + BLOCK_SIZE]);
+            block[..tail].copy_from_slice(&data[last + BLOCK_SIZE..]);
+            data.copy_within(last..last + tail, last + BLOCK_SIZE);
+            self.encrypt_block(&mut block, tweak);
+            data[last..last + BLOCK_SIZE].copy_from_slice(&block);
+        }
+        Ok(())
+    }
+
+    /// Decrypts one data unit in place, the counterpart of `encrypt_sector`
+    pub fn decrypt_sector(&self, data: &mut [u8], sector: u128) -> Result<(), AesError> {
+        if data.len() < BLOCK_SIZE {
+            return Err(AesError::InvalidLength(data.len() as u64));
+        }
+        let full_blocks = data.len() / BLOCK_SIZE;
+        let tail = data.len() % BLOCK_SIZE;
+        // With ciphertext stealing the last full block uses the tweak after its own
+        let regular_blocks = if tail > 0 {
+            full_blocks - 1
+        } else {
+            full_blocks
+        };
+        let mut tweak = self.initial_tweak(sector);
+
+        for block in data[..regular_blocks * BLOCK_SIZE].chunks_exact_mut(BLOCK_SIZE) {
+            self.decrypt_block(block, tweak);
+            tweak = mul_alpha(tweak);
+        }
+        if tail > 0 {
+            let last = regular_blocks * BLOCK_SIZE;
+            let mut block = [0u8; BLOCK_SIZE];
+            block.copy_from_slice(&data[last..last + BLOCK_SIZE]);
+            self.decrypt_block(&mut block, mul_alpha(tweak));
+            let mut stolen = block;
+            stolen[..tail].copy_from_slice(&data[last + BLOCK_SIZE..]);
+            data[last + BLOCK_SIZE..].copy_from_slice(&block[..tail]);
+            self.decrypt_block(&mut stolen, tweak);
+            data[last..last + BLOCK_SIZE].copy_from_slice(&stolen);
+        }
+        Ok(())
+    }
+
+    /// Encrypts the little-endian sector number with the tweak key
+    fn initial_tweak(&self, sector: u128) -> u128 {
+        let mut block = sector.to_le_bytes();
+        aes_v2(&mut block, &self.tweak_keys, self.nr);
+        u128::from_le_bytes(block)
+    }
+
+    #[inline]
+    fn encrypt_block(&self, block: &mut [u8], tweak: u128) {
+        xor_tweak(block, tweak);
+        aes_v2(block, &self.data_keys, self.nr);
+        xor_tweak(block, tweak);
+    }
+
+    #[inline]
+    fn decrypt_block(&self, block: &mut [u8], tweak: u128) {
+        xor_tweak(block, tweak);
+        inv_aes_v2(block, &self.data_keys, self.nr);
+        xor_tweak(block, tweak);
+    }
+}
+
+/// Multiplies the tweak by the primitive element x of GF(2^128) (little-endian convention)
+#[inline]
+fn mul_alpha(tweak: u128) -> u128 {
+    let carry = (tweak >> 127) as u8;
+    (tweak << 1) ^ (0x87 * carry as u128)
+}
+
+#[inline]
+fn xor_tweak(block: &mut [u8], tweak: u128) {
+    for (byte, t) in block.iter_mut().zip(tweak.to_le_bytes().iter()) {
+        *byte ^= t;
+    }
+}
+
+/// Encrypts or decrypts a file sector by sector on the thread pool.
+/// Sector `n` starts at byte `n * sector_size` behind the current position of the
+/// input file, the last sector may be shorter but has to hold at least one block.
+pub fn process_file(
+    xts: Arc<Xts>,
+    encrypt: bool,
+    sector_size: usize,
+    mut input_file: File,
+    output_file: File,
+) -> Result<(), AesError> {
+    let len = input_file.metadata()?.len() - input_file.stream_position()?;
+    let tail = len % sector_size as u64;
+    if tail > 0 && tail < BLOCK_SIZE as u64 {
+        return Err(AesError::InvalidLength(len));
+    }
+
+    process_file_chunked(input_file, output_file, 0, move |chunk_id, _, chunk, _| {
+        let first_sector = (chunk_id * CHUNK_SIZE / sector_size) as u128;
+        for (i, sector) in chunk.chunks_mut(sector_size).enumerate() {
+            if encrypt {
+                xts.encrypt_sector(sector, first_sector + i as u128)?;
+            } else {
+                xts.decrypt_sector(sector, first_sector + i as u128)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::unhex;
+
+    /// Encrypts `plaintext` as one data unit, checks the ciphertext and decrypts it again
+    fn check(key: &str, sector: u128, plaintext: &str, ciphertext: &str) {
+        let xts = Xts::new(&unhex(key)).unwrap();
+        let mut data = unhex(plaintext);
+        xts.encrypt_sector(&mut data, sector).unwrap();
+        assert_eq!(hex::encode(&data), ciphertext);
+        xts.decrypt_sector(&mut data, sector).unwrap();
+        assert_eq!(hex::encode(&data), plaintext);
+    }
+
+    /// IEEE 1619-2007 Annex B, vector 1: all-zero keys and data
+    #[test]
+    fn ieee1619_vector_1() {
+        check(
+            &"00".repeat(32),
+            0,
+            &"00".repeat(32),
+            "917cf69ebd68b2ec9b9fe9a3eadda692cd43d2f59598ed858c02c2652fbf922e",
+        );
+    }
+
+    /// IEEE 1619-2007 Annex B, vector 2
+    #[test]
+    fn ieee1619_vector_2() {
+        check(
+            &("11".repeat(16) + &"22".repeat(16)),
+            0x3333333333,
+            &"44".repeat(32),
+            "c454185e6a16936e39334038acef838bfb186fff7480adc4289382ecd6d394f0",
+        );
+    }
+
+    /// IEEE 1619-2007 Annex B, vector 15: 17 bytes, i.e. ciphertext stealing
+    #[test]
+    fn ieee1619_vector_15() {
+        check(
+            "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0bfbebdbcbbbab9b8b7b6b5b4b3b2b1b0",
+            0x123456789a,
+            "000102030405060708090a0b0c0d0e0f10",
+            "6c1625db4671522d3d7599601de7ca09ed",
+        );
+    }
+
+    /// Every tail length of ciphertext stealing decrypts to the plaintext
+    #[test]
+    fn round_trip() {
+        let xts = Xts::new(&[7u8; 64]).unwrap();
+        for len in BLOCK_SIZE..4 * BLOCK_SIZE {
+            let plaintext: Vec<u8> = (0..len as u8).collect();
+            let mut data = plaintext.clone();
+            xts.encrypt_sector(&mut data, 42).unwrap();
+            assert_ne!(data, plaintext);
+            xts.decrypt_sector(&mut data, 42).unwrap();
+            assert_eq!(data, plaintext);
+        }
+    }
+}